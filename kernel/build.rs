@@ -0,0 +1,5 @@
+fn main() {
+    let manifest_dir = env!("CARGO_MANIFEST_DIR");
+    println!("cargo:rustc-link-arg=-T{manifest_dir}/linker.ld");
+    println!("cargo:rerun-if-changed={manifest_dir}/linker.ld");
+}
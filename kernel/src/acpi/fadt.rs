@@ -0,0 +1,71 @@
+use core::fmt;
+
+use crate::acpi::sdt::{AcpiTable, Sdt};
+
+/// Fixed ACPI Description Table. Only the fields of ACPI 1.0 are read, later revisions append
+/// to them.
+#[derive(Copy, Clone, Debug)]
+#[repr(C, packed)]
+pub struct Fadt {
+    header: Sdt,
+    firmware_ctrl: u32,
+    dsdt: u32,
+    reserved: u8,
+    preferred_pm_profile: u8,
+    sci_interrupt: u16,
+    smi_command_port: u32,
+    acpi_enable: u8,
+    acpi_disable: u8,
+    s4bios_request: u8,
+    pstate_control: u8,
+    pm1a_event_block: u32,
+    pm1b_event_block: u32,
+    pm1a_control_block: u32,
+    pm1b_control_block: u32,
+    pm2_control_block: u32,
+    pm_timer_block: u32,
+    gpe0_block: u32,
+    gpe1_block: u32,
+    pm1_event_length: u8,
+    pm1_control_length: u8,
+    pm2_control_length: u8,
+    pm_timer_length: u8,
+    gpe0_length: u8,
+    gpe1_length: u8,
+    gpe1_base: u8,
+    c_state_control: u8,
+    worst_c2_latency: u16,
+    worst_c3_latency: u16,
+    flush_size: u16,
+    flush_stride: u16,
+    duty_offset: u8,
+    duty_width: u8,
+    day_alarm: u8,
+    month_alarm: u8,
+    /// The CMOS register holding the century, or 0 if there's none.
+    century: u8,
+    boot_architecture_flags: u16,
+    reserved2: u8,
+    flags: u32,
+}
+
+impl Fadt {
+    /// The CMOS register of the real-time clock holding the century, if it has one.
+    pub fn century_register(&self) -> Option<u8> {
+        (self.century != 0).then_some(self.century)
+    }
+}
+
+impl AcpiTable for Fadt {
+    fn load_from_address<T>(address: u32) -> Self {
+        Sdt::load_from_address::<Fadt>(address)
+    }
+}
+
+impl fmt::Display for Fadt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Fadt\n{}", self.header)?;
+        write!(f, "\tcentury: {:#X}\n", self.century)?;
+        write!(f, "\tflags: {:#X}\n", self.flags as u32)
+    }
+}
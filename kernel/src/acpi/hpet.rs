@@ -0,0 +1,52 @@
+use core::fmt;
+
+use crate::acpi::sdt::{AcpiTable, GenericAddress, Sdt};
+use crate::bits::Bits;
+
+/// High Precision Event Timer Description Table
+#[derive(Copy, Clone, Debug)]
+#[repr(C, packed)]
+pub struct Hpet {
+    header: Sdt,
+    event_timer_block_id: u32,
+    base_address: GenericAddress,
+    pub hpet_number: u8,
+    /// The smallest periodic tick the HPET supports without losing interrupts, in main counter
+    /// ticks.
+    pub minimum_tick: u16,
+    page_protection: u8,
+}
+
+impl Hpet {
+    /// The physical address of the registers of the HPET.
+    pub fn base_address(&self) -> u64 {
+        self.base_address.address
+    }
+
+    /// Whether the registers are in memory. The HPET specification requires it, but the table
+    /// can say otherwise.
+    pub fn is_memory_mapped(&self) -> bool {
+        self.base_address.address_space_id == GenericAddress::SYSTEM_MEMORY
+    }
+
+    pub fn comparator_count(&self) -> u8 {
+        let event_timer_block_id = self.event_timer_block_id;
+        event_timer_block_id.get_bits(8..13) as u8 + 1
+    }
+}
+
+impl AcpiTable for Hpet {
+    fn load_from_address<T>(address: u32) -> Self {
+        Sdt::load_from_address::<Hpet>(address)
+    }
+}
+
+impl fmt::Display for Hpet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Hpet\n{}", self.header)?;
+        write!(f, "\tbase_address: {:#X}\n", self.base_address())?;
+        write!(f, "\tcomparator_count: {}\n", self.comparator_count())?;
+        write!(f, "\thpet_number: {}\n", self.hpet_number)?;
+        write!(f, "\tminimum_tick: {}\n", self.minimum_tick as u16)
+    }
+}
@@ -3,11 +3,15 @@ use spin::Once;
 use rsdp::Rsdp;
 
 use crate::{println, trace};
+use crate::acpi::fadt::Fadt;
+use crate::acpi::hpet::Hpet;
 use crate::acpi::madt::Madt;
 use crate::acpi::rsdt::Rsdt;
 use crate::acpi::sdt::Signature;
 
 mod acpi_from_lib;
+pub(crate) mod fadt;
+pub(crate) mod hpet;
 pub(crate) mod madt;
 mod rsdp;
 mod rsdt;
@@ -18,6 +22,8 @@ pub static ACPI: Once<Acpi> = Once::new();
 #[derive(Debug)]
 pub struct Acpi {
     pub madt: Madt,
+    pub hpet: Option<Hpet>,
+    pub fadt: Option<Fadt>,
 }
 
 impl Acpi {
@@ -30,7 +36,17 @@ impl Acpi {
             .expect("Failed to find MADT table");
         trace!("MADT table: {}", madt);
 
-        Acpi { madt }
+        let hpet = rsdt.find_table::<Hpet>(Signature::HPET);
+        if let Some(hpet) = &hpet {
+            trace!("HPET table: {}", hpet);
+        }
+
+        let fadt = rsdt.find_table::<Fadt>(Signature::FADT);
+        if let Some(fadt) = &fadt {
+            trace!("FADT table: {}", fadt);
+        }
+
+        Acpi { madt, hpet, fadt }
     }
 }
 
@@ -48,11 +48,28 @@ impl fmt::Display for Sdt {
     }
 }
 
+/// Generic Address Structure: where a register is, in memory or in I/O space.
+#[derive(Copy, Clone, Debug)]
+#[repr(C, packed)]
+pub struct GenericAddress {
+    pub address_space_id: u8,
+    pub register_bit_width: u8,
+    pub register_bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    pub const SYSTEM_MEMORY: u8 = 0;
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub(crate) struct Signature([u8; 4]);
 
 impl Signature {
     pub const MADT: Signature = Signature(*b"APIC");
+    pub const HPET: Signature = Signature(*b"HPET");
+    pub const FADT: Signature = Signature(*b"FACP");
 }
 
 impl fmt::Display for Signature {
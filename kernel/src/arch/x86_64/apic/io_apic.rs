@@ -15,9 +15,16 @@ pub fn init() {
             _ => None,
         })
         .expect("IOApic not found");
-    trace!("IO APIC address: {:#X}, ID: {}", io_apic.io_apic_address as usize, io_apic.io_apic_id);
+    trace!(
+        "IO APIC address: {:#X}, ID: {}",
+        io_apic.io_apic_address as usize,
+        io_apic.io_apic_id
+    );
 
-    let io_apic = IoApic { io_apic_address: io_apic.io_apic_address, io_apic_id: io_apic.io_apic_id };
+    let io_apic = IoApic {
+        io_apic_address: io_apic.io_apic_address,
+        io_apic_id: io_apic.io_apic_id,
+    };
     IO_APIC.call_once(|| io_apic);
 }
 
@@ -42,7 +49,13 @@ pub struct IoApic {
 
 impl IoApic {
     pub fn redirect(&self, irq: Irq, vector: u32) -> &Self {
-        let index = 0x10 + irq as u32 * 2;
+        self.redirect_input(irq as u8, vector)
+    }
+
+    /// Routes the interrupt input `input` of the IO APIC to `vector`, for devices that aren't
+    /// wired to a legacy IRQ.
+    pub fn redirect_input(&self, input: u8, vector: u32) -> &Self {
+        let index = 0x10 + input as u32 * 2;
         let high_index = index + 1;
 
         let low_part = (vector & 0xFF) | (1 << 15);
@@ -1,9 +1,19 @@
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
 use spin::Once;
 
 use crate::acpi::ACPI;
+use crate::arch::{instructions, pit, registers};
+use crate::bits::Bits;
 use crate::trace;
 
 pub static LOCAL_APIC: Once<LocalApic> = Once::new();
+
+/// How long the timer is measured against the PIT.
+const CALIBRATION_TIME: Duration = Duration::from_millis(10);
+
 pub fn init() {
     let madt = ACPI.get().expect("ACPI not initialized").madt;
     let local_apic = madt
@@ -13,6 +23,9 @@ pub fn init() {
                 local_apic_address: madt.local_apic_address,
                 acpi_processor_id: local.acpi_processor_id,
                 apic_id: local.apic_id,
+                timer: Timer::Periodic { frequency: 0 },
+                next_deadline: AtomicU64::new(0),
+                timer_period: AtomicU64::new(0),
             }),
             _ => None,
         })
@@ -25,12 +38,25 @@ pub fn init() {
         local_apic.apic_id
     );
 
+    let mut local_apic = local_apic;
     local_apic.init();
     LOCAL_APIC.call_once(|| local_apic);
 }
 
 pub fn end_of_interrupt() {
-    LOCAL_APIC.get().expect("Local APIC not initialized.").end_of_interrupt();
+    LOCAL_APIC
+        .get()
+        .expect("Local APIC not initialized.")
+        .end_of_interrupt();
+}
+
+/// Sets the next deadline of the timer, when it doesn't restart by itself. Called by every
+/// timer interrupt.
+pub fn rearm_timer() {
+    LOCAL_APIC
+        .get()
+        .expect("Local APIC not initialized.")
+        .rearm_timer();
 }
 
 #[repr(u32)]
@@ -44,6 +70,8 @@ enum Registers {
     LvtTimer = 0x320,
     // Timer initial count
     InitialCount = 0x380,
+    // Timer current count
+    CurrentCount = 0x390,
     // Timer divide configuration
     DivisorConfig = 0x3E0,
 }
@@ -51,10 +79,30 @@ pub struct LocalApic {
     local_apic_address: u32,
     acpi_processor_id: u8,
     apic_id: u8,
+    timer: Timer,
+    /// The time-stamp counter value of the next timer interrupt, in TSC-deadline mode.
+    next_deadline: AtomicU64,
+    /// How much the time-stamp counter advances between two interrupts, in TSC-deadline mode.
+    timer_period: AtomicU64,
 }
 
+/// How the timer measures time, with the frequencies measured at boot, in Hz.
+#[derive(Debug, Clone, Copy)]
+enum Timer {
+    /// The timer counts down from an initial count at the bus frequency, divided by 16, and
+    /// restarts when it reaches 0.
+    Periodic { frequency: u64 },
+    /// The timer fires when the time-stamp counter reaches a deadline, which is set again after
+    /// each interrupt. The deadlines don't drift, as they're absolute.
+    TscDeadline { tsc_frequency: u64 },
+}
+
+/// The timer mode field of the LVT timer register.
+const TIMER_MODE_PERIODIC: u32 = 0b01 << 17;
+const TIMER_MODE_TSC_DEADLINE: u32 = 0b10 << 17;
+
 impl LocalApic {
-    fn init(&self) {
+    fn init(&mut self) {
         // set task priority to 0 to allow all
         self.write(Registers::TaskPriority, 0);
 
@@ -66,23 +114,75 @@ impl LocalApic {
         let div = 0x03u32; // Divide by 16
         self.write(Registers::DivisorConfig, div);
 
-        // configure timer initial count
-        let timer_initial_count = 500_000_00;
-        self.write(Registers::InitialCount, timer_initial_count);
+        self.timer = self.calibrate_timer();
+        trace!("Local APIC timer: {:?}", self.timer);
+    }
+
+    /// Measures the frequency of the timer against the PIT, as it depends on the machine. The
+    /// time-stamp counter is used instead when the timer supports TSC-deadline mode.
+    fn calibrate_timer(&self) -> Timer {
+        let calibrations_per_second =
+            (Duration::from_secs(1).as_nanos() / CALIBRATION_TIME.as_nanos()) as u64;
+
+        self.write(Registers::InitialCount, u32::MAX);
+        let tsc_start = instructions::read_tsc();
+        pit::wait(CALIBRATION_TIME);
+        let tsc_elapsed = instructions::read_tsc() - tsc_start;
+        let elapsed = u32::MAX - self.read(Registers::CurrentCount);
+        self.write(Registers::InitialCount, 0);
+
+        let features = unsafe { __cpuid(1) };
+        if features.ecx.get_bit(24) {
+            Timer::TscDeadline {
+                tsc_frequency: tsc_elapsed * calibrations_per_second,
+            }
+        } else {
+            Timer::Periodic {
+                frequency: elapsed as u64 * calibrations_per_second,
+            }
+        }
     }
 
-    pub fn enable_interrupt(&self, vector: u8) {
-        let timer_mode = 1u32; // Periodic mode
-        // Periodic mode | Timer vector
-        let data = (timer_mode << 17) | vector as u32;
-        self.write(Registers::LvtTimer, data);
+    /// Starts the timer, which then raises the interrupt `vector` `frequency` times per second.
+    pub fn start_timer(&self, vector: u8, frequency: u64) {
+        match self.timer {
+            Timer::Periodic {
+                frequency: timer_frequency,
+            } => {
+                self.write(Registers::LvtTimer, TIMER_MODE_PERIODIC | vector as u32);
+                let initial_count = (timer_frequency / frequency).clamp(1, u32::MAX as u64);
+                self.write(Registers::InitialCount, initial_count as u32);
+            }
+            Timer::TscDeadline { tsc_frequency } => {
+                self.write(Registers::LvtTimer, TIMER_MODE_TSC_DEADLINE | vector as u32);
+                let period = tsc_frequency / frequency;
+                self.timer_period.store(period, Ordering::Relaxed);
+                let deadline = instructions::read_tsc() + period;
+                self.next_deadline.store(deadline, Ordering::Relaxed);
+                registers::write_msr(registers::IA32_TSC_DEADLINE, deadline);
+            }
+        }
+    }
 
+    fn rearm_timer(&self) {
+        if let Timer::TscDeadline { .. } = self.timer {
+            let period = self.timer_period.load(Ordering::Relaxed);
+            let deadline = self.next_deadline.fetch_add(period, Ordering::Relaxed) + period;
+            registers::write_msr(registers::IA32_TSC_DEADLINE, deadline);
+        }
     }
 
     pub fn end_of_interrupt(&self) {
         self.write(Registers::EndOfInterrupt, 0);
     }
 
+    fn read(&self, register: Registers) -> u32 {
+        unsafe {
+            let offset = (self.local_apic_address as u64 + register as u64) as *const u32;
+            core::ptr::read_volatile(offset)
+        }
+    }
+
     fn write(&self, register: Registers, value: u32) {
         unsafe {
             let offset = (self.local_apic_address as u64 + register as u64) as *mut u32;
@@ -1,4 +1,5 @@
 use core::arch::asm;
+use core::cell::SyncUnsafeCell;
 use core::mem::size_of;
 
 use spin::Once;
@@ -12,7 +13,7 @@ use crate::println;
 const STACK_SIZE: usize = 8192;
 pub static SELECTORS: Once<Selectors> = Once::new();
 static GDT: Once<GlobalDescriptorTable> = Once::new();
-static TSS: Once<TaskStateSegment> = Once::new();
+static TSS: Once<SyncUnsafeCell<TaskStateSegment>> = Once::new();
 
 pub fn init() {
     build();
@@ -20,6 +21,14 @@ pub fn init() {
     println!("Global Descriptor Table initialized.");
 }
 
+/// Sets the stack the CPU switches to when an interrupt arrives while running in ring 3.
+pub fn set_kernel_stack(stack_top: VirtualAddress) {
+    let tss = TSS.get().expect("TSS not initialized.").get();
+    unsafe {
+        (*tss).privilege_stack_table[0] = stack_top;
+    }
+}
+
 #[repr(packed)]
 pub struct TaskStateSegment {
     reserved_1: u32,
@@ -137,7 +146,7 @@ pub struct Selectors {
 }
 
 fn build() {
-    TSS.call_once(|| TaskStateSegment::new());
+    TSS.call_once(|| SyncUnsafeCell::new(TaskStateSegment::new()));
 
     let mut gdt = GlobalDescriptorTable::new();
 
@@ -145,7 +154,7 @@ fn build() {
     let kernel_data_selector = gdt.add_kernel_data_entry();
     let user_data_selector = gdt.add_user_data_entry();
     let user_code_selector = gdt.add_user_code_entry();
-    let tss_selector = gdt.add_tss_entry(TSS.get().unwrap());
+    let tss_selector = gdt.add_tss_entry(TSS.get().unwrap().get());
 
     let selectors = Selectors {
         kernel_code: kernel_code_selector,
@@ -1,8 +1,8 @@
 use core::arch::asm;
 use core::mem::size_of;
 
-use crate::arch::{InterruptFrame, PrivilegeLevel, SegmentSelector};
 use crate::arch::x86_64::registers::read_cs;
+use crate::arch::{InterruptFrame, PrivilegeLevel, SegmentSelector};
 use crate::bits::Bits;
 
 #[repr(C)]
@@ -51,8 +51,8 @@ impl InterruptDescriptorTable {
         self.set_handler(13, handler);
     }
 
-    pub fn set_page_fault_handler(&mut self, handler: Handler) {
-        self.set_handler(14, handler);
+    pub fn set_page_fault_handler(&mut self, handler: HandlerWithErrorCode) {
+        self.entries[14] = Entry::with_address(handler as u64);
     }
 
     pub fn set_handler(&mut self, index: usize, handler: Handler) {
@@ -60,16 +60,23 @@ impl InterruptDescriptorTable {
     }
 
     pub fn set_privilege_level(&mut self, index: usize, privilege_level: PrivilegeLevel) {
-        self.entries[index].options.0.set_bits(13..15, privilege_level as u16);
+        self.entries[index]
+            .options
+            .0
+            .set_bits(13..15, privilege_level as u16);
     }
 }
 
 type Handler = extern "x86-interrupt" fn(InterruptFrame);
+/// Handler of an exception for which the CPU pushes an error code after the interrupt frame.
+type HandlerWithErrorCode = extern "x86-interrupt" fn(InterruptFrame, u64);
 
 impl Entry {
     fn new(handler: Handler) -> Self {
-        let addr = handler as u64;
+        Self::with_address(handler as u64)
+    }
 
+    fn with_address(addr: u64) -> Self {
         Entry {
             gdt_selector: read_cs().0,
             pointer_low: addr as u16,
@@ -2,7 +2,14 @@ use core::arch::asm;
 
 /// Halt the CPU until the next interrupt.
 pub fn halt() {
+    unsafe { asm!("hlt", options(nomem, nostack, preserves_flags)) }
+}
+
+/// Reads the time-stamp counter, which counts at a constant rate since the CPU started.
+pub fn read_tsc() -> u64 {
+    let (high, low): (u32, u32);
     unsafe {
-        asm!("hlt", options(nomem, nostack, preserves_flags))
+        asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
     }
-}
\ No newline at end of file
+    ((high as u64) << 32) | (low as u64)
+}
@@ -1,15 +1,17 @@
 use core::arch::asm;
-use core::ptr;
-use spin::Once;
+use core::fmt;
 
-use kernel_api::syscall::{EXIT, SPAWN};
+use kernel_api::syscall::EXIT_SEGFAULT;
+use spin::Once;
 
 use crate::arch::apic::io_apic::{Irq, IO_APIC};
-use crate::arch::apic::local_apic::{end_of_interrupt, LOCAL_APIC};
-use crate::arch::x86_64::idt::{InterruptFrame, Registers};
+use crate::arch::apic::local_apic::{self, end_of_interrupt, LOCAL_APIC};
+use crate::arch::x86_64::idt::InterruptFrame;
 use crate::arch::x86_64::{idt, registers};
-use crate::arch::PrivilegeLevel;
-use crate::{println, syscall};
+use crate::arch::{Context, PrivilegeLevel};
+use crate::memory::vma::Access;
+use crate::process::{scheduler, table};
+use crate::{println, syscall, time};
 
 static IDT: Once<idt::InterruptDescriptorTable> = Once::new();
 
@@ -18,7 +20,9 @@ static IDT: Once<idt::InterruptDescriptorTable> = Once::new();
 pub enum InterruptVector {
     Timer = 0x20,
     Keyboard,
+    Hpet,
     Syscall = 0x80,
+    Yield,
 }
 
 pub(crate) fn init() {
@@ -30,7 +34,7 @@ pub(crate) fn init() {
     LOCAL_APIC
         .get()
         .expect("Local APIC not initialized.")
-        .enable_interrupt(InterruptVector::Timer as u8);
+        .start_timer(InterruptVector::Timer as u8, time::TIMER_FREQUENCY);
 
     IDT.call_once(|| {
         let mut idt = idt::InterruptDescriptorTable::new();
@@ -40,7 +44,15 @@ pub(crate) fn init() {
         idt.set_overflow_handler(double_fault_handler);
         idt.set_page_fault_handler(page_fault_handler);
 
-        idt.set_handler(InterruptVector::Timer as usize, timer_interrupt_handler);
+        idt.set_handler(
+            InterruptVector::Timer as usize,
+            timer_interrupt_handler_naked_wrap,
+        );
+        idt.set_handler(InterruptVector::Yield as usize, yield_handler_naked_wrap);
+        idt.set_handler(
+            InterruptVector::Hpet as usize,
+            hpet_interrupt_handler_naked_wrap,
+        );
         idt.set_handler(
             InterruptVector::Keyboard as usize,
             keyboard_interrupt_handler,
@@ -86,8 +98,87 @@ extern "x86-interrupt" fn general_protection_fault_handler(interrupt_frame: Inte
     }
 }
 
-extern "x86-interrupt" fn page_fault_handler(interrupt_frame: InterruptFrame) {
-    println!("Error: Page fault at {}\n{}", registers::read_cr2(), interrupt_frame);
+/// The error code of a page fault, describing the access that caused it.
+#[derive(Debug, Clone, Copy)]
+struct PageFaultErrorCode(u64);
+
+impl PageFaultErrorCode {
+    const PRESENT: u64 = 1 << 0;
+    const WRITE: u64 = 1 << 1;
+    const USER: u64 = 1 << 2;
+    const INSTRUCTION_FETCH: u64 = 1 << 4;
+
+    /// The page was present, so the access broke its protection.
+    fn present(&self) -> bool {
+        self.0 & Self::PRESENT != 0
+    }
+
+    fn write(&self) -> bool {
+        self.0 & Self::WRITE != 0
+    }
+
+    /// The access came from user mode.
+    fn user(&self) -> bool {
+        self.0 & Self::USER != 0
+    }
+
+    fn instruction_fetch(&self) -> bool {
+        self.0 & Self::INSTRUCTION_FETCH != 0
+    }
+
+    fn access(&self) -> Access {
+        if self.instruction_fetch() {
+            Access::Execute
+        } else if self.write() {
+            Access::Write
+        } else {
+            Access::Read
+        }
+    }
+}
+
+impl fmt::Display for PageFaultErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = match self.access() {
+            Access::Read => "read",
+            Access::Write => "write",
+            Access::Execute => "instruction fetch",
+        };
+        let page = if self.present() {
+            "protected"
+        } else {
+            "non-present"
+        };
+        let mode = if self.user() { "user" } else { "kernel" };
+        write!(f, "{} {} of a {} page ({:#x})", mode, access, page, self.0)
+    }
+}
+
+extern "x86-interrupt" fn page_fault_handler(interrupt_frame: InterruptFrame, error_code: u64) {
+    let addr = registers::read_cr2();
+    let error_code = PageFaultErrorCode(error_code);
+
+    // Only user accesses fault pages in, or break copy-on-write sharing. The kernel accesses user
+    // memory through the physical memory mapping.
+    if error_code.user() {
+        let handled = scheduler::current_address_space()
+            .is_some_and(|space| space.handle_page_fault(addr, error_code.access()));
+        if handled {
+            scheduler::exit_if_requested();
+            return;
+        }
+
+        println!(
+            "Segmentation fault: {} at {}\n{}",
+            error_code, addr, interrupt_frame
+        );
+        table::exit(EXIT_SEGFAULT);
+    }
+
+    println!(
+        "Error: Page fault: {} at {}\n{}",
+        error_code, addr, interrupt_frame
+    );
 
     loop {
         unsafe {
@@ -96,10 +187,6 @@ extern "x86-interrupt" fn page_fault_handler(interrupt_frame: InterruptFrame) {
     }
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_: InterruptFrame) {
-    end_of_interrupt();
-}
-
 extern "x86-interrupt" fn keyboard_interrupt_handler(_: InterruptFrame) {
     let code: u16;
     unsafe {
@@ -110,71 +197,144 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_: InterruptFrame) {
     end_of_interrupt();
 }
 
+macro_rules! push_registers {
+    () => {
+        "
+        push rbp
+        push rax
+        push rbx
+        push rcx
+        push rdx
+        push rsi
+        push rdi
+        push r8
+        push r9
+        push r10
+        push r11
+        push r12
+        push r13
+        push r14
+        push r15
+        "
+    };
+}
+
+macro_rules! pop_registers {
+    () => {
+        "
+        pop r15
+        pop r14
+        pop r13
+        pop r12
+        pop r11
+        pop r10
+        pop r9
+        pop r8
+        pop rdi
+        pop rsi
+        pop rdx
+        pop rcx
+        pop rbx
+        pop rax
+        pop rbp
+        "
+    };
+}
+
+/// Generates an interrupt wrapper that saves the [`Context`] of the interrupted task on its
+/// stack and calls `$handler` with a pointer to it. The handler returns the context to resume,
+/// which may belong to another task.
+macro_rules! context_switch_wrapper {
+    ($name:ident, $handler:path) => {
+        #[naked]
+        extern "x86-interrupt" fn $name(_: InterruptFrame) {
+            unsafe {
+                asm!(
+                push_registers!(),
+                "mov rdi, rsp",
+                "call {}",
+                "mov rsp, rax",
+                pop_registers!(),
+                "iretq",
+                sym $handler,
+                options(noreturn)
+                );
+            }
+        }
+    };
+}
+
+context_switch_wrapper!(timer_interrupt_handler_naked_wrap, timer_interrupt_handler);
+context_switch_wrapper!(yield_handler_naked_wrap, yield_handler);
+context_switch_wrapper!(hpet_interrupt_handler_naked_wrap, hpet_interrupt_handler);
+
+extern "C" fn timer_interrupt_handler(context: *mut Context) -> *mut Context {
+    end_of_interrupt();
+    local_apic::rearm_timer();
+    time::tick();
+    scheduler::schedule(context)
+}
+
+extern "C" fn yield_handler(context: *mut Context) -> *mut Context {
+    scheduler::schedule(context)
+}
+
+/// The one-shot timer of the HPET fired: a sleeping task is due, and runs right away.
+extern "C" fn hpet_interrupt_handler(context: *mut Context) -> *mut Context {
+    end_of_interrupt();
+    time::expire_timers();
+    scheduler::schedule(context)
+}
+
+/// Entry of `int 0x80`, the syscall path kept for code that can't use the `syscall` instruction.
 #[naked]
-pub extern "x86-interrupt" fn syscall_handler_naked_wrap(interrupt_frame: InterruptFrame) {
+pub extern "x86-interrupt" fn syscall_handler_naked_wrap(_: InterruptFrame) {
     unsafe {
         asm!(
-        "
-                push rbp
-                push rax
-                push rbx
-                push rcx
-                push rdx
-                push rsi
-                push rdi
-                push r8
-                push r9
-                push r10
-                push r11
-                push r12
-                push r13
-                push r14
-                push r15
-                mov rsi, rsp // registers
-                mov rdi, rsp
-                add rdi, 15*8 // interrupt_frame
-                call {}
-                pop r15
-                pop r14
-                pop r13
-                pop r12
-                pop r11
-                pop r10
-                pop r9
-                pop r8
-                pop rdi
-                pop rsi
-                pop rdx
-                pop rcx
-                pop rbx
-                pop rax
-                pop rbp
-                iretq
-            ",
+        push_registers!(),
+        "mov rdi, rsp",
+        "call {}",
+        pop_registers!(),
+        "iretq",
         sym syscall_handler,
         options(noreturn)
         );
     }
 }
 
-// TODO: Move to the process
-static mut STACK_FRAME: Option<InterruptFrame> = None;
-static mut REGISTERS: Option<Registers> = None;
+/// Runs the syscall of the task whose state was saved in `context`, and stores the result in its
+/// `rax`. The task terminates instead if it was asked to exit meanwhile.
+pub(crate) extern "C" fn syscall_handler(context: &mut Context) {
+    let regs = &context.registers;
+    let syscall_number = regs.rax;
+    let args = [regs.rdi, regs.rsi, regs.rdx, regs.r10, regs.r8, regs.r9];
+    let result = syscall::dispatcher(syscall_number, args, context);
+    context.set_syscall_result(result);
+    scheduler::exit_if_requested();
+}
 
-pub extern "C" fn syscall_handler(interrupt_frame: &mut InterruptFrame, regs: &mut Registers) {
-    if regs.rax == SPAWN {
+/// Runs `f` with interrupts disabled, so it can't be preempted. Locks that interrupt handlers or
+/// the scheduler take must be held this way, or a task switched out while holding one would
+/// deadlock the CPU.
+pub(crate) fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let flags: u64;
+    unsafe {
+        asm!("pushfq", "pop {}", "cli", out(reg) flags, options(nomem));
+    }
+    let result = f();
+    if flags & INTERRUPT_FLAG != 0 {
         unsafe {
-            STACK_FRAME = Some(interrupt_frame.clone());
-            REGISTERS = Some(regs.clone());
+            asm!("sti", options(nomem, nostack));
         }
     }
+    result
+}
 
-    syscall::dispatcher(regs.rax, regs.rdi, regs.rsi);
+const INTERRUPT_FLAG: u64 = 1 << 9;
 
-    if regs.rax == EXIT {
-        unsafe {
-            ptr::write_volatile(interrupt_frame as *mut InterruptFrame, STACK_FRAME.unwrap());
-            ptr::write_volatile(regs, REGISTERS.unwrap());
-        }
+/// Gives the CPU to the next ready task. Returns when the scheduler picks the caller again.
+pub(crate) fn yield_now() {
+    unsafe {
+        asm!("int {}", const InterruptVector::Yield as u8);
     }
 }
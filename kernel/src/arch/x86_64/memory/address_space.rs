@@ -0,0 +1,783 @@
+use alloc::vec::Vec;
+use core::ptr;
+
+use spin::Mutex;
+
+use crate::allocate_frame;
+use crate::arch::interrupt::without_interrupts;
+use crate::arch::memory::mapper::MemoryMapper;
+use crate::arch::memory::paging;
+use crate::arch::memory::paging::{Page, PageFlags, PageIter, PageTable, PAGE_SIZE};
+use crate::arch::registers::{read_cr3, write_cr3};
+use crate::memory::address::{PhysicalAddress, VirtualAddress};
+use crate::memory::allocator;
+use crate::memory::allocator::frame_allocator::FrameAllocator;
+use crate::memory::allocator::FRAME_ALLOCATOR;
+use crate::memory::frame::{FrameUsage, PhysicalFrame};
+use crate::memory::vma::{Access, Vma, VmaKind, VmaTree};
+use crate::memory::MEMORY_MAPPER;
+
+/// The end of the lower half, where user mappings live.
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+/// How much memory a level 4 entry maps.
+const LEVEL_4_ENTRY_SIZE: u64 = 1 << 39;
+
+/// A virtual address space with its own level 4 page table.
+///
+/// The kernel entries of the level 4 table (the higher half, plus the lower-half regions the
+/// kernel still relies on, like the identity map and the heap) are cloned from the kernel table
+/// when the address space is created. The lower level tables behind those entries are shared, so
+/// kernel mappings made later on are visible in every address space, as long as they don't need
+/// a new level 4 entry.
+///
+/// The user memory is described by virtual memory areas. Their pages are mapped on the first
+/// access, by [`AddressSpace::handle_page_fault`].
+pub struct AddressSpace {
+    mapper: MemoryMapper,
+    areas: Mutex<VmaTree>,
+}
+
+impl AddressSpace {
+    pub fn new() -> Self {
+        let kernel_mapper = MEMORY_MAPPER.get().expect("Memory mapper not initialized.");
+        let level_4_frame = allocate_frame!(FrameUsage::PageTable);
+        let mapper =
+            MemoryMapper::with_level_4_frame(kernel_mapper.physical_memory_offset, level_4_frame);
+
+        let kernel_table = kernel_mapper.level_4_table();
+        let table = mapper.level_4_table();
+        table.zero();
+        for index in 0..PageTable::ENTRY_COUNT {
+            let entry = &kernel_table[index];
+            if entry.present() && !entry.user_accessible() {
+                table[index] = *entry;
+            }
+        }
+
+        Self {
+            mapper,
+            areas: Mutex::new(VmaTree::new()),
+        }
+    }
+
+    pub fn level_4_frame(&self) -> PhysicalFrame {
+        self.mapper.level_4_frame
+    }
+
+    pub fn is_active(&self) -> bool {
+        PhysicalFrame::containing_address(read_cr3()) == self.level_4_frame()
+    }
+
+    /// Loads the level 4 table of this address space into CR3.
+    pub fn activate(&self) {
+        if !self.is_active() {
+            write_cr3(self.level_4_frame().start_address);
+        }
+    }
+
+    pub fn translate_addr(&self, addr: VirtualAddress) -> Option<PhysicalAddress> {
+        self.mapper.translate_addr(addr)
+    }
+
+    pub(crate) fn map_page(
+        &self,
+        page: Page,
+        frame: PhysicalFrame,
+        user_accessible: bool,
+        writable: bool,
+    ) {
+        let flags = PageFlags {
+            user_accessible,
+            writable,
+            executable: true,
+        };
+        self.map_page_with_flags(page, frame, flags)
+    }
+
+    /// Whether the page can be mapped for user mode: it's in the lower half, and not in a region
+    /// the kernel uses.
+    pub(crate) fn is_user_page(&self, page: Page) -> bool {
+        let entry = &self.mapper.level_4_table()[page.p4_index() as usize];
+        page.start_address.as_u64() < USER_SPACE_END
+            && (entry.is_unused() || entry.user_accessible())
+    }
+
+    /// Whether the whole range can be mapped for user mode, like [`AddressSpace::is_user_page`].
+    pub(crate) fn is_user_region(&self, start: VirtualAddress, end: VirtualAddress) -> bool {
+        if start >= end || end.as_u64() > USER_SPACE_END {
+            return false;
+        }
+        let table = self.mapper.level_4_table();
+        let last = VirtualAddress::new(end.as_u64() - 1);
+        (start.p4_index()..=last.p4_index()).all(|index| {
+            let entry = &table[index as usize];
+            entry.is_unused() || entry.user_accessible()
+        })
+    }
+
+    pub(crate) fn map_page_with_flags(&self, page: Page, frame: PhysicalFrame, flags: PageFlags) {
+        assert!(
+            self.is_user_page(page),
+            "Page {} belongs to the kernel region",
+            page.start_address
+        );
+        self.mapper.map_page_with_flags(page, frame, flags)
+    }
+
+    /// Maps the page to a newly allocated frame filled with zeros. The mapping is the only owner
+    /// of the frame, so it's freed when the page is unmapped.
+    pub(crate) fn map_zeroed_page(&self, page: Page, flags: PageFlags) -> PhysicalFrame {
+        let frame = self.zeroed_frame();
+        self.map_page_with_flags(page, frame, flags);
+        allocator::release_frame(frame);
+        frame
+    }
+
+    fn zeroed_frame(&self) -> PhysicalFrame {
+        let frame = allocate_frame!(FrameUsage::User);
+        unsafe {
+            ptr::write_bytes(self.frame_ptr(frame), 0, PAGE_SIZE as usize);
+        }
+        frame
+    }
+
+    /// Adds a user memory area. Returns `false` if it overlaps another area or the kernel region.
+    pub fn add_area(&self, vma: Vma) -> bool {
+        if !self.is_user_region(vma.start, vma.end) {
+            return false;
+        }
+        without_interrupts(|| self.areas.lock().insert(vma))
+    }
+
+    /// The user memory area containing `addr`.
+    pub fn find_area(&self, addr: VirtualAddress) -> Option<Vma> {
+        without_interrupts(|| self.areas.lock().find(addr).cloned())
+    }
+
+    /// Adds a user memory area of `size` bytes, at the lowest address from `from` on where it fits
+    /// between the other areas, and below `to`. Returns its address.
+    pub fn add_free_area(
+        &self,
+        size: u64,
+        from: VirtualAddress,
+        to: VirtualAddress,
+        flags: PageFlags,
+        kind: VmaKind,
+    ) -> Option<VirtualAddress> {
+        // Searching and inserting under one lock, so another thread can't take the range
+        without_interrupts(|| {
+            let mut areas = self.areas.lock();
+            let mut from = from;
+            let start = loop {
+                let start = areas.find_free(size, from, to)?;
+                if self.is_user_region(start, start + size) {
+                    break start;
+                }
+                // Skips a level 4 entry the kernel uses
+                from = VirtualAddress::new((start.as_u64() | (LEVEL_4_ENTRY_SIZE - 1)) + 1);
+            };
+            areas.insert(Vma::new(start, start + size, flags, kind));
+            Some(start)
+        })
+    }
+
+    /// Adds a user memory area in place of the areas and pages in its range. Returns `false` if
+    /// the range is in the kernel region.
+    pub fn replace_area(&self, vma: Vma) -> bool {
+        if !self.is_user_region(vma.start, vma.end) {
+            return false;
+        }
+        without_interrupts(|| {
+            let mut areas = self.areas.lock();
+            self.remove_range(&mut areas, vma.start, vma.end);
+            areas.insert(vma)
+        })
+    }
+
+    /// Removes the range from the areas, and unmaps its pages.
+    pub fn unmap_range(&self, start: VirtualAddress, end: VirtualAddress) {
+        without_interrupts(|| self.remove_range(&mut self.areas.lock(), start, end));
+    }
+
+    /// Removes the range from `areas` and unmaps the pages of the removed parts. The areas lock is
+    /// held throughout, so faults can't map the pages again in between.
+    fn remove_range(&self, areas: &mut VmaTree, start: VirtualAddress, end: VirtualAddress) {
+        for area in areas.remove_range(start, end) {
+            for page in Self::pages(area.start, area.end) {
+                self.unmap_page(page);
+            }
+        }
+    }
+
+    /// Gives the range new access rights, in its areas and in the pages already mapped. Returns
+    /// `false`, without changing anything, if part of the range isn't in an area.
+    pub fn protect_range(
+        &self,
+        start: VirtualAddress,
+        end: VirtualAddress,
+        flags: PageFlags,
+    ) -> bool {
+        // The pages are updated under the areas lock, so faults see the new rights
+        without_interrupts(|| {
+            let mut areas = self.areas.lock();
+            if !areas.protect_range(start, end, flags) {
+                return false;
+            }
+
+            for page in Self::pages(start, end) {
+                let Some(entry) = self.mapper.page_entry(page).filter(|entry| entry.present())
+                else {
+                    continue;
+                };
+                if flags.user_accessible {
+                    entry.set_user_accessible();
+                } else {
+                    entry.clear_user_accessible();
+                }
+                if flags.executable {
+                    entry.clear_no_execute();
+                } else {
+                    entry.set_no_execute();
+                }
+                if !flags.writable {
+                    entry.clear_writable();
+                    entry.clear_copy_on_write();
+                } else if entry.shared() {
+                    entry.set_writable();
+                } else if !entry.writable() {
+                    // The page may still be shared with a fork, the first write sorts it out
+                    entry.set_copy_on_write();
+                }
+                if self.is_active() {
+                    paging::flush_page(page.start_address);
+                }
+            }
+            true
+        })
+    }
+
+    /// The pages of a non-empty range.
+    fn pages(start: VirtualAddress, end: VirtualAddress) -> PageIter {
+        Page::range_inclusive(
+            Page::containing_address(start),
+            Page::containing_address(VirtualAddress::new(end.as_u64() - 1)),
+        )
+    }
+
+    /// Resolves a page fault at `addr`: maps a zeroed page if `addr` is in an area allowing the
+    /// access, growing stacks down as needed, or breaks copy-on-write sharing on writes. Returns
+    /// `false` if the access is invalid.
+    ///
+    /// Threads of a process can fault on the same page at once. The page tables are only changed
+    /// under the areas lock, after checking again whether another thread resolved the fault, and
+    /// whether the area changed while the frame was filled.
+    pub fn handle_page_fault(&self, addr: VirtualAddress, access: Access) -> bool {
+        if addr.as_u64() >= USER_SPACE_END {
+            return false;
+        }
+        let page = Page::containing_address(addr);
+        loop {
+            if let Some(resolved) = without_interrupts(|| {
+                let _areas = self.areas.lock();
+                self.fault_on_present_page(page, access)
+            }) {
+                return resolved;
+            }
+
+            let Some(area) = without_interrupts(|| self.areas.lock().fault_area(addr)) else {
+                return false;
+            };
+            if !area.allows(access) {
+                return false;
+            }
+            // The frame is filled without holding the lock, as reading the page of a file blocks
+            let filled = match area.object_page(addr) {
+                None => Some((self.zeroed_frame(), false)),
+                Some((object, index, true)) => object.page(index).map(|frame| (frame, true)),
+                Some((object, index, false)) => object.copy_page(index).map(|frame| (frame, false)),
+            };
+            // The file of the object couldn't be read
+            let Some((frame, shared)) = filled else {
+                return false;
+            };
+
+            let resolved = without_interrupts(|| {
+                let mut areas = self.areas.lock();
+                if let Some(resolved) = self.fault_on_present_page(page, access) {
+                    return Some(resolved);
+                }
+                // The area may have been unmapped, protected or replaced meanwhile
+                let current = areas.fault_area(addr)?;
+                if !current.maps_same_page(&area, addr) {
+                    return None;
+                }
+                self.map_page_with_flags(page, frame, area.flags);
+                if shared {
+                    let entry = self.mapper.page_entry(page).expect("Page not mapped");
+                    entry.set_shared();
+                }
+                Some(true)
+            });
+            // The pages of shared objects belong to the object, the others to the mapping
+            if !shared {
+                allocator::release_frame(frame);
+            }
+            if let Some(resolved) = resolved {
+                return resolved;
+            }
+        }
+    }
+
+    /// Resolves a fault on a page that is already mapped, or returns `None` if it isn't. Present
+    /// pages only fault on missing rights, unless another thread mapped them since the fault.
+    /// The caller holds the areas lock.
+    fn fault_on_present_page(&self, page: Page, access: Access) -> Option<bool> {
+        let entry = self
+            .mapper
+            .page_entry(page)
+            .filter(|entry| entry.present())?;
+        let allowed = entry.user_accessible()
+            && match access {
+                Access::Read => true,
+                Access::Write => entry.writable(),
+                Access::Execute => !entry.no_execute(),
+            };
+        Some(allowed || access == Access::Write && self.copy_on_write(page))
+    }
+
+    /// Unmaps the page, and frees its frame if no other mapping uses it.
+    pub fn unmap_page(&self, page: Page) {
+        self.mapper.unmap_page(page)
+    }
+
+    /// Copies `data` to `addr` through the physical memory mapping, so the address space doesn't
+    /// need to be active. The destination pages must be mapped.
+    pub fn write(&self, addr: VirtualAddress, data: &[u8]) {
+        let mut written = 0;
+        while written < data.len() {
+            let current = addr + written as u64;
+            let len =
+                (PAGE_SIZE - current.page_offset()).min((data.len() - written) as u64) as usize;
+            let phys = self
+                .translate_addr(current)
+                .unwrap_or_else(|| panic!("Address {} is not mapped", current));
+            let dest: *mut u8 = (self.mapper.physical_memory_offset + phys.as_u64()).as_mut_ptr();
+            unsafe {
+                ptr::copy_nonoverlapping(data[written..].as_ptr(), dest, len);
+            }
+            written += len;
+        }
+    }
+
+    /// Copies the user memory at `addr` into `buffer`. Returns `false`, without copying anything,
+    /// if part of the range isn't mapped for user mode.
+    pub fn read_user(&self, addr: VirtualAddress, buffer: &mut [u8]) -> bool {
+        self.with_user_chunks(addr, buffer.len(), false, |chunks| {
+            let mut read = 0;
+            for &(src, len) in chunks {
+                unsafe {
+                    ptr::copy_nonoverlapping(src, buffer[read..].as_mut_ptr(), len);
+                }
+                read += len;
+            }
+        })
+        .is_some()
+    }
+
+    /// Copies `data` to the user memory at `addr`. Returns `false`, without copying anything, if
+    /// part of the range isn't mapped as writable for user mode.
+    pub fn write_user(&self, addr: VirtualAddress, data: &[u8]) -> bool {
+        self.with_user_chunks(addr, data.len(), true, |chunks| {
+            let mut written = 0;
+            for &(dest, len) in chunks {
+                unsafe {
+                    ptr::copy_nonoverlapping(data[written..].as_ptr(), dest, len);
+                }
+                written += len;
+            }
+        })
+        .is_some()
+    }
+
+    /// Creates a copy of the user mappings for a forked process. The pages are shared
+    /// copy-on-write: the writable ones become read-only in both address spaces, and the first
+    /// write to them goes through [`AddressSpace::handle_page_fault`]. Pages of shared mappings
+    /// stay writable.
+    pub fn fork(&self) -> AddressSpace {
+        let child = AddressSpace::new();
+        // The areas lock keeps other threads from resolving faults while the tables are copied
+        without_interrupts(|| {
+            let areas = self.areas.lock();
+            *child.areas.lock() = areas.clone();
+            let table = self.mapper.level_4_table();
+            let child_table = child.mapper.level_4_table();
+            for index in 0..PageTable::ENTRY_COUNT {
+                let entry = &table[index];
+                if entry.user_accessible() {
+                    if let Some(frame) = entry.frame() {
+                        child_table[index] = *entry;
+                        child_table[index].set_frame(self.fork_table(frame, 3));
+                    }
+                }
+            }
+        });
+
+        // The writable pages of the parent just became read-only
+        if self.is_active() {
+            write_cr3(self.level_4_frame().start_address);
+        }
+        child
+    }
+
+    /// Resolves a write to a page shared by [`AddressSpace::fork`], by giving this address space
+    /// its own copy of the page, or by making it writable again if nobody else uses it. Returns
+    /// `false` if the page isn't copy-on-write. The caller holds the areas lock.
+    fn copy_on_write(&self, page: Page) -> bool {
+        let Some(entry) = self.mapper.page_entry(page) else {
+            return false;
+        };
+        if !entry.copy_on_write() {
+            return false;
+        }
+        let frame = entry.frame().expect("Copy-on-write page not present");
+
+        without_interrupts(|| {
+            let mut frame_allocator = frame_allocator().lock();
+            if frame_allocator.reference_count(frame) > 1 {
+                let copy = frame_allocator
+                    .allocate_frame(FrameUsage::User)
+                    .expect("Out of memory");
+                let src: *const u8 = self.frame_ptr(frame);
+                unsafe {
+                    ptr::copy_nonoverlapping(src, self.frame_ptr(copy), PAGE_SIZE as usize);
+                }
+                frame_allocator.release_frame(frame);
+                entry.set_frame(copy);
+            }
+        });
+        entry.clear_copy_on_write();
+        entry.set_writable();
+
+        if self.is_active() {
+            paging::flush_page(page.start_address);
+        }
+        true
+    }
+
+    /// Translates `addr` as a user access would, resolving the page fault it would cause. Like
+    /// writes from the kernel, `write` breaks copy-on-write sharing, so the page is private to this
+    /// address space afterwards.
+    pub fn translate_user(&self, addr: VirtualAddress, write: bool) -> Option<PhysicalAddress> {
+        if addr.as_u64() >= USER_SPACE_END {
+            return None;
+        }
+        let access = if write { Access::Write } else { Access::Read };
+        match self.mapper.translate_user_addr(addr, write) {
+            None if self.handle_page_fault(addr, access) => {
+                self.mapper.translate_user_addr(addr, write)
+            }
+            phys => phys,
+        }
+    }
+
+    /// Whether `page` is mapped from a shared mapping, whose frame every address space mapping
+    /// it uses.
+    pub fn is_shared_page(&self, page: Page) -> bool {
+        without_interrupts(|| {
+            let _areas = self.areas.lock();
+            self.mapper
+                .page_entry(page)
+                .is_some_and(|entry| entry.present() && entry.shared())
+        })
+    }
+
+    /// Whether the whole range is mapped for user mode, and writable if `write` is set.
+    pub fn is_user_range(&self, addr: VirtualAddress, len: usize, write: bool) -> bool {
+        self.with_user_chunks(addr, len, write, |_| ()).is_some()
+    }
+
+    /// Splits the user range into the pieces of each page, as pointers into the physical memory
+    /// mapping, and passes them to `f`. Returns `None` if part of the range isn't mapped for user
+    /// mode.
+    ///
+    /// Resolving the fault of a later page can block, and another thread can unmap an earlier
+    /// page meanwhile. A reference to each frame is held until `f` returns, so it's never freed
+    /// under the pointers.
+    fn with_user_chunks<R>(
+        &self,
+        addr: VirtualAddress,
+        len: usize,
+        write: bool,
+        f: impl FnOnce(&[(*mut u8, usize)]) -> R,
+    ) -> Option<R> {
+        let end = addr.as_u64().checked_add(len as u64)?;
+        if end > USER_SPACE_END {
+            return None;
+        }
+
+        let mut frames = Vec::new();
+        let mut chunks = Vec::new();
+        let mut done = 0;
+        while done < len {
+            let current = addr + done as u64;
+            let chunk_len = (PAGE_SIZE - current.page_offset()).min((len - done) as u64) as usize;
+            match self.share_user_frame(current, write) {
+                Some(phys) => {
+                    frames.push(PhysicalFrame::containing_address(phys));
+                    let ptr = (self.mapper.physical_memory_offset + phys.as_u64()).as_mut_ptr();
+                    chunks.push((ptr, chunk_len));
+                    done += chunk_len;
+                }
+                None => break,
+            }
+        }
+
+        let result = (done == len).then(|| f(&chunks));
+        frames.into_iter().for_each(allocator::release_frame);
+        result
+    }
+
+    /// Translates `addr` like [`AddressSpace::translate_user`], and adds a reference to its
+    /// frame. The page is looked up and its frame shared under the areas lock, so it can't be
+    /// unmapped in between.
+    fn share_user_frame(&self, addr: VirtualAddress, write: bool) -> Option<PhysicalAddress> {
+        let access = if write { Access::Write } else { Access::Read };
+        loop {
+            let phys = without_interrupts(|| {
+                let _areas = self.areas.lock();
+                let phys = self.mapper.translate_user_addr(addr, write)?;
+                allocator::share_frame(PhysicalFrame::containing_address(phys));
+                Some(phys)
+            });
+            // Writes from the kernel break copy-on-write sharing like user writes do
+            if phys.is_some() || !self.handle_page_fault(addr, access) {
+                return phys;
+            }
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Releases the user pages and the page tables of the address space. The kernel tables are
+    /// shared, so they're left alone.
+    fn drop(&mut self) {
+        assert!(
+            !self.is_active(),
+            "The active address space can't be released"
+        );
+
+        let table = self.mapper.level_4_table();
+        for index in 0..PageTable::ENTRY_COUNT {
+            let entry = &table[index];
+            if entry.user_accessible() {
+                if let Some(frame) = entry.frame() {
+                    self.release_table(frame, 3);
+                }
+            }
+        }
+        allocator::release_frame(self.level_4_frame());
+    }
+}
+
+impl AddressSpace {
+    /// Copies a user page table for [`AddressSpace::fork`], sharing the pages it maps.
+    fn fork_table(&self, table_frame: PhysicalFrame, level: usize) -> PhysicalFrame {
+        let copy_frame = allocate_frame!(FrameUsage::PageTable);
+        let table = self.mapper.page_table(table_frame);
+        let copy = self.mapper.page_table(copy_frame);
+        copy.zero();
+        for index in 0..PageTable::ENTRY_COUNT {
+            let entry = &mut table[index];
+            let Some(frame) = entry.frame() else {
+                continue;
+            };
+            if level > 1 && !entry.huge_page() {
+                copy[index] = *entry;
+                copy[index].set_frame(self.fork_table(frame, level - 1));
+            } else {
+                if entry.writable() && !entry.shared() {
+                    entry.clear_writable();
+                    entry.set_copy_on_write();
+                }
+                allocator::share_frame(frame);
+                copy[index] = *entry;
+            }
+        }
+        copy_frame
+    }
+
+    fn frame_ptr(&self, frame: PhysicalFrame) -> *mut u8 {
+        (self.mapper.physical_memory_offset + frame.start_address.as_u64()).as_mut_ptr()
+    }
+
+    fn release_table(&self, table_frame: PhysicalFrame, level: usize) {
+        let table = self.mapper.page_table(table_frame);
+        for index in 0..PageTable::ENTRY_COUNT {
+            let entry = &table[index];
+            if let Some(frame) = entry.frame() {
+                if level > 1 && !entry.huge_page() {
+                    self.release_table(frame, level - 1);
+                } else {
+                    allocator::release_frame(frame);
+                }
+            }
+        }
+        allocator::release_frame(table_frame);
+    }
+}
+
+fn frame_allocator() -> &'static Mutex<FrameAllocator> {
+    FRAME_ALLOCATOR
+        .get()
+        .expect("Frame allocator not initialized.")
+}
+
+/// Switches back to the kernel page table.
+pub fn activate_kernel() {
+    let kernel_mapper = MEMORY_MAPPER.get().expect("Memory mapper not initialized.");
+    if PhysicalFrame::containing_address(read_cr3()) != kernel_mapper.level_4_frame {
+        write_cr3(kernel_mapper.level_4_frame.start_address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_kernel_entries_are_shared() {
+        let address_space = AddressSpace::new();
+        let kernel_mapper = MEMORY_MAPPER.get().unwrap();
+
+        let kernel_addr = kernel_mapper.physical_memory_offset + 0x42;
+        assert_eq!(
+            address_space.translate_addr(kernel_addr),
+            kernel_mapper.translate_addr(kernel_addr)
+        );
+    }
+
+    #[test_case]
+    fn test_same_address_in_two_spaces() {
+        let first = AddressSpace::new();
+        let second = AddressSpace::new();
+        let page = Page::containing_address(VirtualAddress::new(0xC0_FFEE_0000));
+
+        let first_frame = allocate_frame!(FrameUsage::User);
+        let second_frame = allocate_frame!(FrameUsage::User);
+        first.map_page(page, first_frame, true, true);
+        second.map_page(page, second_frame, true, true);
+
+        let addr = page.start_address + PAGE_SIZE / 2;
+        assert_eq!(
+            first.translate_addr(addr),
+            Some(first_frame.start_address + PAGE_SIZE / 2)
+        );
+        assert_eq!(
+            second.translate_addr(addr),
+            Some(second_frame.start_address + PAGE_SIZE / 2)
+        );
+        assert_eq!(MEMORY_MAPPER.get().unwrap().translate_addr(addr), None);
+    }
+
+    #[test_case]
+    fn test_fork_copy_on_write() {
+        let parent = AddressSpace::new();
+        let page = Page::containing_address(VirtualAddress::new(0xC0_FFEE_0000));
+        let flags = PageFlags {
+            user_accessible: true,
+            writable: true,
+            executable: false,
+        };
+        let frame = parent.map_zeroed_page(page, flags);
+        parent.write(page.start_address, b"parent");
+
+        let child = parent.fork();
+        let mut buffer = [0; 6];
+        assert!(child.read_user(page.start_address, &mut buffer));
+        assert_eq!(&buffer, b"parent");
+        assert_eq!(
+            child.translate_addr(page.start_address),
+            Some(frame.start_address)
+        );
+        assert!(!parent.is_user_range(page.start_address, 1, true));
+
+        // The child gets its own copy, the parent keeps the original frame
+        assert!(child.write_user(page.start_address, b"child!"));
+        assert_ne!(
+            child.translate_addr(page.start_address),
+            Some(frame.start_address)
+        );
+        assert!(parent.handle_page_fault(page.start_address, Access::Write));
+        assert_eq!(
+            parent.translate_addr(page.start_address),
+            Some(frame.start_address)
+        );
+        assert!(parent.read_user(page.start_address, &mut buffer));
+        assert_eq!(&buffer, b"parent");
+        assert!(!without_interrupts(|| {
+            let _areas = parent.areas.lock();
+            parent.copy_on_write(page)
+        }));
+    }
+
+    #[test_case]
+    fn test_unmap_frees_frames() {
+        let address_space = AddressSpace::new();
+        let page = Page::containing_address(VirtualAddress::new(0xC0_FFEE_0000));
+        let flags = PageFlags {
+            user_accessible: true,
+            writable: true,
+            executable: false,
+        };
+        let frame = address_space.map_zeroed_page(page, flags);
+        assert_eq!(allocator::frame_usage(frame), FrameUsage::User);
+        let next_table = |table: PhysicalFrame, index: u16| {
+            address_space.mapper.page_table(table)[index as usize]
+                .frame()
+                .unwrap()
+        };
+        let level_3 = next_table(address_space.level_4_frame(), page.p4_index());
+        let level_2 = next_table(level_3, page.p3_index());
+        let level_1 = next_table(level_2, page.p2_index());
+
+        address_space.unmap_page(page);
+        assert_eq!(address_space.translate_addr(page.start_address), None);
+        assert_eq!(allocator::frame_usage(frame), FrameUsage::Free);
+        // The tables only held that page, but level 3 ones are kept
+        assert_eq!(allocator::frame_usage(level_1), FrameUsage::Free);
+        assert_eq!(allocator::frame_usage(level_2), FrameUsage::Free);
+        assert_eq!(allocator::frame_usage(level_3), FrameUsage::PageTable);
+    }
+
+    #[test_case]
+    fn test_demand_paging() {
+        let address_space = AddressSpace::new();
+        let start = VirtualAddress::new(0xC0_FFEE_0000);
+        let end = VirtualAddress::new(0xC0_FFEE_0000 + 2 * PAGE_SIZE);
+        let flags = PageFlags {
+            user_accessible: true,
+            writable: false,
+            executable: false,
+        };
+        let area = Vma::new(start, end, flags, VmaKind::Anonymous);
+        assert!(address_space.add_area(area.clone()));
+        assert!(!address_space.add_area(area));
+
+        assert_eq!(address_space.translate_addr(start), None);
+        assert!(!address_space.handle_page_fault(start, Access::Write));
+        assert!(!address_space.handle_page_fault(start, Access::Execute));
+        assert!(address_space.handle_page_fault(start + PAGE_SIZE + 8, Access::Read));
+        assert_eq!(address_space.translate_addr(start), None);
+        // Another thread faulting on the page once it's mapped just retries
+        assert!(address_space.handle_page_fault(start + PAGE_SIZE, Access::Read));
+        assert!(!address_space.handle_page_fault(start + PAGE_SIZE, Access::Write));
+
+        // Reads from the kernel fault pages in too, and they're zeroed
+        let mut buffer = [0xFF; 16];
+        let middle = VirtualAddress::new(0xC0_FFEE_0000 + PAGE_SIZE - 8);
+        assert!(address_space.read_user(middle, &mut buffer));
+        assert_eq!(buffer, [0; 16]);
+        let past_end = VirtualAddress::new(0xC0_FFEE_0000 + 2 * PAGE_SIZE - 8);
+        assert!(!address_space.read_user(past_end, &mut buffer));
+    }
+}
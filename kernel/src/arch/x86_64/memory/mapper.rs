@@ -1,22 +1,48 @@
 use crate::allocate_frame;
-use crate::arch::memory::paging::{Page, PageTable, PageTableEntry};
+use crate::arch::memory::paging;
+use crate::arch::memory::paging::{Page, PageFlags, PageTable, PageTableEntry};
 use crate::arch::registers::read_cr3;
 use crate::memory::address::{PhysicalAddress, VirtualAddress};
-use crate::memory::frame::PhysicalFrame;
+use crate::memory::allocator;
+use crate::memory::frame::{FrameUsage, PhysicalFrame};
 
 pub struct MemoryMapper {
     pub physical_memory_offset: VirtualAddress,
+    pub(crate) level_4_frame: PhysicalFrame,
 }
 
 impl MemoryMapper {
+    /// Creates a mapper for the currently active level 4 table.
     pub fn new(physical_memory_offset: VirtualAddress) -> Self {
+        Self::with_level_4_frame(
+            physical_memory_offset,
+            PhysicalFrame::containing_address(read_cr3()),
+        )
+    }
+
+    /// Creates a mapper for the level 4 table stored in `level_4_frame`, which doesn't need to be
+    /// the active one.
+    pub fn with_level_4_frame(
+        physical_memory_offset: VirtualAddress,
+        level_4_frame: PhysicalFrame,
+    ) -> Self {
         MemoryMapper {
             physical_memory_offset,
+            level_4_frame,
         }
     }
 
+    pub(crate) fn level_4_table(&self) -> &mut PageTable {
+        self.page_table(self.level_4_frame)
+    }
+
+    pub(crate) fn page_table(&self, frame: PhysicalFrame) -> &mut PageTable {
+        let page_table_virt = self.physical_memory_offset + frame.start_address.as_u64();
+        unsafe { &mut *page_table_virt.as_mut_ptr::<PageTable>() }
+    }
+
     pub fn translate_addr(&self, addr: VirtualAddress) -> Option<PhysicalAddress> {
-        let mut frame = PhysicalFrame::containing_address(read_cr3());
+        let mut frame = self.level_4_frame;
 
         let table_indexes = [
             addr.p4_index(),
@@ -58,6 +84,53 @@ impl MemoryMapper {
         Some(frame.start_address + addr.page_offset())
     }
 
+    /// Translates the address as a user mode access would: the entries of every level must allow
+    /// user access, and writes too if `write` is set.
+    pub(crate) fn translate_user_addr(
+        &self,
+        addr: VirtualAddress,
+        write: bool,
+    ) -> Option<PhysicalAddress> {
+        let mut frame = self.level_4_frame;
+
+        let table_indexes = [
+            addr.p4_index(),
+            addr.p3_index(),
+            addr.p2_index(),
+            addr.p1_index(),
+        ];
+
+        for &index in &table_indexes {
+            let page_table_entry = &self.page_table(frame)[index as usize];
+            if !page_table_entry.present()
+                || !page_table_entry.user_accessible()
+                || (write && !page_table_entry.writable())
+            {
+                return None;
+            }
+            // User pages are always 4KiB
+            if page_table_entry.huge_page() {
+                return None;
+            }
+            frame = page_table_entry.frame()?;
+        }
+
+        Some(frame.start_address + addr.page_offset())
+    }
+
+    /// The level 1 entry of the page, or `None` if an upper level doesn't lead to one.
+    pub(crate) fn page_entry(&self, page: Page) -> Option<&mut PageTableEntry> {
+        let mut frame = self.level_4_frame;
+        for index in [page.p4_index(), page.p3_index(), page.p2_index()] {
+            let page_table_entry = &self.page_table(frame)[index as usize];
+            if page_table_entry.huge_page() {
+                return None;
+            }
+            frame = page_table_entry.frame()?;
+        }
+        Some(&mut self.page_table(frame)[page.p1_index() as usize])
+    }
+
     pub(crate) fn map_page(
         &self,
         page: Page,
@@ -65,7 +138,16 @@ impl MemoryMapper {
         user_accessible: bool,
         writable: bool,
     ) {
-        let mut current_frame = PhysicalFrame::containing_address(read_cr3());
+        let flags = PageFlags {
+            user_accessible,
+            writable,
+            executable: true,
+        };
+        self.map_page_with_flags(page, frame, flags)
+    }
+
+    pub(crate) fn map_page_with_flags(&self, page: Page, frame: PhysicalFrame, flags: PageFlags) {
+        let mut current_frame = self.level_4_frame;
         let table_indexes = [
             page.p4_index(),
             page.p3_index(),
@@ -98,77 +180,104 @@ impl MemoryMapper {
                     if i == 3 {
                         current_frame = frame.clone();
                     } else {
-                        current_frame = allocate_frame!();
+                        current_frame = allocate_frame!(FrameUsage::PageTable);
+                        self.page_table(current_frame).zero();
                     }
                 }
             };
-            self.map_page_entry(
-                page_table_entry,
-                current_frame.clone(),
-                user_accessible,
-                writable,
-            )
+            if i == 3 {
+                self.map_page_entry(page_table_entry, current_frame.clone(), flags);
+                allocator::share_frame(frame);
+            } else {
+                // Upper levels never restrict execution, only the L1 entry does
+                let table_flags = PageFlags {
+                    executable: true,
+                    ..flags
+                };
+                self.map_page_entry(page_table_entry, current_frame.clone(), table_flags);
+            }
         }
     }
 
+    /// Unmaps the page, and drops the reference the mapping held to its frame. Level 1 and 2
+    /// tables left empty are freed too. Level 3 ones stay, as the kernel ones are shared by every
+    /// address space.
     pub fn unmap_page(&self, page: Page) {
-        let mut frame = PhysicalFrame::containing_address(read_cr3());
-
-        let table_indexes = [
+        let indexes = [
             page.p4_index(),
             page.p3_index(),
             page.p2_index(),
             page.p1_index(),
-        ];
-
-        let mut page_table_entry: Option<&mut PageTableEntry> = None;
-
-        for &index in &table_indexes {
-            let page_table_virt = self.physical_memory_offset + frame.start_address.as_u64();
-
-            let page_table_ptr: *mut PageTable = page_table_virt.as_mut_ptr();
-            let page_table = unsafe { &mut *page_table_ptr };
-            page_table_entry = Some(&mut page_table[index as usize]);
-
-            if page_table_entry.as_ref().is_some_and(|pte| pte.huge_page()) {
-                break;
+        ]
+        .map(usize::from);
+
+        // The tables on the way to the page, from level 4 down
+        let mut tables = [self.level_4_frame; 4];
+        let mut level = 0;
+        loop {
+            let page_table_entry = &mut self.page_table(tables[level])[indexes[level]];
+            if page_table_entry.huge_page() {
+                page_table_entry.set_unused();
+                paging::flush_page(page.start_address);
+                return;
             }
-
-            frame = match page_table_entry.as_ref().unwrap().frame() {
-                Some(frame) => frame,
-                None => return,
+            let Some(frame) = page_table_entry.frame() else {
+                return;
             };
+            if level == 3 {
+                page_table_entry.set_unused();
+                allocator::release_frame(frame);
+                break;
+            }
+            level += 1;
+            tables[level] = frame;
         }
 
-        if let Some(page_table_entry) = page_table_entry {
-            page_table_entry.set_unused()
+        for level in [3, 2] {
+            let table = tables[level];
+            if !self.page_table(table).is_empty()
+                || allocator::frame_usage(table) != FrameUsage::PageTable
+            {
+                break;
+            }
+            self.page_table(tables[level - 1])[indexes[level - 1]].set_unused();
+            allocator::release_frame(table);
         }
+        paging::flush_page(page.start_address);
     }
 
     fn map_page_entry(
         &self,
         page_table_entry: &mut PageTableEntry,
         frame: PhysicalFrame,
-        user_accessible: bool,
-        writable: bool,
+        flags: PageFlags,
     ) {
         page_table_entry.set_present();
-        if user_accessible {
+        if flags.user_accessible {
             page_table_entry.set_user_accessible();
         }
-        if writable {
+        if flags.writable {
             page_table_entry.set_writable();
         }
+        if !flags.executable {
+            page_table_entry.set_no_execute();
+        }
         page_table_entry.set_frame(frame);
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::arch::interrupt::without_interrupts;
+    use crate::memory::allocator::FRAME_ALLOCATOR;
     use crate::memory::MEMORY_MAPPER;
 
     use super::*;
 
+    fn reference_count(frame: PhysicalFrame) -> usize {
+        without_interrupts(|| FRAME_ALLOCATOR.get().unwrap().lock().reference_count(frame))
+    }
+
     #[test_case]
     fn test_translate_addr() {
         let mapper = unsafe { MEMORY_MAPPER.get_unchecked() };
@@ -178,11 +287,12 @@ mod tests {
         let phys = mapper.translate_addr(mapper.physical_memory_offset + 0x42);
         assert_eq!(phys, Some(PhysicalAddress::new(0x42)));
 
-        let phys = mapper.translate_addr(VirtualAddress::new(0xFEED_CAFE_000));
-        assert_eq!(phys, Some(PhysicalAddress::new(0x1000)));
+        // The heap is mapped to contiguous frames
+        let heap = mapper.translate_addr(VirtualAddress::new(0xFEED_CAFE_000));
+        assert!(heap.is_some());
 
         let phys = mapper.translate_addr(VirtualAddress::new(0xFEEDCB0A000));
-        assert_eq!(phys, Some(PhysicalAddress::new(0x10000)));
+        assert_eq!(phys, heap.map(|heap| heap + 0xC000));
 
         let phys = mapper.translate_addr(VirtualAddress::new(0xFEED_DEAD_0000));
         assert_eq!(phys, None);
@@ -199,7 +309,7 @@ mod tests {
 
         // map the page
         let page = Page::containing_address(virt);
-        let frame = allocate_frame!();
+        let frame = allocate_frame!(FrameUsage::Kernel);
         mapper.map_page(page, frame.clone(), false, false);
 
         // check that the page is mapped
@@ -214,22 +324,26 @@ mod tests {
         // map the page
         let virt = VirtualAddress::new(0xFEED_DEAD_2000);
         let page = Page::containing_address(virt);
-        let frame = allocate_frame!();
+        let frame = allocate_frame!(FrameUsage::Kernel);
         mapper.map_page(page, frame.clone(), false, false);
 
         // check that the page is mapped
         let phys = mapper.translate_addr(virt);
         assert_eq!(phys, Some(frame.start_address));
 
+        // the mapping holds a reference to the frame
+        assert_eq!(reference_count(frame), 2);
+
         // unmap the page
         mapper.unmap_page(page);
 
-        // check that the page is not mapped
+        // check that the page is not mapped, and the mapping dropped its reference
         let phys = mapper.translate_addr(virt);
         assert_eq!(phys, None);
+        assert_eq!(reference_count(frame), 1);
 
         // check that the page can be remapped
-        let frame = allocate_frame!();
+        let frame = allocate_frame!(FrameUsage::Kernel);
         mapper.map_page(page, frame.clone(), false, false);
         let phys = mapper.translate_addr(virt);
         assert_eq!(phys, Some(frame.start_address));
@@ -1,2 +1,3 @@
+pub(crate) mod address_space;
+pub(crate) mod mapper;
 pub(crate) mod paging;
-pub(crate) mod mapper;
\ No newline at end of file
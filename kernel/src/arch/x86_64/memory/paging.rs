@@ -1,7 +1,11 @@
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
 use core::fmt;
 use core::ops::{Index, IndexMut};
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use crate::arch::x86_64::registers;
+use crate::bits::Bits;
 use crate::memory::address::{PhysicalAddress, VirtualAddress};
 use crate::memory::frame::PhysicalFrame;
 
@@ -13,6 +17,48 @@ pub unsafe fn active_level_4_table(physical_memory_offset: u64) -> &'static mut
     &mut *page_table_ptr
 }
 
+static NO_EXECUTE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Enables the no-execute bit in page table entries, if the CPU supports it. Without it, every
+/// mapped page is executable.
+pub(crate) fn enable_no_execute() {
+    let extended_features = unsafe { __cpuid(0x8000_0001) };
+    if extended_features.edx.get_bit(20) {
+        let efer = registers::read_msr(registers::IA32_EFER);
+        registers::write_msr(registers::IA32_EFER, efer | 1 << 11);
+        NO_EXECUTE_ENABLED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Drops the translation of `addr` cached in the TLB.
+pub(crate) fn flush_page(addr: VirtualAddress) {
+    unsafe {
+        asm!("invlpg [{}]", in(reg) addr.as_u64(), options(nostack, preserves_flags));
+    }
+}
+
+/// Access rights of a mapped page.
+///
+/// x86_64 can't express execute-only pages with regular paging, so a page that is not writable is
+/// always readable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PageFlags {
+    pub user_accessible: bool,
+    pub writable: bool,
+    pub executable: bool,
+}
+
+impl PageFlags {
+    /// Flags that allow everything allowed by `self` or `other`, for pages shared by two mappings.
+    pub fn union(self, other: PageFlags) -> PageFlags {
+        PageFlags {
+            user_accessible: self.user_accessible || other.user_accessible,
+            writable: self.writable || other.writable,
+            executable: self.executable || other.executable,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(C)]
 pub struct Page {
@@ -74,6 +120,20 @@ pub(crate) struct PageTable {
     entries: [PageTableEntry; 512],
 }
 
+impl PageTable {
+    pub const ENTRY_COUNT: usize = 512;
+
+    pub fn zero(&mut self) {
+        for entry in self.entries.iter_mut() {
+            entry.set_unused();
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter().all(PageTableEntry::is_unused)
+    }
+}
+
 impl Index<usize> for PageTable {
     type Output = PageTableEntry;
 
@@ -88,6 +148,7 @@ impl IndexMut<usize> for PageTable {
     }
 }
 
+#[derive(Clone, Copy)]
 #[repr(transparent)]
 pub struct PageTableEntry {
     entry: u64,
@@ -124,7 +185,11 @@ impl PageTableEntry {
         self.entry |= 1 << 1;
     }
 
-    fn user_accessible(&self) -> bool {
+    pub fn clear_writable(&mut self) {
+        self.entry &= !(1 << 1);
+    }
+
+    pub fn user_accessible(&self) -> bool {
         (self.entry >> 2) & 1 == 1
     }
 
@@ -132,6 +197,10 @@ impl PageTableEntry {
         self.entry |= 1 << 2;
     }
 
+    pub fn clear_user_accessible(&mut self) {
+        self.entry &= !(1 << 2);
+    }
+
     fn write_through(&self) -> bool {
         (self.entry >> 3) & 1 == 1
     }
@@ -144,10 +213,49 @@ impl PageTableEntry {
         (self.entry >> 6) & 1 == 1
     }
 
+    /// Set by the kernel on user pages shared after a fork. They're mapped read-only, and get a
+    /// private copy on the first write.
+    pub fn copy_on_write(&self) -> bool {
+        (self.entry >> 9) & 1 == 1
+    }
+
+    pub fn set_copy_on_write(&mut self) {
+        self.entry |= 1 << 9;
+    }
+
+    pub fn clear_copy_on_write(&mut self) {
+        self.entry &= !(1 << 9);
+    }
+
+    /// Set by the kernel on pages of shared mappings. They stay writable after a fork, so both
+    /// processes keep seeing the same memory.
+    pub fn shared(&self) -> bool {
+        (self.entry >> 10) & 1 == 1
+    }
+
+    pub fn set_shared(&mut self) {
+        self.entry |= 1 << 10;
+    }
+
     pub(crate) fn huge_page(&self) -> bool {
         (self.entry >> 7) & 1 == 1
     }
 
+    pub fn no_execute(&self) -> bool {
+        (self.entry >> 63) & 1 == 1
+    }
+
+    /// Has no effect if the CPU doesn't support the no-execute bit.
+    pub fn set_no_execute(&mut self) {
+        if NO_EXECUTE_ENABLED.load(Ordering::Relaxed) {
+            self.entry |= 1 << 63;
+        }
+    }
+
+    pub fn clear_no_execute(&mut self) {
+        self.entry &= !(1 << 63);
+    }
+
     pub(crate) fn frame(&self) -> Option<PhysicalFrame> {
         if self.present() {
             Some(PhysicalFrame::containing_address(
@@ -191,7 +299,8 @@ impl fmt::Display for PageTableEntry {
         write!(f, ", write through: {}", self.write_through())?;
         write!(f, ", accessed: {}", self.accessed())?;
         write!(f, ", dirty: {}", self.dirty())?;
-        write!(f, ", huge page: {}", self.huge_page())
+        write!(f, ", huge page: {}", self.huge_page())?;
+        write!(f, ", no execute: {}", self.no_execute())
     }
 }
 
@@ -1,14 +1,25 @@
 use core::fmt;
 
+use crate::memory::address::VirtualAddress;
+
 pub(crate) mod apic;
 pub mod gdt;
 mod idt;
 pub(crate) mod instructions;
+#[macro_use]
 pub(crate) mod interrupt;
 pub(crate) mod memory;
+pub(crate) mod pit;
 pub(crate) mod port;
 pub(crate) mod registers;
 mod serial_writer;
+pub(crate) mod syscall;
+
+/// Sets the thread pointer, which is the FS base on x86_64. Thread-local variables are found
+/// through it.
+pub(crate) fn set_thread_pointer(thread_pointer: VirtualAddress) {
+    registers::write_msr(registers::IA32_FS_BASE, thread_pointer.as_u64());
+}
 
 #[repr(C, packed)]
 #[derive(Copy, PartialEq, Eq, Clone, PartialOrd, Ord, Hash, Debug)]
@@ -35,7 +46,7 @@ pub enum PrivilegeLevel {
     Ring3 = 3,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 #[repr(C)]
 pub struct Registers {
     pub r15: usize,
@@ -65,6 +76,67 @@ pub struct InterruptFrame {
     pub stack_segment: u64,
 }
 
+/// The state of a task saved on its kernel stack when it's interrupted, in the order it is pushed
+/// by the CPU and the interrupt wrappers.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Context {
+    pub registers: Registers,
+    pub frame: InterruptFrame,
+}
+
+impl Context {
+    const INTERRUPTS_ENABLED: u64 = 1 << 9;
+
+    /// Context that starts executing `entry` in ring 3 with the given stack.
+    pub fn new_user(entry: VirtualAddress, stack_pointer: VirtualAddress) -> Self {
+        let selectors = gdt::SELECTORS.get().expect("GDT not initialized.");
+        Self {
+            registers: Registers::default(),
+            frame: InterruptFrame {
+                instruction_pointer: entry.as_u64(),
+                code_segment: selectors.user_code.as_raw() as u64,
+                cpu_flags: Self::INTERRUPTS_ENABLED,
+                stack_pointer: stack_pointer.as_u64(),
+                stack_segment: selectors.user_data.as_raw() as u64,
+            },
+        }
+    }
+
+    /// Context that calls `entry(arg)` in ring 0 with the given stack. `entry` must not return.
+    pub fn new_kernel(entry: VirtualAddress, stack_pointer: VirtualAddress, arg: usize) -> Self {
+        let selectors = gdt::SELECTORS.get().expect("GDT not initialized.");
+        Self {
+            registers: Registers {
+                rdi: arg,
+                ..Registers::default()
+            },
+            frame: InterruptFrame {
+                instruction_pointer: entry.as_u64(),
+                code_segment: selectors.kernel_code.as_raw() as u64,
+                cpu_flags: Self::INTERRUPTS_ENABLED,
+                stack_pointer: stack_pointer.as_u64(),
+                stack_segment: selectors.kernel_data.as_raw() as u64,
+            },
+        }
+    }
+
+    /// Whether the context resumes in ring 3.
+    pub fn in_user_mode(&self) -> bool {
+        self.frame.code_segment & 3 == 3
+    }
+
+    /// Sets the first argument of the function the context starts in.
+    pub fn set_argument(&mut self, value: usize) {
+        self.registers.rdi = value;
+    }
+
+    /// Sets the value the task gets back from the syscall it's in.
+    pub fn set_syscall_result(&mut self, value: usize) {
+        self.registers.rax = value;
+    }
+}
+
 impl fmt::Display for InterruptFrame {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "InterruptFrame\n")?;
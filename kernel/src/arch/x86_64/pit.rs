@@ -0,0 +1,40 @@
+use core::hint;
+use core::time::Duration;
+
+use crate::arch::port;
+
+/// The frequency the counters of the PIT run at, in Hz.
+const FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL_2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+/// Controls the gate of channel 2 and the speaker, and reads back the output of channel 2.
+const SPEAKER_CONTROL: u16 = 0x61;
+
+const GATE: u8 = 1 << 0;
+const SPEAKER: u8 = 1 << 1;
+const OUTPUT: u8 = 1 << 5;
+
+/// The longest wait the 16-bit counter allows.
+pub(crate) const MAX_WAIT: Duration = Duration::from_millis(54);
+
+/// Busy-waits for `duration`, up to [`MAX_WAIT`], with channel 2 of the PIT. Its frequency is
+/// fixed, so it's used to measure the frequency of the other timers.
+pub(crate) fn wait(duration: Duration) {
+    assert!(duration <= MAX_WAIT, "PIT wait too long: {:?}", duration);
+    let count = (duration.as_nanos() as u64 * FREQUENCY / 1_000_000_000) as u16;
+
+    // Stop the channel and keep the speaker off while it's programmed
+    let control = port::read_8(SPEAKER_CONTROL) & !(GATE | SPEAKER);
+    port::write(SPEAKER_CONTROL, control);
+    // Channel 2, low then high byte, mode 0: the output goes high when the count reaches 0
+    port::write(COMMAND, 0b1011_0000);
+    port::write(CHANNEL_2, count as u8);
+    port::write(CHANNEL_2, (count >> 8) as u8);
+
+    port::write(SPEAKER_CONTROL, control | GATE);
+    while port::read_8(SPEAKER_CONTROL) & OUTPUT == 0 {
+        hint::spin_loop();
+    }
+    port::write(SPEAKER_CONTROL, control);
+}
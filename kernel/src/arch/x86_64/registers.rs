@@ -1,7 +1,15 @@
 use core::arch::asm;
 
 use crate::arch::SegmentSelector;
-use crate::memory::address::PhysicalAddress;
+use crate::memory::address::{PhysicalAddress, VirtualAddress};
+
+pub(crate) const IA32_TSC_DEADLINE: u32 = 0x6E0;
+pub(crate) const IA32_EFER: u32 = 0xC000_0080;
+pub(crate) const IA32_STAR: u32 = 0xC000_0081;
+pub(crate) const IA32_LSTAR: u32 = 0xC000_0082;
+pub(crate) const IA32_FMASK: u32 = 0xC000_0084;
+pub(crate) const IA32_FS_BASE: u32 = 0xC000_0100;
+pub(crate) const IA32_KERNEL_GS_BASE: u32 = 0xC000_0102;
 
 pub(crate) fn read_cs() -> SegmentSelector {
     let segment: u16;
@@ -78,14 +86,14 @@ pub fn set_gs(segment: SegmentSelector) {
     }
 }
 
-pub(crate) fn read_cr2() -> PhysicalAddress {
+pub(crate) fn read_cr2() -> VirtualAddress {
     let cr2: u64;
     unsafe {
         asm!("mov {}, cr2",
         out(reg) cr2,
         options(nomem, nostack, preserves_flags));
     }
-    return PhysicalAddress::new(cr2)
+    return VirtualAddress::new(cr2);
 }
 
 pub(crate) fn read_cr3() -> PhysicalAddress {
@@ -95,5 +103,33 @@ pub(crate) fn read_cr3() -> PhysicalAddress {
             out(reg) cr3,
             options(nomem, nostack, preserves_flags));
     }
-    return PhysicalAddress::new(cr3 & 0x_000f_ffff_ffff_f000) // Mask out the lower 12 bits
+    return PhysicalAddress::new(cr3 & 0x_000f_ffff_ffff_f000); // Mask out the lower 12 bits
+}
+
+pub(crate) fn write_cr3(level_4_table: PhysicalAddress) {
+    unsafe {
+        asm!("mov cr3, {}",
+            in(reg) level_4_table.as_u64(),
+            options(nostack, preserves_flags));
+    }
+}
+
+pub(crate) fn read_msr(msr: u32) -> u64 {
+    let (high, low): (u32, u32);
+    unsafe {
+        asm!("rdmsr",
+            in("ecx") msr,
+            out("eax") low, out("edx") high,
+            options(nomem, nostack, preserves_flags));
+    }
+    ((high as u64) << 32) | (low as u64)
+}
+
+pub(crate) fn write_msr(msr: u32, value: u64) {
+    unsafe {
+        asm!("wrmsr",
+            in("ecx") msr,
+            in("eax") value as u32, in("edx") (value >> 32) as u32,
+            options(nostack, preserves_flags));
+    }
 }
@@ -0,0 +1,124 @@
+use core::arch::asm;
+use core::cell::SyncUnsafeCell;
+use core::mem::offset_of;
+
+use crate::arch::gdt;
+use crate::arch::interrupt::syscall_handler;
+use crate::arch::registers::{
+    read_msr, write_msr, IA32_EFER, IA32_FMASK, IA32_KERNEL_GS_BASE, IA32_LSTAR, IA32_STAR,
+};
+use crate::memory::address::VirtualAddress;
+use crate::println;
+
+const EFER_SYSCALL_ENABLE: u64 = 1 << 0;
+/// The flags cleared when entering the kernel: trap, interrupts, direction and alignment check.
+const FLAGS_MASK: u64 = 1 << 8 | 1 << 9 | 1 << 10 | 1 << 18;
+
+/// What the `syscall` entry needs before it has a stack. It's reached through `gs` after `swapgs`.
+#[repr(C)]
+struct SyscallData {
+    /// The top of the kernel stack of the current task.
+    kernel_stack: u64,
+    /// Where the user stack pointer is kept while the entry switches stacks.
+    user_stack: u64,
+    user_code: u64,
+    user_data: u64,
+}
+
+static SYSCALL_DATA: SyncUnsafeCell<SyscallData> = SyncUnsafeCell::new(SyscallData {
+    kernel_stack: 0,
+    user_stack: 0,
+    user_code: 0,
+    user_data: 0,
+});
+
+pub(crate) fn init() {
+    let selectors = gdt::SELECTORS.get().expect("GDT not initialized.");
+    let user_code = selectors.user_code.as_raw() as u64;
+    let user_data = selectors.user_data.as_raw() as u64;
+    unsafe {
+        let data = &mut *SYSCALL_DATA.get();
+        data.user_code = user_code;
+        data.user_data = user_data;
+    }
+
+    // SYSCALL loads CS from STAR[47:32], and SS from the next descriptor. SYSRET loads SS from
+    // STAR[63:48] + 8 and CS from STAR[63:48] + 16, so the user code descriptor must follow the
+    // user data one.
+    assert_eq!(user_code, user_data + 8, "The GDT order doesn't fit SYSRET");
+    let kernel_base = selectors.kernel_code.as_raw() as u64;
+    let user_base = user_data - 8;
+    write_msr(IA32_STAR, user_base << 48 | kernel_base << 32);
+    write_msr(IA32_LSTAR, syscall_entry as usize as u64);
+    write_msr(IA32_FMASK, FLAGS_MASK);
+    write_msr(IA32_KERNEL_GS_BASE, SYSCALL_DATA.get() as u64);
+    write_msr(IA32_EFER, read_msr(IA32_EFER) | EFER_SYSCALL_ENABLE);
+
+    println!("Fast system calls enabled.");
+}
+
+/// Sets the stack `syscall` switches to. It's the same one interrupts from ring 3 use.
+pub(crate) fn set_kernel_stack(stack_top: VirtualAddress) {
+    unsafe {
+        (*SYSCALL_DATA.get()).kernel_stack = stack_top.as_u64();
+    }
+}
+
+/// Entry of the `syscall` instruction. It saves a [`Context`](crate::arch::Context) on the kernel
+/// stack of the task, as an interrupt from ring 3 would, so the rest of the kernel can't tell the
+/// two paths apart.
+#[naked]
+extern "C" fn syscall_entry() {
+    unsafe {
+        asm!(
+        "swapgs",
+        "mov gs:[{user_stack}], rsp",
+        "mov rsp, gs:[{kernel_stack}]",
+        "push qword ptr gs:[{user_data}]",
+        "push qword ptr gs:[{user_stack}]",
+        "push r11", // flags
+        "push qword ptr gs:[{user_code}]",
+        "push rcx", // return address
+        "swapgs",
+        push_registers!(),
+        "mov rdi, rsp",
+        "call {handler}",
+        pop_registers!(),
+        // SYSRET faults in ring 0 if the return address isn't canonical, so anything outside the
+        // lower half goes back through iretq
+        "mov rcx, [rsp]",
+        "mov r11, rcx",
+        "shr r11, 47",
+        "jnz 2f",
+        "mov r11, [rsp + 16]",
+        "mov rsp, [rsp + 24]",
+        "sysretq",
+        "2:",
+        "iretq",
+        kernel_stack = const offset_of!(SyscallData, kernel_stack),
+        user_stack = const offset_of!(SyscallData, user_stack),
+        user_code = const offset_of!(SyscallData, user_code),
+        user_data = const offset_of!(SyscallData, user_data),
+        handler = sym syscall_handler,
+        options(noreturn)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_syscall_msrs() {
+        let selectors = gdt::SELECTORS.get().unwrap();
+        let star = read_msr(IA32_STAR);
+        let user_base = (star >> 48) as u16;
+
+        assert_eq!((star >> 32) as u16, selectors.kernel_code.as_raw());
+        assert_eq!((user_base + 16) | 3, selectors.user_code.as_raw());
+        assert_eq!((user_base + 8) | 3, selectors.user_data.as_raw());
+        assert_eq!(read_msr(IA32_LSTAR), syscall_entry as usize as u64);
+        assert_ne!(read_msr(IA32_EFER) & EFER_SYSCALL_ENABLE, 0);
+    }
+}
@@ -2,6 +2,8 @@ use alloc::format;
 use alloc::string::String;
 use alloc::vec::Vec;
 
+use crate::time::date::DateTime;
+
 #[derive(Debug)]
 pub struct DirectoryEntry {
     pub name: String,
@@ -28,6 +30,18 @@ impl DirectoryEntry {
         self.attributes & 0x10 != 0
     }
 
+    /// When the file was created, if the file system recorded it.
+    pub fn created(&self) -> Option<DateTime> {
+        let mut created = DateTime::from_fat(self.creation_date, self.creation_time)?;
+        // Hundredths of a second, up to 199, below the 2 second resolution of the time field
+        created.second += self.creation_time_tenths / 100;
+        Some(created)
+    }
+
+    pub fn modified(&self) -> Option<DateTime> {
+        DateTime::from_fat(self.modification_date, self.modification_time)
+    }
+
     pub fn cluster(&self) -> u32 {
         ((self.cluster_high as u32) << 16) | self.cluster_low as u32
     }
@@ -306,6 +306,8 @@ mod tests {
         let entry = entry.unwrap();
         assert_eq!(entry.file_name(), "DEEPFILE.TXT");
         assert_eq!(entry.size, 0x22);
+        // Copied to the image when it was built
+        assert!(entry.modified().unwrap().year >= 2024);
     }
 
     #[test_case]
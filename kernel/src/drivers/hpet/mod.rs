@@ -0,0 +1,221 @@
+use core::ptr;
+use core::time::Duration;
+
+use spin::Once;
+
+use crate::acpi::ACPI;
+use crate::arch::apic::io_apic::IO_APIC;
+use crate::arch::interrupt::InterruptVector;
+use crate::arch::memory::paging::Page;
+use crate::bits::Bits;
+use crate::memory::address::{PhysicalAddress, VirtualAddress};
+use crate::memory::frame::PhysicalFrame;
+use crate::memory::MEMORY_MAPPER;
+use crate::time::ClockSource;
+use crate::{println, time, trace};
+
+pub(crate) static HPET: Once<Hpet> = Once::new();
+
+const FEMTOSECONDS_PER_NANOSECOND: u128 = 1_000_000;
+/// The interrupt inputs of the IO APIC.
+const IO_APIC_INPUTS: usize = 24;
+
+/// The registers, as offsets from the base address.
+#[repr(usize)]
+enum Registers {
+    Capabilities = 0x000,
+    Configuration = 0x010,
+    MainCounter = 0x0F0,
+}
+
+/// The registers of comparator `n` are at `0x100 + 0x20 * n`.
+const fn timer_configuration(n: usize) -> usize {
+    0x100 + 0x20 * n
+}
+
+const fn timer_comparator(n: usize) -> usize {
+    0x108 + 0x20 * n
+}
+
+const ENABLE: u64 = 1 << 0;
+const LEGACY_REPLACEMENT: u64 = 1 << 1;
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_LEVEL_TRIGGERED: u64 = 1 << 1;
+
+/// High Precision Event Timer: a main counter running at a fixed frequency, and comparators that
+/// raise an interrupt when the counter reaches them.
+/// Specification: https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/software-developers-hpet-spec-1-0a.pdf
+pub(crate) struct Hpet {
+    base_address: u64,
+    /// The period of the main counter, in femtoseconds.
+    period: u64,
+    pub comparator_count: usize,
+    /// The comparator used for one-shot timers, and the IO APIC input it's routed to.
+    one_shot: Option<(usize, u8)>,
+}
+
+impl Hpet {
+    fn new(base_address: u64, comparator_count: usize) -> Self {
+        Self {
+            base_address,
+            period: 0,
+            comparator_count,
+            one_shot: None,
+        }
+    }
+
+    /// Starts the main counter from 0. Returns `false` if the HPET can't be used as a clock
+    /// source, as its counter is only 32 bits wide and wraps around within minutes.
+    fn init(&mut self) -> bool {
+        self.map_address();
+
+        let capabilities = self.read(Registers::Capabilities as usize);
+        if !capabilities.get_bit(13) {
+            return false;
+        }
+        self.period = capabilities.get_bits(32..64);
+
+        let configuration = self.read(Registers::Configuration as usize);
+        self.write(
+            Registers::Configuration as usize,
+            configuration & !(ENABLE | LEGACY_REPLACEMENT),
+        );
+        self.write(Registers::MainCounter as usize, 0);
+
+        for n in 0..self.comparator_count {
+            let timer = self.read(timer_configuration(n));
+            self.write(timer_configuration(n), timer & !TIMER_INTERRUPT_ENABLE);
+        }
+        self.one_shot = self.init_one_shot();
+
+        let configuration = self.read(Registers::Configuration as usize);
+        self.write(Registers::Configuration as usize, configuration | ENABLE);
+        true
+    }
+
+    /// Sets up the first comparator as a one-shot, edge-triggered timer, routed to the highest
+    /// IO APIC input it allows, where it doesn't share the line with a legacy device.
+    fn init_one_shot(&self) -> Option<(usize, u8)> {
+        let timer = self.read(timer_configuration(0));
+        // The comparator must be 64 bits wide, like the counter
+        if !timer.get_bit(5) {
+            return None;
+        }
+        let routes = timer.get_bits(32..64) as u32;
+        let input = (0..IO_APIC_INPUTS as u8)
+            .rev()
+            .find(|input| routes.get_bit(*input as usize))?;
+
+        self.write(timer_comparator(0), u64::MAX);
+        let mut timer = timer & !(TIMER_PERIODIC | TIMER_LEVEL_TRIGGERED);
+        timer.set_bits(9..14, input as u64);
+        self.write(timer_configuration(0), timer | TIMER_INTERRUPT_ENABLE);
+
+        IO_APIC
+            .get()
+            .expect("IO APIC not initialized.")
+            .redirect_input(input, InterruptVector::Hpet as u32);
+        Some((0, input))
+    }
+
+    fn map_address(&self) {
+        let page = Page::containing_address(VirtualAddress::new(self.base_address));
+        let mapper = MEMORY_MAPPER.get().expect("Memory mapper not initialized.");
+        if mapper.translate_addr(page.start_address).is_none() {
+            let frame = PhysicalFrame::containing_address(PhysicalAddress::new(self.base_address));
+            mapper.map_page(page, frame, false, true);
+        }
+    }
+
+    pub fn frequency(&self) -> u64 {
+        (1_000_000_000 * FEMTOSECONDS_PER_NANOSECOND / self.period as u128) as u64
+    }
+
+    pub fn counter(&self) -> u64 {
+        self.read(Registers::MainCounter as usize)
+    }
+
+    /// Time since the main counter started.
+    pub fn elapsed(&self) -> Duration {
+        let nanoseconds =
+            self.counter() as u128 * self.period as u128 / FEMTOSECONDS_PER_NANOSECOND;
+        Duration::from_nanos(nanoseconds as u64)
+    }
+
+    /// Raises an interrupt when [`Hpet::elapsed`] reaches `deadline`, replacing the previous
+    /// one-shot timer. Returns `false` if the HPET has no comparator for it.
+    pub fn set_one_shot(&self, deadline: Duration) -> bool {
+        let Some((comparator, _)) = self.one_shot else {
+            return false;
+        };
+        let count = deadline.as_nanos() * FEMTOSECONDS_PER_NANOSECOND / self.period as u128;
+        self.write(timer_comparator(comparator), count as u64);
+        true
+    }
+
+    fn read(&self, offset: usize) -> u64 {
+        unsafe { ptr::read_volatile((self.base_address as usize + offset) as *const u64) }
+    }
+
+    fn write(&self, offset: usize, value: u64) {
+        unsafe { ptr::write_volatile((self.base_address as usize + offset) as *mut u64, value) }
+    }
+}
+
+pub(crate) fn init() {
+    let Some(table) = ACPI.get().expect("ACPI not initialized").hpet else {
+        println!("No HPET found.");
+        return;
+    };
+    if !table.is_memory_mapped() {
+        println!("HPET registers aren't memory mapped.");
+        return;
+    }
+
+    let mut hpet = Hpet::new(table.base_address(), table.comparator_count() as usize);
+    if !hpet.init() {
+        println!("HPET counter is too narrow to keep time.");
+        return;
+    }
+    trace!("HPET one-shot timer: {:?}", hpet.one_shot);
+    println!("HPET initialized. Frequency: {}Hz", hpet.frequency());
+
+    let hpet = HPET.call_once(|| hpet);
+    time::set_clock_source(ClockSource {
+        read: elapsed,
+        arm: hpet.one_shot.map(|_| set_one_shot as fn(Duration)),
+    });
+}
+
+fn elapsed() -> Duration {
+    HPET.get().expect("HPET not initialized.").elapsed()
+}
+
+fn set_one_shot(deadline: Duration) {
+    HPET.get()
+        .expect("HPET not initialized.")
+        .set_one_shot(deadline);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::arch::interrupt::without_interrupts;
+    use crate::arch::pit;
+
+    use super::*;
+
+    #[test_case]
+    fn test_clock_source() {
+        let hpet = HPET.get().unwrap();
+        assert!(hpet.frequency() > 0);
+
+        // No tick happens meanwhile, the time comes from the HPET
+        let (start, end) = without_interrupts(|| {
+            let start = time::now();
+            pit::wait(Duration::from_millis(2));
+            (start, time::now())
+        });
+        assert!(end - start >= Duration::from_micros(1500));
+    }
+}
@@ -2,11 +2,15 @@ use crate::println;
 use core::mem::MaybeUninit;
 
 mod pci;
+pub(crate) mod hpet;
 pub(crate) mod nvme;
+pub(crate) mod rtc;
 pub(crate) mod fs;
 pub mod keyboard;
 
 pub fn init() {
+    hpet::init();
+    rtc::init();
     pci::init();
     nvme::init();
     fs::init();
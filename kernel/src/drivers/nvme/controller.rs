@@ -2,8 +2,6 @@ use alloc::vec::Vec;
 use core::fmt;
 use core::mem::MaybeUninit;
 
-use spin::Mutex;
-
 use crate::arch::instructions;
 use crate::arch::memory::paging::Page;
 use crate::bits::Bits;
@@ -16,6 +14,7 @@ use crate::memory::address::{PhysicalAddress, VirtualAddress};
 use crate::memory::allocator::dma_allocator::Dma;
 use crate::memory::frame::PhysicalFrame;
 use crate::memory::MEMORY_MAPPER;
+use crate::sync::mutex::Mutex;
 use crate::trace;
 
 const NVME_QUEUE_SIZE: usize = 10;
@@ -1,9 +1,10 @@
-use core::{fmt, hint};
+use core::fmt;
 
 use crate::bits::Bits;
 use crate::drivers::nvme::command::NvmeCommand;
 use crate::memory::address::PhysicalAddress;
 use crate::memory::allocator::dma_allocator::Dma;
+use crate::process::kthread;
 
 /// A group of queues that are used to submit and complete commands.
 struct Submission;
@@ -91,9 +92,11 @@ impl Queue<Submission> {
 }
 
 impl Queue<Completion> {
+    /// Waits for the controller to post the next completion. Completion interrupts aren't set up,
+    /// so the queue is polled, and the CPU goes to other tasks in between.
     pub fn wait_for_completion(&mut self) {
         while self.commands[self.tail].phase() != self.phase {
-            hint::spin_loop();
+            kthread::yield_now();
         }
 
         self.tail = (self.tail + 1) % self.commands.len();
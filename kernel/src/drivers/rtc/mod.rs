@@ -0,0 +1,174 @@
+use core::time::Duration;
+
+use crate::acpi::ACPI;
+use crate::arch::interrupt::without_interrupts;
+use crate::arch::port;
+use crate::time::date::DateTime;
+use crate::{println, time};
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+/// The CMOS registers of the real-time clock.
+#[repr(u8)]
+enum Registers {
+    Seconds = 0x00,
+    Minutes = 0x02,
+    Hours = 0x04,
+    Day = 0x07,
+    Month = 0x08,
+    Year = 0x09,
+    StatusA = 0x0A,
+    StatusB = 0x0B,
+}
+
+/// Set in status register A while the clock updates its registers.
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+/// Set in status register B when the hours count from 0 to 23 instead of 1 to 12.
+const HOURS_24: u8 = 1 << 1;
+/// Set in status register B when the registers hold binary values instead of BCD.
+const BINARY: u8 = 1 << 2;
+/// Set in the hours register for PM hours in 12-hour mode.
+const PM: u8 = 1 << 7;
+
+/// Reads the date from the real-time clock and sets the wall-clock time from it.
+pub(crate) fn init() {
+    let now = read();
+    time::set_realtime(now.to_unix());
+    println!("RTC initialized. Date: {} UTC", now);
+}
+
+/// Time since the Unix epoch, according to the real-time clock. Its resolution is a second.
+pub(crate) fn realtime() -> Duration {
+    read().to_unix()
+}
+
+/// Reads the current date from the real-time clock. The clock is assumed to run in UTC.
+pub(crate) fn read() -> DateTime {
+    let century_register = ACPI
+        .get()
+        .and_then(|acpi| acpi.fadt)
+        .and_then(|fadt| fadt.century_register());
+
+    // The registers can change between two reads, so they're read until they agree
+    let mut raw = read_raw(century_register);
+    loop {
+        let again = read_raw(century_register);
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+    let status_b = read_register(Registers::StatusB as u8);
+    raw.decode(status_b)
+}
+
+/// The registers of the clock as they're stored, in BCD or binary, and in 12 or 24-hour mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RawDateTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: Option<u8>,
+}
+
+impl RawDateTime {
+    fn decode(&self, status_b: u8) -> DateTime {
+        let value = |raw: u8| {
+            if status_b & BINARY != 0 {
+                raw
+            } else {
+                from_bcd(raw)
+            }
+        };
+
+        let mut hour = value(self.hour & !PM);
+        if status_b & HOURS_24 == 0 {
+            // 12 AM is midnight, and 12 PM noon
+            hour %= 12;
+            if self.hour & PM != 0 {
+                hour += 12;
+            }
+        }
+
+        // Without a century register, the clock is assumed to be in the 21st century
+        let century = self.century.map_or(20, value) as u16;
+        DateTime {
+            year: century * 100 + value(self.year) as u16,
+            month: value(self.month),
+            day: value(self.day),
+            hour,
+            minute: value(self.minute),
+            second: value(self.second),
+        }
+    }
+}
+
+fn read_raw(century_register: Option<u8>) -> RawDateTime {
+    without_interrupts(|| {
+        while read_register(Registers::StatusA as u8) & UPDATE_IN_PROGRESS != 0 {
+            core::hint::spin_loop();
+        }
+        RawDateTime {
+            second: read_register(Registers::Seconds as u8),
+            minute: read_register(Registers::Minutes as u8),
+            hour: read_register(Registers::Hours as u8),
+            day: read_register(Registers::Day as u8),
+            month: read_register(Registers::Month as u8),
+            year: read_register(Registers::Year as u8),
+            century: century_register.map(read_register),
+        }
+    })
+}
+
+fn read_register(register: u8) -> u8 {
+    port::write(CMOS_ADDRESS, register);
+    port::read_8(CMOS_DATA)
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_decode() {
+        let raw = RawDateTime {
+            second: 0x56,
+            minute: 0x34,
+            hour: 0x12 | PM,
+            day: 0x29,
+            month: 0x02,
+            year: 0x24,
+            century: Some(0x20),
+        };
+        let date_time = raw.decode(0);
+        assert_eq!(date_time.to_unix(), Duration::from_secs(1_709_210_096));
+
+        let midnight = RawDateTime {
+            second: 56,
+            minute: 34,
+            hour: 12,
+            day: 29,
+            month: 2,
+            year: 24,
+            century: None,
+        };
+        let date_time = midnight.decode(BINARY);
+        assert_eq!(date_time.to_unix(), Duration::from_secs(1_709_166_896));
+        assert_eq!(midnight.decode(BINARY | HOURS_24).hour, 12);
+    }
+
+    #[test_case]
+    fn test_realtime() {
+        assert!(realtime() > Duration::from_secs(1_700_000_000));
+        let difference = time::realtime().abs_diff(realtime());
+        assert!(difference < Duration::from_secs(5));
+    }
+}
@@ -9,6 +9,7 @@
 #![feature(strict_provenance)]
 #![feature(maybe_uninit_as_bytes)]
 #![feature(naked_functions)]
+#![feature(sync_unsafe_cell)]
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
@@ -26,7 +27,9 @@ pub mod print;
 mod serial;
 pub(crate) mod bits;
 mod process;
+mod sync;
 mod syscall;
+mod time;
 pub mod utils;
 
 pub fn init() {
@@ -39,6 +42,8 @@ pub fn init() {
     arch::apic::init();
     arch::gdt::init();
     arch::interrupt::init();
+    arch::syscall::init();
+    process::init();
 
     unsafe {
         asm!("sti");
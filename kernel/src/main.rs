@@ -11,7 +11,7 @@ use core::arch::asm;
 #[no_mangle]
 unsafe extern "C" fn _start() -> ! {
     kernel::init();
-    kernel_api::syscall::spawn("/boot/init");
+    kernel_api::syscall::spawn("/boot/init", &[]).expect("Failed to start the init process");
 
     loop {
         asm!("hlt");
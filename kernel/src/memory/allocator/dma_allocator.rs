@@ -6,8 +6,10 @@ use core::ptr;
 use core::ptr::NonNull;
 
 use crate::allocate_frame;
+use crate::arch::interrupt::without_interrupts;
 use crate::memory::address::PhysicalAddress;
 use crate::memory::allocator::FRAME_ALLOCATOR;
+use crate::memory::frame::FrameUsage;
 use crate::memory::MEMORY_MAPPER;
 
 #[derive(Debug)]
@@ -62,7 +64,9 @@ impl<T> Dma<[MaybeUninit<T>]> {
 pub struct DmaAllocator;
 unsafe impl Allocator for DmaAllocator {
     fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
-        let frame_address = allocate_frame!(layout.size()).start_address.as_u64();
+        let frame_address = allocate_frame!(FrameUsage::Dma, layout.size())
+            .start_address
+            .as_u64();
         let virt_address = MEMORY_MAPPER.get().unwrap().physical_memory_offset + frame_address;
 
         let ptr = unsafe { NonNull::new_unchecked(virt_address.as_mut_ptr()) };
@@ -74,11 +78,13 @@ unsafe impl Allocator for DmaAllocator {
         let phys_addr = PhysicalAddress::new(
             ptr.as_ptr() as u64 - MEMORY_MAPPER.get().unwrap().physical_memory_offset.as_u64(),
         );
-        FRAME_ALLOCATOR
-            .get()
-            .unwrap()
-            .lock()
-            .deallocate_frames(phys_addr, layout.size());
+        without_interrupts(|| {
+            FRAME_ALLOCATOR
+                .get()
+                .unwrap()
+                .lock()
+                .deallocate_frames(phys_addr, layout.size())
+        });
     }
 }
 
@@ -113,9 +119,11 @@ mod tests {
         let addr = block.addr();
         drop(block);
 
-        let block = Dma::<[u8; 0x100_000]>::zeroed();
-        let block2 = Dma::<[u8; 0x1000]>::zeroed();
-        assert_eq!(addr.as_u64(), block2.addr().as_u64());
+        // The freed frames merge back into the block they came from
+        let block = Dma::<[u8; 0x101_000]>::zeroed();
+        assert_eq!(addr.as_u64(), block.addr().as_u64());
+        // Contiguous, and aligned to the next power of two
+        assert_eq!(addr.as_u64() % 0x200_000, 0);
     }
 
     #[test_case]
@@ -138,8 +146,9 @@ mod tests {
             // after deallocation, allocate a blocks of 5 and 3 elements
             let block = Dma::<Block>::new_zeroed_slice(5).assume_init();
             let block2 = Dma::<Block>::new_zeroed_slice(3).assume_init();
-            // check if the address is the same as the first allocation
-            assert_eq!(addr, block2.addr());
+            // check that they are aligned to their size
+            assert_eq!(block.addr().as_u64() % 0x2000, 0);
+            assert_ne!(block.addr(), block2.addr());
         }
 
         // after deallocation, allocate one block of 8 elements again
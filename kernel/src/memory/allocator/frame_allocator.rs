@@ -1,70 +1,382 @@
-use alloc::vec::Vec;
+use core::mem::size_of;
+use core::slice;
 
 use limine::memory_map::{Entry, EntryType};
 
-use crate::memory::address::PhysicalAddress;
-use crate::memory::frame::{PhysicalFrame, FRAME_SIZE};
+use crate::memory::address::{PhysicalAddress, VirtualAddress};
+use crate::memory::frame::{FrameUsage, PhysicalFrame, FRAME_SIZE};
 
-struct UsableBlock {
-    start: u64,
-    size: u64,
+/// Blocks have up to 2^MAX_ORDER frames, i.e. 1GiB.
+const MAX_ORDER: usize = 18;
+/// Order of the frames that don't start a free block: allocated, reserved, or inside a larger
+/// free block.
+const NOT_FREE: u8 = u8::MAX;
+/// End of a free list.
+const NONE: usize = usize::MAX;
+
+/// What the allocator knows about a frame.
+#[derive(Debug, Clone, Copy)]
+struct FrameInfo {
+    /// The order of the free block starting at this frame, or [`NOT_FREE`].
+    order: u8,
+    usage: FrameUsage,
+    /// Number of owners of an allocated frame: the code that allocated it, or the mappings it
+    /// was handed over to.
+    reference_count: u32,
 }
 
-pub struct FrameAllocator {
-    // TODO: Keep the iterator instead of the memory map
-    memory_map: &'static [&'static Entry],
-    reusable_frames: Vec<PhysicalFrame>,
+impl FrameInfo {
+    const RESERVED: Self = Self {
+        order: NOT_FREE,
+        usage: FrameUsage::Reserved,
+        reference_count: 0,
+    };
+
+    fn is_allocated(&self) -> bool {
+        !matches!(self.usage, FrameUsage::Reserved | FrameUsage::Free)
+    }
+}
+
+/// Links of a free list, stored in the first frame of each free block.
+struct FreeBlock {
     next: usize,
+    prev: usize,
+}
+
+/// Buddy allocator over the usable entries of the memory map.
+///
+/// Free memory is made of blocks of 2^order frames, aligned to their size, with a free list per
+/// order. An allocation splits the smallest free block that fits, and a freed block merges with
+/// its buddy, the other half of the block it was split from, as long as that one is free too.
+/// Frames are numbered by their physical address divided by [`FRAME_SIZE`].
+///
+/// Each frame also has a usage and a reference count. Allocated frames start with one reference,
+/// and are freed by [`FrameAllocator::release_frame`] once the last one is dropped.
+pub struct FrameAllocator {
+    physical_memory_offset: VirtualAddress,
+    frames: &'static mut [FrameInfo],
+    free_lists: [usize; MAX_ORDER + 1],
+    total_frames: usize,
+    free_frames: usize,
 }
 
 impl FrameAllocator {
-    pub fn new(entries: &'static [&'static Entry]) -> Self {
-        let reusable_frames = Vec::new();
-        Self {
-            memory_map: entries,
-            reusable_frames,
-            next: 0,
+    /// Takes over the usable memory. The frame metadata is kept at the start of the first usable
+    /// entry large enough for it, so this doesn't need the heap.
+    pub fn new(entries: &'static [&'static Entry], physical_memory_offset: VirtualAddress) -> Self {
+        let usable_ranges = || {
+            entries
+                .iter()
+                .filter(|entry| entry.entry_type == EntryType::USABLE)
+                .map(|entry| {
+                    let start = entry.base.div_ceil(FRAME_SIZE as u64) as usize;
+                    let end = ((entry.base + entry.length) / FRAME_SIZE as u64) as usize;
+                    (start, end)
+                })
+                .filter(|(start, end)| start < end)
+        };
+
+        let frame_count = usable_ranges().map(|(_, end)| end).max().unwrap_or(0);
+        let info_frames = (frame_count * size_of::<FrameInfo>()).div_ceil(FRAME_SIZE);
+        let (info_start, _) = usable_ranges()
+            .find(|(start, end)| end - start >= info_frames)
+            .expect("No memory for the frame allocator");
+        let frames = unsafe {
+            let address = physical_memory_offset + (info_start * FRAME_SIZE) as u64;
+            slice::from_raw_parts_mut(address.as_mut_ptr::<FrameInfo>(), frame_count)
+        };
+        frames.fill(FrameInfo::RESERVED);
+
+        let mut allocator = Self {
+            physical_memory_offset,
+            frames,
+            free_lists: [NONE; MAX_ORDER + 1],
+            total_frames: 0,
+            free_frames: 0,
+        };
+        for (start, end) in usable_ranges() {
+            if start == info_start {
+                allocator.free_range(start + info_frames, end);
+            } else {
+                allocator.free_range(start, end);
+            }
         }
+        allocator.total_frames = allocator.free_frames + info_frames;
+        allocator.set_allocated(info_start, info_frames, FrameUsage::Kernel);
+        allocator
     }
 
-    pub fn allocate_frame(&mut self) -> Option<PhysicalFrame> {
-        self.allocate_frames(FRAME_SIZE)
+    pub fn allocate_frame(&mut self, usage: FrameUsage) -> Option<PhysicalFrame> {
+        self.allocate_frames(FRAME_SIZE, usage)
     }
 
-    // TODO: Should be contiguous frames in some cases
-    pub fn allocate_frames(&mut self, size: usize) -> Option<PhysicalFrame> {
-        let frame_count = size.div_ceil(FRAME_SIZE);
-
-        // TODO: Allow fetching partial frames
-        if self.reusable_frames.len() >= frame_count {
-            let frame = self
-                .reusable_frames
-                .split_off(self.reusable_frames.len() - frame_count);
-            return Some(frame[0]);
+    /// Allocates physically contiguous frames for `size` bytes. The first one is aligned to the
+    /// size rounded up to a power of two frames.
+    pub fn allocate_frames(&mut self, size: usize, usage: FrameUsage) -> Option<PhysicalFrame> {
+        let count = size.div_ceil(FRAME_SIZE).max(1);
+        let order = count.next_power_of_two().trailing_zeros() as usize;
+        if order > MAX_ORDER {
+            return None;
         }
 
-        let frame = self.usable_frames().nth(self.next);
-        self.next += frame_count;
-        frame
+        let start = self.allocate_block(order)?;
+        self.free_frames -= 1 << order;
+        // Only the frames asked for are kept, the end of the block is free again
+        self.free_range(start + count, start + (1 << order));
+        self.set_allocated(start, count, usage);
+        Some(Self::frame(start))
     }
 
+    /// Frees frames from [`FrameAllocator::allocate_frames`], or part of them, whatever their
+    /// reference counts.
     pub fn deallocate_frames(&mut self, start_address: PhysicalAddress, size: usize) {
-        let end_address = start_address + (size as u64 - 1);
+        let start = Self::frame_number(PhysicalFrame::containing_address(start_address));
+        self.free_range(start, start + size.div_ceil(FRAME_SIZE));
+    }
 
-        let start_frame = PhysicalFrame::containing_address(start_address);
-        let end_frame = PhysicalFrame::containing_address(end_address);
+    /// Adds a reference to an allocated frame, which then needs one more
+    /// [`FrameAllocator::release_frame`] to be freed. Frames the allocator doesn't manage are
+    /// ignored.
+    pub fn share_frame(&mut self, frame: PhysicalFrame) {
+        if let Some(info) = self.info_mut(frame) {
+            info.reference_count += 1;
+        }
+    }
 
-        PhysicalFrame::range_inclusive(start_frame, end_frame).for_each(|frame| {
-            self.reusable_frames.push(frame);
-        });
-        self.reusable_frames.sort();
+    /// Drops a reference to the frame, and frees it once nothing else uses it.
+    pub fn release_frame(&mut self, frame: PhysicalFrame) {
+        let Some(info) = self.info_mut(frame) else {
+            return;
+        };
+        assert!(
+            info.reference_count > 0,
+            "Frame {} released twice",
+            frame.start_address
+        );
+        info.reference_count -= 1;
+        if info.reference_count == 0 {
+            let number = Self::frame_number(frame);
+            self.free_range(number, number + 1);
+        }
+    }
+
+    /// The number of references to an allocated frame, 0 for the others.
+    pub fn reference_count(&self, frame: PhysicalFrame) -> usize {
+        self.frames
+            .get(Self::frame_number(frame))
+            .map_or(0, |info| info.reference_count as usize)
+    }
+
+    pub fn usage(&self, frame: PhysicalFrame) -> FrameUsage {
+        self.frames
+            .get(Self::frame_number(frame))
+            .map_or(FrameUsage::Reserved, |info| info.usage)
     }
 
-    fn usable_frames(&self) -> impl Iterator<Item = PhysicalFrame> {
-        self.memory_map
+    /// Number of frames currently allocated for `usage`, to find out where memory goes.
+    pub fn frames_used_for(&self, usage: FrameUsage) -> usize {
+        self.frames
             .iter()
-            .filter(|entry| entry.entry_type == EntryType::USABLE)
-            .flat_map(|entry| (entry.base..entry.base + entry.length).step_by(FRAME_SIZE))
-            .map(|address| PhysicalFrame::containing_address(PhysicalAddress::new(address)))
+            .filter(|info| info.usage == usage)
+            .count()
+    }
+
+    /// Number of frames managed by the allocator.
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+
+    /// Takes a free block of `order`, splitting a larger one if needed.
+    fn allocate_block(&mut self, order: usize) -> Option<usize> {
+        let found = (order..=MAX_ORDER).find(|&order| self.free_lists[order] != NONE)?;
+        let start = self.free_lists[found];
+        self.remove(start, found);
+        // Keep the lower half at each split, and free the upper one
+        for order in (order..found).rev() {
+            self.push(start + (1 << order), order);
+        }
+        Some(start)
+    }
+
+    fn set_allocated(&mut self, start: usize, count: usize, usage: FrameUsage) {
+        for info in &mut self.frames[start..start + count] {
+            info.usage = usage;
+            info.reference_count = 1;
+        }
+    }
+
+    /// The metadata of an allocated frame.
+    fn info_mut(&mut self, frame: PhysicalFrame) -> Option<&mut FrameInfo> {
+        self.frames
+            .get_mut(Self::frame_number(frame))
+            .filter(|info| info.is_allocated())
+    }
+
+    /// Frees the frames `start..end`, as the largest aligned blocks that fit.
+    fn free_range(&mut self, mut start: usize, end: usize) {
+        for info in &mut self.frames[start..end] {
+            info.usage = FrameUsage::Free;
+            info.reference_count = 0;
+        }
+        while start < end {
+            let mut order = (start.trailing_zeros() as usize).min(MAX_ORDER);
+            while start + (1 << order) > end {
+                order -= 1;
+            }
+            self.free_block(start, order);
+            self.free_frames += 1 << order;
+            start += 1 << order;
+        }
+    }
+
+    /// Frees a block, merging it with its buddy as long as the buddy is free.
+    fn free_block(&mut self, mut start: usize, mut order: usize) {
+        debug_assert_eq!(self.frames[start].order, NOT_FREE, "Frame freed twice");
+        while order < MAX_ORDER {
+            let buddy = start ^ (1 << order);
+            if self.frames.get(buddy).map(|info| info.order) != Some(order as u8) {
+                break;
+            }
+            self.remove(buddy, order);
+            start = start.min(buddy);
+            order += 1;
+        }
+        self.push(start, order);
+    }
+
+    fn push(&mut self, start: usize, order: usize) {
+        let next = self.free_lists[order];
+        unsafe {
+            self.block(start).write(FreeBlock { next, prev: NONE });
+            if next != NONE {
+                (*self.block(next)).prev = start;
+            }
+        }
+        self.free_lists[order] = start;
+        self.frames[start].order = order as u8;
+    }
+
+    fn remove(&mut self, start: usize, order: usize) {
+        let FreeBlock { next, prev } = unsafe { self.block(start).read() };
+        unsafe {
+            if prev != NONE {
+                (*self.block(prev)).next = next;
+            }
+            if next != NONE {
+                (*self.block(next)).prev = prev;
+            }
+        }
+        if prev == NONE {
+            self.free_lists[order] = next;
+        }
+        self.frames[start].order = NOT_FREE;
+    }
+
+    fn block(&self, start: usize) -> *mut FreeBlock {
+        (self.physical_memory_offset + (start * FRAME_SIZE) as u64).as_mut_ptr()
+    }
+
+    fn frame(number: usize) -> PhysicalFrame {
+        PhysicalFrame::containing_address(PhysicalAddress::new((number * FRAME_SIZE) as u64))
+    }
+
+    fn frame_number(frame: PhysicalFrame) -> usize {
+        frame.start_address.as_u64() as usize / FRAME_SIZE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::arch::interrupt::without_interrupts;
+    use crate::memory::allocator::FRAME_ALLOCATOR;
+
+    use super::*;
+
+    #[test_case]
+    fn test_contiguous_frames() {
+        without_interrupts(|| {
+            let mut allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+            let free = allocator.free_frames();
+            assert_eq!(allocator.used_frames() + free, allocator.total_frames());
+
+            let frame = allocator
+                .allocate_frames(5 * FRAME_SIZE, FrameUsage::Kernel)
+                .unwrap();
+            let start = FrameAllocator::frame_number(frame);
+            assert_eq!(start % 8, 0);
+            assert_eq!(allocator.free_frames(), free - 5);
+            // The 3 frames after the allocation went back to the free lists
+            assert!(allocator.frames[start..start + 5]
+                .iter()
+                .all(|info| info.order == NOT_FREE && info.usage == FrameUsage::Kernel));
+            assert_eq!(allocator.frames[start + 5].order, 0);
+            assert_eq!(allocator.frames[start + 6].order, 1);
+
+            // Freed frames merge with their buddies, so the same block is found again
+            allocator.deallocate_frames(frame.start_address, 5 * FRAME_SIZE);
+            assert_eq!(allocator.free_frames(), free);
+            assert_eq!(
+                allocator.allocate_frames(5 * FRAME_SIZE, FrameUsage::Kernel),
+                Some(frame)
+            );
+            allocator.deallocate_frames(frame.start_address, 5 * FRAME_SIZE);
+        });
+    }
+
+    #[test_case]
+    fn test_merge() {
+        without_interrupts(|| {
+            let mut allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+            let free = allocator.free_frames();
+            let block = allocator
+                .allocate_frames(4 * FRAME_SIZE, FrameUsage::Kernel)
+                .unwrap();
+            let start = FrameAllocator::frame_number(block);
+
+            // Its buddy is still allocated
+            allocator.deallocate_frames(block.start_address + FRAME_SIZE as u64, FRAME_SIZE);
+            assert_eq!(allocator.frames[start + 1].order, 0);
+            allocator.deallocate_frames(block.start_address, FRAME_SIZE);
+            assert_eq!(allocator.frames[start].order, 1);
+            assert_eq!(allocator.frames[start + 1].order, NOT_FREE);
+            allocator
+                .deallocate_frames(block.start_address + 2 * FRAME_SIZE as u64, 2 * FRAME_SIZE);
+            assert_ne!(allocator.frames[start].order, 1);
+            assert_eq!(allocator.free_frames(), free);
+        });
+    }
+
+    #[test_case]
+    fn test_reference_counts() {
+        without_interrupts(|| {
+            let mut allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+            let user_frames = allocator.frames_used_for(FrameUsage::User);
+            let frame = allocator.allocate_frame(FrameUsage::User).unwrap();
+            assert_eq!(allocator.usage(frame), FrameUsage::User);
+            assert_eq!(allocator.frames_used_for(FrameUsage::User), user_frames + 1);
+            assert_eq!(allocator.reference_count(frame), 1);
+
+            allocator.share_frame(frame);
+            assert_eq!(allocator.reference_count(frame), 2);
+            allocator.release_frame(frame);
+            assert_eq!(allocator.usage(frame), FrameUsage::User);
+            allocator.release_frame(frame);
+            assert_eq!(allocator.usage(frame), FrameUsage::Free);
+            assert_eq!(allocator.reference_count(frame), 0);
+
+            // Memory the allocator doesn't manage has no references to drop
+            let reserved = PhysicalFrame::containing_address(PhysicalAddress::new(u64::MAX));
+            allocator.share_frame(reserved);
+            allocator.release_frame(reserved);
+            assert_eq!(allocator.usage(reserved), FrameUsage::Reserved);
+        });
     }
 }
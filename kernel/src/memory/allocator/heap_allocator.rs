@@ -0,0 +1,156 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+
+use crate::arch::interrupt::without_interrupts;
+use crate::arch::memory::paging::Page;
+use crate::memory::address::VirtualAddress;
+use crate::memory::allocator::linked_list_allocator::LinkedListAllocator;
+use crate::memory::allocator::slab_allocator::SlabAllocator;
+use crate::memory::allocator::{align_up, MutexWrapper, FRAME_ALLOCATOR};
+use crate::memory::frame::{FrameUsage, FRAME_SIZE};
+use crate::memory::MEMORY_MAPPER;
+
+/// The heap grows by at least this much at once.
+const HEAP_GROWTH: usize = 64 * 1024;
+
+/// The kernel heap. Small objects come from slabs, whose pages are taken from the frame allocator
+/// and used through the physical memory mapping. Larger allocations take whole pages of the heap
+/// region, which grows by mapping new frames when it's exhausted.
+pub struct HeapAllocator {
+    slabs: SlabAllocator,
+    large: LinkedListAllocator,
+    /// The end of the mapped part of the heap region.
+    end: usize,
+    /// The end of the virtual memory reserved for the heap.
+    limit: usize,
+}
+
+impl HeapAllocator {
+    pub const fn new() -> Self {
+        Self {
+            slabs: SlabAllocator::new(),
+            large: LinkedListAllocator::new(),
+            end: 0,
+            limit: 0,
+        }
+    }
+
+    /// Starts the heap with the `size` mapped bytes at `start`. It may grow up to `max_size`.
+    pub unsafe fn init(&mut self, start: usize, size: usize, max_size: usize) {
+        self.large.init(start, size);
+        self.end = start + size;
+        self.limit = start + max_size;
+    }
+
+    pub unsafe fn alloc(&mut self, layout: Layout) -> Option<usize> {
+        match SlabAllocator::size_class(layout) {
+            Some(class) => {
+                if let Some(addr) = self.slabs.alloc(class) {
+                    return Some(addr);
+                }
+                self.slabs.add_page(class, Self::slab_page()?);
+                self.slabs.alloc(class)
+            }
+            None => {
+                let layout = Self::page_layout(layout);
+                if let Some(addr) = self.large.alloc_block(layout) {
+                    return Some(addr);
+                }
+                self.grow(layout.size())?;
+                self.large.alloc_block(layout)
+            }
+        }
+    }
+
+    pub unsafe fn dealloc(&mut self, ptr: usize, layout: Layout) {
+        match SlabAllocator::size_class(layout) {
+            Some(class) => self.slabs.dealloc(class, ptr),
+            None => self.large.dealloc_block(ptr, Self::page_layout(layout)),
+        }
+    }
+
+    /// Large allocations are made of whole pages.
+    fn page_layout(layout: Layout) -> Layout {
+        Layout::from_size_align(
+            align_up(layout.size(), FRAME_SIZE),
+            layout.align().max(FRAME_SIZE),
+        )
+        .expect("Invalid heap layout")
+    }
+
+    fn slab_page() -> Option<usize> {
+        let frame = FRAME_ALLOCATOR
+            .get()?
+            .lock()
+            .allocate_frame(FrameUsage::KernelHeap)?;
+        let mapper = MEMORY_MAPPER.get()?;
+        Some((mapper.physical_memory_offset + frame.start_address.as_u64()).as_u64() as usize)
+    }
+
+    /// Maps at least `size` more bytes at the end of the heap region.
+    unsafe fn grow(&mut self, size: usize) -> Option<()> {
+        let size = align_up(size.max(HEAP_GROWTH), FRAME_SIZE);
+        if self.end + size > self.limit {
+            return None;
+        }
+
+        let mapper = MEMORY_MAPPER.get()?;
+        for addr in (self.end..self.end + size).step_by(FRAME_SIZE) {
+            let frame = FRAME_ALLOCATOR
+                .get()?
+                .lock()
+                .allocate_frame(FrameUsage::KernelHeap)?;
+            let page = Page::containing_address(VirtualAddress::new(addr as u64));
+            mapper.map_page(page, frame, false, true);
+            // The mapping is the only owner of the frame
+            FRAME_ALLOCATOR.get()?.lock().release_frame(frame);
+            // Mapped a page at a time, so a failure leaves the heap consistent
+            self.large.add_free_region(addr, FRAME_SIZE);
+            self.end = addr + FRAME_SIZE;
+        }
+        Some(())
+    }
+}
+
+unsafe impl GlobalAlloc for MutexWrapper<HeapAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        without_interrupts(|| {
+            self.lock()
+                .alloc(layout)
+                .map_or(ptr::null_mut(), |addr| addr as *mut u8)
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        without_interrupts(|| self.lock().dealloc(ptr as usize, layout));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::vec;
+
+    use super::*;
+    use crate::memory::allocator::INITIAL_HEAP_SIZE;
+
+    #[test_case]
+    fn test_small_objects_reused() {
+        // Nothing else may allocate in between
+        without_interrupts(|| {
+            let first = Box::new([0u64; 4]);
+            let addr = &*first as *const _ as usize;
+            assert_eq!(addr % 32, 0);
+            drop(first);
+            let second = Box::new([1u64; 4]);
+            assert_eq!(&*second as *const _ as usize, addr);
+        });
+    }
+
+    #[test_case]
+    fn test_heap_growth() {
+        let large = vec![0x42u8; 2 * INITIAL_HEAP_SIZE];
+        assert_eq!(large.as_ptr() as usize % FRAME_SIZE, 0);
+        assert!(large.iter().all(|&byte| byte == 0x42));
+    }
+}
@@ -1,7 +1,7 @@
-use core::alloc::{GlobalAlloc, Layout};
+use core::alloc::Layout;
 use core::mem;
 
-use crate::memory::allocator::{align_up, MutexWrapper};
+use crate::memory::allocator::align_up;
 
 pub struct LinkedListAllocator {
     head: Block,
@@ -18,7 +18,7 @@ impl LinkedListAllocator {
         self.add_free_region(heap_start, heap_size)
     }
 
-    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+    pub unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
         assert!(
             size >= mem::size_of::<Block>(),
             "Size should be at least the size of a block. Size: {}",
@@ -115,18 +115,6 @@ impl LinkedListAllocator {
     }
 }
 
-unsafe impl GlobalAlloc for MutexWrapper<LinkedListAllocator> {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        self.lock()
-            .alloc_block(layout)
-            .expect("Could not allocate memory.") as *mut u8
-    }
-
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        self.lock().dealloc_block(ptr as usize, layout);
-    }
-}
-
 struct Block {
     next: Option<&'static mut Block>,
     size: usize,
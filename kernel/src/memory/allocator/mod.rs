@@ -2,64 +2,99 @@ use limine::memory_map::Entry;
 use spin::once::Once;
 use spin::Mutex;
 
+use crate::arch::interrupt::without_interrupts;
 use crate::arch::memory::paging::Page;
 use crate::memory::address::VirtualAddress;
 use crate::memory::allocator::frame_allocator::FrameAllocator;
-use crate::memory::allocator::linked_list_allocator::LinkedListAllocator;
+use crate::memory::allocator::heap_allocator::HeapAllocator;
+use crate::memory::frame::{FrameUsage, PhysicalFrame, FRAME_SIZE};
 use crate::memory::MEMORY_MAPPER;
 
 pub mod dma_allocator;
 pub mod frame_allocator;
+mod heap_allocator;
 mod linked_list_allocator;
+mod slab_allocator;
 
 const HEAP_START: usize = 0xFEED_CAFE_000;
-const HEAP_SIZE: usize = 1024 * 1024 * 5; // 5MB
+const INITIAL_HEAP_SIZE: usize = 1024 * 1024; // 1MB
+const HEAP_MAX_SIZE: usize = 1024 * 1024 * 1024; // 1GB
 
 #[global_allocator]
-static ALLOCATOR: MutexWrapper<LinkedListAllocator> = MutexWrapper::new(LinkedListAllocator::new());
+static ALLOCATOR: MutexWrapper<HeapAllocator> = MutexWrapper::new(HeapAllocator::new());
 
 pub static FRAME_ALLOCATOR: Once<Mutex<FrameAllocator>> = Once::new();
 
 #[macro_export]
 macro_rules! allocate_frame {
-    () => {
-        crate::memory::allocator::FRAME_ALLOCATOR
-            .get()
-            .unwrap()
-            .lock()
-            .allocate_frame()
-            .expect("Out of memory")
+    ($usage:expr) => {
+        crate::arch::interrupt::without_interrupts(|| {
+            crate::memory::allocator::FRAME_ALLOCATOR
+                .get()
+                .unwrap()
+                .lock()
+                .allocate_frame($usage)
+                .expect("Out of memory")
+        })
     };
-    ($size:expr) => {
-        crate::memory::allocator::FRAME_ALLOCATOR
-            .get()
-            .unwrap()
-            .lock()
-            .allocate_frames($size)
-            .expect("Out of memory")
+    ($usage:expr, $size:expr) => {
+        crate::arch::interrupt::without_interrupts(|| {
+            crate::memory::allocator::FRAME_ALLOCATOR
+                .get()
+                .unwrap()
+                .lock()
+                .allocate_frames($size, $usage)
+                .expect("Out of memory")
+        })
     };
 }
 
 pub fn init(entries: &'static [&'static Entry]) {
     unsafe {
-        FRAME_ALLOCATOR.call_once(|| Mutex::new(FrameAllocator::new(entries)));
+        let mapper = MEMORY_MAPPER.get_unchecked();
+        FRAME_ALLOCATOR
+            .call_once(|| Mutex::new(FrameAllocator::new(entries, mapper.physical_memory_offset)));
 
         let heap_page_start = Page::containing_address(VirtualAddress::new(HEAP_START as u64));
-        let heap_page_end =
-            Page::containing_address(VirtualAddress::new((HEAP_START + HEAP_SIZE - 1) as u64));
+        let heap_page_end = Page::containing_address(VirtualAddress::new(
+            (HEAP_START + INITIAL_HEAP_SIZE - 1) as u64,
+        ));
         let heap_pages = Page::range_inclusive(heap_page_start, heap_page_end);
 
-        for page in heap_pages {
-            let frame = allocate_frame!();
-            MEMORY_MAPPER
-                .get_unchecked()
-                .map_page(page, frame, false, true)
+        // The heap is physically contiguous too. Its frames belong to the mappings only.
+        let heap_start = allocate_frame!(FrameUsage::KernelHeap, INITIAL_HEAP_SIZE).start_address;
+        for (i, page) in heap_pages.enumerate() {
+            let frame = PhysicalFrame::containing_address(heap_start + (i * FRAME_SIZE) as u64);
+            mapper.map_page(page, frame, false, true);
+            release_frame(frame);
         }
 
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR
+            .lock()
+            .init(HEAP_START, INITIAL_HEAP_SIZE, HEAP_MAX_SIZE);
     }
 }
 
+fn frame_allocator() -> &'static Mutex<FrameAllocator> {
+    FRAME_ALLOCATOR
+        .get()
+        .expect("Frame allocator not initialized.")
+}
+
+/// Adds a reference to the frame, see [`FrameAllocator::share_frame`].
+pub(crate) fn share_frame(frame: PhysicalFrame) {
+    without_interrupts(|| frame_allocator().lock().share_frame(frame));
+}
+
+/// Drops a reference to the frame, see [`FrameAllocator::release_frame`].
+pub(crate) fn release_frame(frame: PhysicalFrame) {
+    without_interrupts(|| frame_allocator().lock().release_frame(frame));
+}
+
+pub(crate) fn frame_usage(frame: PhysicalFrame) -> FrameUsage {
+    without_interrupts(|| frame_allocator().lock().usage(frame))
+}
+
 fn align_up(addr: usize, align: usize) -> usize {
     let offset = (addr as *const usize).align_offset(align);
     addr + offset
@@ -0,0 +1,108 @@
+use core::alloc::Layout;
+
+use crate::memory::frame::FRAME_SIZE;
+
+/// Object sizes served by slabs. Larger allocations go to the page-granular heap.
+const SIZE_CLASSES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Small object allocator with one free list per size class.
+///
+/// Each slab is a page cut into objects of a single size class. Pages are aligned, so objects are
+/// aligned to their size. Freed objects go back to the free list of their class, and pages are
+/// never given back.
+pub struct SlabAllocator {
+    free_lists: [Option<&'static mut FreeObject>; SIZE_CLASSES.len()],
+}
+
+struct FreeObject {
+    next: Option<&'static mut FreeObject>,
+}
+
+impl SlabAllocator {
+    pub const fn new() -> Self {
+        Self {
+            free_lists: [const { None }; SIZE_CLASSES.len()],
+        }
+    }
+
+    /// The size class for the layout, or `None` if it's too large for a slab.
+    pub fn size_class(layout: Layout) -> Option<usize> {
+        let size = layout.size().max(layout.align());
+        SIZE_CLASSES.iter().position(|&class| class >= size)
+    }
+
+    /// Takes an object of the class, or returns `None` if the class needs a new page.
+    pub fn alloc(&mut self, class: usize) -> Option<usize> {
+        let object = self.free_lists[class].take()?;
+        self.free_lists[class] = object.next.take();
+        Some(object as *mut FreeObject as usize)
+    }
+
+    /// # Safety
+    /// `addr` must come from [`SlabAllocator::alloc`] with the same class.
+    pub unsafe fn dealloc(&mut self, class: usize, addr: usize) {
+        let object = addr as *mut FreeObject;
+        object.write(FreeObject {
+            next: self.free_lists[class].take(),
+        });
+        self.free_lists[class] = Some(&mut *object);
+    }
+
+    /// Cuts a new slab into objects of the class.
+    ///
+    /// # Safety
+    /// `page` must be the address of a page-aligned, unused page.
+    pub unsafe fn add_page(&mut self, class: usize, page: usize) {
+        let size = SIZE_CLASSES[class];
+        // Pushed from the end, so objects are handed out in address order
+        for object in (page..page + FRAME_SIZE).step_by(size).rev() {
+            self.dealloc(class, object);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[repr(align(4096))]
+    struct Page([u8; FRAME_SIZE]);
+
+    #[test_case]
+    fn test_size_class() {
+        let class =
+            |size, align| SlabAllocator::size_class(Layout::from_size_align(size, align).unwrap());
+        assert_eq!(class(1, 1), Some(0));
+        assert_eq!(class(8, 8), Some(0));
+        assert_eq!(class(9, 1), Some(1));
+        assert_eq!(class(4, 64), Some(3));
+        assert_eq!(class(2048, 8), Some(8));
+        assert_eq!(class(2049, 8), None);
+    }
+
+    #[test_case]
+    fn test_slab_objects() {
+        let mut page = Page([0; FRAME_SIZE]);
+        let start = page.0.as_mut_ptr() as usize;
+        let mut slabs = SlabAllocator::new();
+        let class = SlabAllocator::size_class(Layout::new::<[u64; 128]>()).unwrap();
+        assert_eq!(slabs.alloc(class), None);
+
+        unsafe { slabs.add_page(class, start) };
+        let objects: [_; 4] = core::array::from_fn(|_| slabs.alloc(class));
+        assert_eq!(
+            objects,
+            [
+                Some(start),
+                Some(start + 1024),
+                Some(start + 2048),
+                Some(start + 3072)
+            ]
+        );
+        assert_eq!(slabs.alloc(class), None);
+
+        // Freed objects are reused first
+        unsafe { slabs.dealloc(class, start + 2048) };
+        assert_eq!(slabs.alloc(class), Some(start + 2048));
+    }
+}
@@ -1,6 +1,23 @@
 use crate::memory::address::PhysicalAddress;
 
 pub const FRAME_SIZE: usize = 4096;
+
+/// What a frame is used for, as tracked by the frame allocator.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameUsage {
+    /// Not managed by the frame allocator: firmware, bootloader or device memory.
+    Reserved,
+    Free,
+    /// Kernel data that doesn't fit elsewhere, e.g. the frame allocator's own metadata.
+    Kernel,
+    KernelHeap,
+    PageTable,
+    User,
+    Dma,
+    PageCache,
+}
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct PhysicalFrame {
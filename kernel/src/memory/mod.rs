@@ -1,14 +1,17 @@
-use limine::memory_map::{Entry, EntryType};
 use limine::request::{HhdmRequest, MemoryMapRequest};
 use spin::Once;
 
 use crate::arch::memory::mapper::MemoryMapper;
+use crate::arch::memory::paging;
 use crate::memory::address::VirtualAddress;
+use crate::memory::frame::FRAME_SIZE;
 use crate::{println, trace};
 
 pub mod address;
 pub mod allocator;
 pub mod frame;
+pub mod object;
+pub mod vma;
 
 static MEMORY_MAP_REQUEST: MemoryMapRequest = MemoryMapRequest::new();
 static HHDM_REQUEST: HhdmRequest = HhdmRequest::new();
@@ -25,23 +28,24 @@ pub fn init() {
         .get_response()
         .expect("Failed to get memory map");
 
+    paging::enable_no_execute();
     MEMORY_MAPPER.call_once(|| MemoryMapper::new(VirtualAddress::new(pm_offset)));
     allocator::init(memory_map.entries());
 
+    let (free, total) = {
+        let frame_allocator = allocator::FRAME_ALLOCATOR.get().unwrap().lock();
+        (
+            frame_allocator.free_frames(),
+            frame_allocator.total_frames(),
+        )
+    };
     println!(
-        "Memory initialized. Available memory: {:.0}MB",
-        calculate_available_memory(memory_map.entries()) as f64 / 1024.0 / 1024.0
+        "Memory initialized. Free memory: {}MB of {}MB",
+        free * FRAME_SIZE / 1024 / 1024,
+        total * FRAME_SIZE / 1024 / 1024
     )
 }
 
-fn calculate_available_memory(entries: &[&Entry]) -> usize {
-    entries
-        .iter()
-        .filter(|entry| entry.entry_type == EntryType::USABLE)
-        .map(|entry| entry.length as usize)
-        .sum()
-}
-
 #[cfg(test)]
 mod tests {
     use crate::memory::allocator::FRAME_ALLOCATOR;
@@ -0,0 +1,161 @@
+use alloc::collections::btree_map::Entry;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use core::ptr;
+
+use spin::Mutex;
+
+use crate::allocate_frame;
+use crate::arch::interrupt::without_interrupts;
+use crate::drivers::fs::path::Path;
+use crate::drivers::fs::{VirtualNode, BOOT_FS};
+use crate::memory::allocator;
+use crate::memory::frame::{FrameUsage, PhysicalFrame, FRAME_SIZE};
+use crate::memory::MEMORY_MAPPER;
+
+/// The objects of the files mapped somewhere, by normalized path, so every mapping of a file
+/// shares its pages.
+static FILE_OBJECTS: Mutex<BTreeMap<String, Weak<MemoryObject>>> = Mutex::new(BTreeMap::new());
+
+/// Memory that several areas can map: the contents of a file, or anonymous memory shared across
+/// forks. The pages are read or zeroed on their first use and kept, so every mapping sees the
+/// same ones. The object holds a reference to each of its frames until it's dropped.
+pub struct MemoryObject {
+    file: Option<VirtualNode>,
+    pages: Mutex<BTreeMap<u64, PhysicalFrame>>,
+}
+
+impl MemoryObject {
+    /// Zero-filled memory.
+    pub fn anonymous() -> Arc<Self> {
+        Arc::new(Self {
+            file: None,
+            pages: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// The object of the file at `path` on the boot file system, or `None` if there's no such
+    /// file. The file system is read-only, so changes to the pages are never written back.
+    pub fn file(path: &str) -> Option<Arc<Self>> {
+        let path = Path::new(path)?;
+        let key = path.iter().fold(String::new(), |key, part| {
+            key + "/" + &part.to_ascii_lowercase()
+        });
+
+        let found = without_interrupts(|| {
+            let mut objects = FILE_OBJECTS.lock();
+            objects.retain(|_, object| object.strong_count() > 0);
+            objects.get(&key).and_then(Weak::upgrade)
+        });
+        if found.is_some() {
+            return found;
+        }
+
+        // Opening the file reads the disk, which blocks, so it's done without holding the lock
+        let node = BOOT_FS.read().as_ref()?.open(&path)?;
+        without_interrupts(|| {
+            let mut objects = FILE_OBJECTS.lock();
+            // Another task may have opened the file in the meantime
+            if let Some(object) = objects.get(&key).and_then(Weak::upgrade) {
+                return Some(object);
+            }
+            let object = Arc::new(Self {
+                file: Some(node),
+                pages: Mutex::new(BTreeMap::new()),
+            });
+            objects.insert(key, Arc::downgrade(&object));
+            Some(object)
+        })
+    }
+
+    /// The frame holding page `index` of the object. Bytes past the end of the file read as
+    /// zeros. Returns `None` if the file can't be read.
+    pub fn page(&self, index: u64) -> Option<PhysicalFrame> {
+        if let Some(frame) = without_interrupts(|| self.pages.lock().get(&index).copied()) {
+            return Some(frame);
+        }
+
+        // Like opening, reading the page is done without holding the lock
+        let usage = match self.file {
+            Some(_) => FrameUsage::PageCache,
+            None => FrameUsage::User,
+        };
+        let frame = allocate_frame!(usage);
+        let data = unsafe { &mut *frame_ptr(frame) };
+        data.fill(0);
+        if let Some(node) = &self.file {
+            let offset = index * FRAME_SIZE as u64;
+            if offset < node.size {
+                let len = (node.size - offset).min(FRAME_SIZE as u64) as usize;
+                let fs = BOOT_FS.read();
+                let fs = fs.as_ref().expect("Boot file system not initialized.");
+                if fs.read(node, offset as usize, &mut data[..len]).is_err() {
+                    allocator::release_frame(frame);
+                    return None;
+                }
+            }
+        }
+
+        without_interrupts(|| match self.pages.lock().entry(index) {
+            // Another task read the page first
+            Entry::Occupied(entry) => {
+                allocator::release_frame(frame);
+                Some(*entry.get())
+            }
+            Entry::Vacant(entry) => Some(*entry.insert(frame)),
+        })
+    }
+
+    /// A new frame with a copy of page `index` of the object. Returns `None` if the file can't be
+    /// read.
+    pub fn copy_page(&self, index: u64) -> Option<PhysicalFrame> {
+        let page = self.page(index)?;
+        let frame = allocate_frame!(FrameUsage::User);
+        unsafe {
+            ptr::copy_nonoverlapping(
+                frame_ptr(page) as *const u8,
+                frame_ptr(frame) as *mut u8,
+                FRAME_SIZE,
+            );
+        }
+        Some(frame)
+    }
+}
+
+impl Drop for MemoryObject {
+    fn drop(&mut self) {
+        for frame in self.pages.get_mut().values() {
+            allocator::release_frame(*frame);
+        }
+    }
+}
+
+fn frame_ptr(frame: PhysicalFrame) -> *mut [u8; FRAME_SIZE] {
+    let mapper = MEMORY_MAPPER.get().expect("Memory mapper not initialized.");
+    (mapper.physical_memory_offset + frame.start_address.as_u64()).as_mut_ptr()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_file_pages() {
+        let object = MemoryObject::file("/boot/init").unwrap();
+        assert!(Arc::ptr_eq(
+            &object,
+            &MemoryObject::file("/BOOT/INIT").unwrap()
+        ));
+        assert!(MemoryObject::file("/boot/missing").is_none());
+
+        let frame = object.page(0).unwrap();
+        assert_eq!(object.page(0), Some(frame));
+        assert_eq!(allocator::frame_usage(frame), FrameUsage::PageCache);
+        let header = unsafe { &(*frame_ptr(frame))[..4] };
+        assert_eq!(header, b"\x7FELF");
+
+        drop(object);
+        assert_eq!(allocator::frame_usage(frame), FrameUsage::Free);
+    }
+}
@@ -0,0 +1,408 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::arch::memory::paging::{Page, PageFlags, PAGE_SIZE};
+use crate::memory::address::VirtualAddress;
+use crate::memory::object::MemoryObject;
+
+/// What a page fault tried to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+/// What backs the memory of an area.
+#[derive(Clone)]
+pub enum VmaKind {
+    /// Zero-filled memory, allocated on first access.
+    Anonymous,
+    /// Anonymous memory that grows down on faults below it, as far as `limit`.
+    Stack { limit: VirtualAddress },
+    /// The pages of a memory object, from `offset` bytes into it. Shared areas map the pages of
+    /// the object, private ones get their own copies.
+    Object {
+        object: Arc<MemoryObject>,
+        offset: u64,
+        shared: bool,
+    },
+}
+
+/// A virtual memory area: a page-aligned range of user memory with the same access rights and
+/// backing. Its pages are mapped when they're first accessed.
+#[derive(Clone)]
+pub struct Vma {
+    pub start: VirtualAddress,
+    pub end: VirtualAddress,
+    /// Areas that can't be accessed at all aren't user accessible.
+    pub flags: PageFlags,
+    pub kind: VmaKind,
+}
+
+impl Vma {
+    pub fn new(
+        start: VirtualAddress,
+        end: VirtualAddress,
+        flags: PageFlags,
+        kind: VmaKind,
+    ) -> Self {
+        Self {
+            start,
+            end,
+            flags,
+            kind,
+        }
+    }
+
+    pub fn contains(&self, addr: VirtualAddress) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    pub fn allows(&self, access: Access) -> bool {
+        self.flags.user_accessible
+            && match access {
+                Access::Read => true,
+                Access::Write => self.flags.writable,
+                Access::Execute => self.flags.executable,
+            }
+    }
+
+    /// The object page a fault at `addr` maps, with whether the area shares it, or `None` if the
+    /// area is anonymous.
+    pub fn object_page(&self, addr: VirtualAddress) -> Option<(&Arc<MemoryObject>, u64, bool)> {
+        let VmaKind::Object {
+            object,
+            offset,
+            shared,
+        } = &self.kind
+        else {
+            return None;
+        };
+        let page = Page::containing_address(addr).start_address;
+        let index = (offset + (page.as_u64() - self.start.as_u64())) / PAGE_SIZE;
+        Some((object, index, *shared))
+    }
+
+    /// Whether a fault at `addr` maps the same page, with the same rights, in both areas. They
+    /// may have been cut or grown since.
+    pub fn maps_same_page(&self, other: &Vma, addr: VirtualAddress) -> bool {
+        let same_backing = match (self.object_page(addr), other.object_page(addr)) {
+            (None, None) => true,
+            (Some((object, index, shared)), Some((other_object, other_index, other_shared))) => {
+                Arc::ptr_eq(object, other_object) && index == other_index && shared == other_shared
+            }
+            _ => false,
+        };
+        self.flags == other.flags && same_backing
+    }
+
+    /// Cuts the area at `addr`, and returns the part above it.
+    fn split_off(&mut self, addr: VirtualAddress) -> Vma {
+        let mut upper = self.clone();
+        upper.start = addr;
+        match &mut upper.kind {
+            VmaKind::Anonymous => {}
+            // Only the lowest part of a stack grows
+            VmaKind::Stack { .. } => upper.kind = VmaKind::Anonymous,
+            VmaKind::Object { offset, .. } => *offset += addr.as_u64() - self.start.as_u64(),
+        }
+        self.end = addr;
+        upper
+    }
+}
+
+/// The areas of an address space, by start address. They never overlap.
+#[derive(Clone, Default)]
+pub struct VmaTree {
+    areas: BTreeMap<VirtualAddress, Vma>,
+}
+
+impl VmaTree {
+    pub const fn new() -> Self {
+        Self {
+            areas: BTreeMap::new(),
+        }
+    }
+
+    /// Adds an area. Returns `false` if it overlaps another one.
+    pub fn insert(&mut self, vma: Vma) -> bool {
+        let overlaps = self
+            .areas
+            .range(..vma.end)
+            .next_back()
+            .is_some_and(|(_, area)| area.end > vma.start);
+        if overlaps || vma.start >= vma.end {
+            return false;
+        }
+        self.areas.insert(vma.start, vma);
+        true
+    }
+
+    /// The area containing `addr`.
+    pub fn find(&self, addr: VirtualAddress) -> Option<&Vma> {
+        self.areas
+            .range(..=addr)
+            .next_back()
+            .map(|(_, area)| area)
+            .filter(|area| area.contains(addr))
+    }
+
+    /// The area a fault at `addr` is resolved in. A stack right above `addr` grows down to its
+    /// page if its limit allows.
+    pub fn fault_area(&mut self, addr: VirtualAddress) -> Option<Vma> {
+        if let Some(area) = self.find(addr) {
+            return Some(area.clone());
+        }
+
+        let (&start, above) = self.areas.range(addr..).next()?;
+        let VmaKind::Stack { limit } = above.kind else {
+            return None;
+        };
+        if addr < limit {
+            return None;
+        }
+        let mut stack = self.areas.remove(&start)?;
+        stack.start = Page::containing_address(addr).start_address;
+        self.areas.insert(stack.start, stack.clone());
+        Some(stack)
+    }
+
+    /// The lowest address from `from` on where `size` bytes fit between the areas, and below
+    /// `to`.
+    pub fn find_free(
+        &self,
+        size: u64,
+        from: VirtualAddress,
+        to: VirtualAddress,
+    ) -> Option<VirtualAddress> {
+        let mut start = from;
+        for area in self.areas.values() {
+            if area.end <= start {
+                continue;
+            }
+            if area.start.as_u64() >= start.as_u64().checked_add(size)? {
+                break;
+            }
+            start = area.end;
+        }
+        let end = start.as_u64().checked_add(size)?;
+        (end <= to.as_u64()).then_some(start)
+    }
+
+    /// Splits the area containing `addr` in two, so an area starts at `addr`.
+    fn split(&mut self, addr: VirtualAddress) {
+        if let Some((_, area)) = self.areas.range_mut(..addr).next_back() {
+            if area.contains(addr) {
+                let upper = area.split_off(addr);
+                self.areas.insert(addr, upper);
+            }
+        }
+    }
+
+    /// Removes the range from the areas, cutting the ones it overlaps. Returns the removed parts.
+    pub fn remove_range(&mut self, start: VirtualAddress, end: VirtualAddress) -> Vec<Vma> {
+        self.split(start);
+        self.split(end);
+        let starts: Vec<_> = self
+            .areas
+            .range(start..end)
+            .map(|(start, _)| *start)
+            .collect();
+        starts
+            .iter()
+            .filter_map(|start| self.areas.remove(start))
+            .collect()
+    }
+
+    /// Gives the range new access rights, cutting the areas it overlaps. Returns `false`, without
+    /// changing anything, if part of the range isn't in an area.
+    pub fn protect_range(
+        &mut self,
+        start: VirtualAddress,
+        end: VirtualAddress,
+        flags: PageFlags,
+    ) -> bool {
+        let mut covered = start;
+        while covered < end {
+            match self.find(covered) {
+                Some(area) => covered = area.end,
+                None => return false,
+            }
+        }
+
+        self.split(start);
+        self.split(end);
+        for area in self.areas.range_mut(start..end).map(|(_, area)| area) {
+            area.flags = flags;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FLAGS: PageFlags = PageFlags {
+        user_accessible: true,
+        writable: true,
+        executable: false,
+    };
+
+    fn addr(addr: u64) -> VirtualAddress {
+        VirtualAddress::new(addr)
+    }
+
+    fn area(start: u64, end: u64, kind: VmaKind) -> Vma {
+        Vma::new(addr(start), addr(end), FLAGS, kind)
+    }
+
+    #[test_case]
+    fn test_insert_and_find() {
+        let mut tree = VmaTree::new();
+        assert!(tree.insert(area(0x1000, 0x3000, VmaKind::Anonymous)));
+        assert!(tree.insert(area(0x5000, 0x6000, VmaKind::Anonymous)));
+        assert!(!tree.insert(area(0x2000, 0x4000, VmaKind::Anonymous)));
+        assert!(!tree.insert(area(0x0, 0x8000, VmaKind::Anonymous)));
+        assert!(tree.insert(area(0x3000, 0x5000, VmaKind::Anonymous)));
+
+        assert_eq!(
+            tree.find(addr(0x2FFF)).map(|vma| vma.start),
+            Some(addr(0x1000))
+        );
+        assert!(tree.find(addr(0x6000)).is_none());
+        assert!(tree.find(addr(0x800)).is_none());
+        assert!(tree.find(addr(0x4000)).is_some());
+    }
+
+    #[test_case]
+    fn test_stack_growth() {
+        let mut tree = VmaTree::new();
+        let stack = VmaKind::Stack {
+            limit: addr(0x4000),
+        };
+        tree.insert(area(0x7000, 0x8000, stack));
+        tree.insert(area(0x1000, 0x2000, VmaKind::Anonymous));
+
+        let grown = tree.fault_area(addr(0x5008)).unwrap();
+        assert_eq!(grown.start, addr(0x5000));
+        assert!(tree.find(addr(0x6000)).is_some());
+        // Below the limit, or under another area
+        assert!(tree.fault_area(addr(0x3FFF)).is_none());
+        assert!(tree.fault_area(addr(0x2000)).is_none());
+    }
+
+    #[test_case]
+    fn test_remove_range() {
+        let mut tree = VmaTree::new();
+        let object = VmaKind::Object {
+            object: MemoryObject::anonymous(),
+            offset: 0x1000,
+            shared: true,
+        };
+        tree.insert(area(0x1000, 0x5000, object));
+        tree.insert(area(0x6000, 0x8000, VmaKind::Anonymous));
+
+        let removed = tree.remove_range(addr(0x2000), addr(0x7000));
+        let ranges: Vec<_> = removed.iter().map(|vma| (vma.start, vma.end)).collect();
+        assert_eq!(
+            ranges,
+            [(addr(0x2000), addr(0x5000)), (addr(0x6000), addr(0x7000))]
+        );
+        // The cut parts keep their place in the object
+        assert!(matches!(
+            removed[0].kind,
+            VmaKind::Object { offset: 0x2000, .. }
+        ));
+        assert_eq!(
+            tree.find(addr(0x1FFF)).map(|vma| vma.end),
+            Some(addr(0x2000))
+        );
+        assert_eq!(
+            tree.find(addr(0x7000)).map(|vma| vma.start),
+            Some(addr(0x7000))
+        );
+        assert!(tree.find(addr(0x2000)).is_none());
+    }
+
+    #[test_case]
+    fn test_protect_range() {
+        let mut tree = VmaTree::new();
+        tree.insert(area(0x1000, 0x3000, VmaKind::Anonymous));
+        tree.insert(area(0x3000, 0x4000, VmaKind::Anonymous));
+        let read_only = PageFlags {
+            writable: false,
+            ..FLAGS
+        };
+
+        // Not covered by areas
+        assert!(!tree.protect_range(addr(0x2000), addr(0x5000), read_only));
+        assert!(tree.find(addr(0x3000)).unwrap().allows(Access::Write));
+
+        assert!(tree.protect_range(addr(0x2000), addr(0x4000), read_only));
+        assert!(tree.find(addr(0x1000)).unwrap().allows(Access::Write));
+        assert!(!tree.find(addr(0x2000)).unwrap().allows(Access::Write));
+        assert!(!tree.find(addr(0x3000)).unwrap().allows(Access::Write));
+        assert!(tree.find(addr(0x3000)).unwrap().allows(Access::Read));
+    }
+
+    #[test_case]
+    fn test_find_free() {
+        let mut tree = VmaTree::new();
+        tree.insert(area(0x2000, 0x3000, VmaKind::Anonymous));
+        tree.insert(area(0x4000, 0x6000, VmaKind::Anonymous));
+
+        assert_eq!(
+            tree.find_free(0x1000, addr(0x1000), addr(0x10000)),
+            Some(addr(0x1000))
+        );
+        assert_eq!(
+            tree.find_free(0x1000, addr(0x2000), addr(0x10000)),
+            Some(addr(0x3000))
+        );
+        assert_eq!(
+            tree.find_free(0x2000, addr(0x1000), addr(0x10000)),
+            Some(addr(0x6000))
+        );
+        assert_eq!(tree.find_free(0x2000, addr(0x1000), addr(0x7000)), None);
+    }
+
+    #[test_case]
+    fn test_maps_same_page() {
+        let object = MemoryObject::anonymous();
+        let mapping = |start, offset| {
+            area(
+                start,
+                0x5000,
+                VmaKind::Object {
+                    object: object.clone(),
+                    offset,
+                    shared: false,
+                },
+            )
+        };
+        let whole = mapping(0x1000, 0);
+        assert_eq!(
+            whole.object_page(addr(0x3008)).map(|(_, index, _)| index),
+            Some(2)
+        );
+        // Cut parts keep their place in the object
+        assert!(whole.maps_same_page(&mapping(0x3000, 0x2000), addr(0x3008)));
+        assert!(!whole.maps_same_page(&mapping(0x3000, 0x3000), addr(0x3008)));
+
+        let other = area(0x1000, 0x5000, VmaKind::Anonymous);
+        assert!(!whole.maps_same_page(&other, addr(0x3008)));
+        let stack = VmaKind::Stack { limit: addr(0) };
+        assert!(other.maps_same_page(&area(0x2000, 0x5000, stack), addr(0x3008)));
+        let read_only = Vma {
+            flags: PageFlags {
+                writable: false,
+                ..FLAGS
+            },
+            ..other.clone()
+        };
+        assert!(!other.maps_same_page(&read_only, addr(0x3008)));
+    }
+}
@@ -1,5 +1,6 @@
 use core::fmt;
 
+use crate::arch::interrupt::without_interrupts;
 use crate::display::DISPLAY;
 use crate::serial::SERIAL;
 
@@ -22,21 +23,25 @@ macro_rules! trace {
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    SERIAL
-        .lock()
-        .write_fmt(args)
-        .expect("Printing to serial failed");
-    DISPLAY
-        .lock()
-        .write_fmt(args)
-        .expect("Printing to display failed");
+    without_interrupts(|| {
+        SERIAL
+            .lock()
+            .write_fmt(args)
+            .expect("Printing to serial failed");
+        DISPLAY
+            .lock()
+            .write_fmt(args)
+            .expect("Printing to display failed");
+    });
 }
 
 #[doc(hidden)]
 pub fn _trace(args: fmt::Arguments) {
     use core::fmt::Write;
-    SERIAL
-        .lock()
-        .write_fmt(args)
-        .expect("Printing to serial failed");
+    without_interrupts(|| {
+        SERIAL
+            .lock()
+            .write_fmt(args)
+            .expect("Printing to serial failed");
+    });
 }
@@ -1,3 +1,9 @@
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::{fmt, ptr};
+
+mod relocation;
+
 #[derive(Debug)]
 #[repr(C)]
 struct ElfHeader {
@@ -17,8 +23,9 @@ struct ElfHeader {
     e_shstrndx: u16,
 }
 
-#[repr(u32)]
-#[derive(Debug)]
+/// The type of a segment, from `p_type`. Values outside the known ones are kept as they are,
+/// since a file may use any of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ProgramHeaderType {
     Null,
     Load,
@@ -28,22 +35,63 @@ pub enum ProgramHeaderType {
     Shlib,
     Phdr,
     Tls,
-    Loos = 0x60000000,
-    Hios = 0x6FFFFFFF,
-    Loproc = 0x70000000,
-    Hiproc = 0x7FFFFFFF,
+    /// Between `PT_LOOS` and `PT_HIOS`.
+    OsSpecific(u32),
+    /// Between `PT_LOPROC` and `PT_HIPROC`.
+    ProcessorSpecific(u32),
+    Unknown(u32),
+}
+
+impl From<u32> for ProgramHeaderType {
+    fn from(value: u32) -> Self {
+        match value {
+            0 => Self::Null,
+            1 => Self::Load,
+            2 => Self::Dynamic,
+            3 => Self::Interp,
+            4 => Self::Note,
+            5 => Self::Shlib,
+            6 => Self::Phdr,
+            7 => Self::Tls,
+            0x6000_0000..=0x6FFF_FFFF => Self::OsSpecific(value),
+            0x7000_0000..=0x7FFF_FFFF => Self::ProcessorSpecific(value),
+            _ => Self::Unknown(value),
+        }
+    }
 }
 
 #[repr(C)]
 pub struct ProgramHeader {
-    pub(crate) p_type: ProgramHeaderType,
-    p_flags: u32,
+    p_type: u32,
+    pub(crate) p_flags: u32,
     pub(crate) p_offset: u64,
     pub(crate) p_vaddr: u64,
     p_paddr: u64,
     pub(crate) p_filesz: u64,
     pub(crate) p_memsz: u64,
-    p_align: u64,
+    pub(crate) p_align: u64,
+}
+
+impl ProgramHeader {
+    const FLAG_EXECUTABLE: u32 = 1 << 0;
+    const FLAG_WRITABLE: u32 = 1 << 1;
+    const FLAG_READABLE: u32 = 1 << 2;
+
+    pub fn header_type(&self) -> ProgramHeaderType {
+        ProgramHeaderType::from(self.p_type)
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.p_flags & Self::FLAG_EXECUTABLE != 0
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.p_flags & Self::FLAG_WRITABLE != 0
+    }
+
+    pub fn is_readable(&self) -> bool {
+        self.p_flags & Self::FLAG_READABLE != 0
+    }
 }
 
 #[repr(C)]
@@ -61,44 +109,189 @@ struct SectionHeader {
     sh_entsize: u64,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// The buffer is smaller than the ELF header.
+    Truncated,
+    InvalidMagic,
+    /// Only ELF64 files are supported.
+    UnsupportedClass,
+    /// Only little endian files are supported.
+    UnsupportedEndianness,
+    UnsupportedVersion,
+    /// Only x86-64 files are supported.
+    UnsupportedMachine,
+    /// Only executables and position independent executables are supported.
+    UnsupportedType,
+    InvalidProgramHeaderSize,
+    InvalidSectionHeaderSize,
+    ProgramHeadersOutOfBounds,
+    SectionHeadersOutOfBounds,
+    /// A loadable segment is larger in the file than in memory, or wraps around the address space.
+    InvalidSegment,
+    /// The data of a segment is past the end of the buffer.
+    SegmentOutOfBounds,
+    /// The dynamic section, or a table it points to, is malformed.
+    InvalidDynamicSection,
+    UnsupportedRelocation(u32),
+    /// A relocation refers to a symbol that isn't defined in the file. There's no dynamic linker,
+    /// so only self-contained executables can be loaded.
+    UnresolvedSymbol,
+    /// A relocation would patch memory outside of the loadable segments.
+    InvalidRelocation,
+    /// A segment would be loaded outside of the user address space.
+    InvalidLoadAddress,
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
 pub struct ElfFile<'a> {
-    header: &'a ElfHeader,
-    pub(crate) program_headers: &'a [ProgramHeader],
-    section_headers: &'a [SectionHeader],
+    header: ElfHeader,
+    pub(crate) program_headers: Vec<ProgramHeader>,
+    section_headers: Vec<SectionHeader>,
     buffer: &'a [u8],
 }
 
 impl<'a> ElfFile<'a> {
-    pub(crate) fn parse(buffer: &'a [u8]) -> Self {
-        let elf_header = unsafe { &*(buffer.as_ptr() as *const ElfHeader) };
-        let program_headers = unsafe {
-            core::slice::from_raw_parts(
-                (buffer.as_ptr() as *const ProgramHeader).byte_add(elf_header.e_phoff as usize),
-                elf_header.e_phnum as usize,
-            )
-        };
-        let section_headers = unsafe {
-            core::slice::from_raw_parts(
-                (buffer.as_ptr() as *const SectionHeader).byte_add(elf_header.e_shoff as usize),
-                elf_header.e_shnum as usize,
-            )
-        };
-        Self {
-            header: elf_header,
+    const MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+    const CLASS_64: u8 = 2;
+    const DATA_LITTLE_ENDIAN: u8 = 1;
+    const VERSION_CURRENT: u8 = 1;
+    const TYPE_EXECUTABLE: u16 = 2;
+    const TYPE_SHARED_OBJECT: u16 = 3;
+    const MACHINE_X86_64: u16 = 0x3E;
+
+    /// Parses and validates the headers of an ELF64 x86-64 executable. The headers are copied out
+    /// of the buffer, so it doesn't need to be aligned.
+    pub(crate) fn parse(buffer: &'a [u8]) -> Result<Self, ElfError> {
+        let header: ElfHeader = read(buffer, 0).ok_or(ElfError::Truncated)?;
+
+        let ident = &header.e_ident;
+        if ident[0..4] != Self::MAGIC {
+            return Err(ElfError::InvalidMagic);
+        }
+        if ident[4] != Self::CLASS_64 {
+            return Err(ElfError::UnsupportedClass);
+        }
+        if ident[5] != Self::DATA_LITTLE_ENDIAN {
+            return Err(ElfError::UnsupportedEndianness);
+        }
+        if ident[6] != Self::VERSION_CURRENT || header.e_version != Self::VERSION_CURRENT as u32 {
+            return Err(ElfError::UnsupportedVersion);
+        }
+        if header.e_machine != Self::MACHINE_X86_64 {
+            return Err(ElfError::UnsupportedMachine);
+        }
+        if header.e_type != Self::TYPE_EXECUTABLE && header.e_type != Self::TYPE_SHARED_OBJECT {
+            return Err(ElfError::UnsupportedType);
+        }
+
+        if header.e_phnum > 0 && header.e_phentsize as usize != size_of::<ProgramHeader>() {
+            return Err(ElfError::InvalidProgramHeaderSize);
+        }
+        if header.e_shnum > 0 && header.e_shentsize as usize != size_of::<SectionHeader>() {
+            return Err(ElfError::InvalidSectionHeaderSize);
+        }
+        let program_headers: Vec<ProgramHeader> =
+            read_table(buffer, header.e_phoff, header.e_phnum as usize)
+                .ok_or(ElfError::ProgramHeadersOutOfBounds)?;
+        let section_headers = read_table(buffer, header.e_shoff, header.e_shnum as usize)
+            .ok_or(ElfError::SectionHeadersOutOfBounds)?;
+
+        for program_header in program_headers.iter() {
+            if program_header.header_type() == ProgramHeaderType::Load
+                && (program_header.p_filesz > program_header.p_memsz
+                    || program_header
+                        .p_vaddr
+                        .checked_add(program_header.p_memsz)
+                        .is_none())
+            {
+                return Err(ElfError::InvalidSegment);
+            }
+            let end = program_header.p_offset.checked_add(program_header.p_filesz);
+            if end.map_or(true, |end| end > buffer.len() as u64) {
+                return Err(ElfError::SegmentOutOfBounds);
+            }
+        }
+
+        Ok(Self {
+            header,
             program_headers,
             section_headers,
             buffer,
-        }
+        })
     }
 
     pub(crate) fn entry_point(&self) -> u64 {
         self.header.e_entry
     }
 
-    pub(crate) fn data(&self, segment_index: usize) -> &'a [u8] {
-        let header = &self.program_headers[segment_index];
+    /// Whether the file is a position independent executable, which can be loaded at any base.
+    pub(crate) fn is_position_independent(&self) -> bool {
+        self.header.e_type == Self::TYPE_SHARED_OBJECT
+    }
+
+    /// The virtual address of the program headers, if they're part of a loadable segment.
+    pub(crate) fn program_headers_address(&self) -> Option<u64> {
+        if let Some(header) = self
+            .program_headers
+            .iter()
+            .find(|header| header.header_type() == ProgramHeaderType::Phdr)
+        {
+            return Some(header.p_vaddr);
+        }
+
+        let start = self.header.e_phoff;
+        let end = start + (self.program_headers.len() * size_of::<ProgramHeader>()) as u64;
+        self.load_segments()
+            .find(|header| start >= header.p_offset && end <= header.p_offset + header.p_filesz)
+            .map(|header| header.p_vaddr + (start - header.p_offset))
+    }
+
+    /// The bytes of the segment stored in the file, `p_filesz` long. The bounds are checked by
+    /// [`ElfFile::parse`].
+    pub(crate) fn data(&self, header: &ProgramHeader) -> &'a [u8] {
         &self.buffer[header.p_offset as usize..(header.p_offset + header.p_filesz) as usize]
     }
+
+    pub(crate) fn load_segments(&self) -> impl Iterator<Item = &ProgramHeader> {
+        self.program_headers
+            .iter()
+            .filter(|header| header.header_type() == ProgramHeaderType::Load)
+    }
+
+    /// The PT_TLS segment, the template of the thread-local variables. It lies within a loadable
+    /// segment.
+    pub(crate) fn tls_segment(&self) -> Option<&ProgramHeader> {
+        self.program_headers
+            .iter()
+            .find(|header| header.header_type() == ProgramHeaderType::Tls)
+    }
+}
+
+/// Copies a `T` out of the buffer at `offset`. Returns `None` if it doesn't fit in it.
+fn read<T>(buffer: &[u8], offset: u64) -> Option<T> {
+    let start = usize::try_from(offset).ok()?;
+    let bytes = buffer.get(start..start.checked_add(size_of::<T>())?)?;
+    Some(unsafe { ptr::read_unaligned(bytes.as_ptr() as *const T) })
+}
+
+/// Copies `count` entries of a table out of the buffer. Returns `None` if the table doesn't fit in
+/// it.
+fn read_table<T>(buffer: &[u8], offset: u64, count: usize) -> Option<Vec<T>> {
+    let size = size_of::<T>().checked_mul(count)?;
+    let end = usize::try_from(offset).ok()?.checked_add(size)?;
+    if end > buffer.len() {
+        return None;
+    }
+
+    (0..count)
+        .map(|index| read(buffer, offset + (index * size_of::<T>()) as u64))
+        .collect()
 }
 
 #[cfg(test)]
@@ -119,7 +312,7 @@ mod tests {
         let mut buffer = vec![0; node.size as usize];
         fs.read(&node, 0, &mut buffer).unwrap();
 
-        let elf = ElfFile::parse(&buffer);
+        let elf = ElfFile::parse(&buffer).unwrap();
 
         assert_eq!(elf.header.e_ident[0..4], [0x7F, 0x45, 0x4C, 0x46]);
         assert_eq!(elf.header.e_phnum as usize, elf.program_headers.len());
@@ -130,4 +323,53 @@ mod tests {
         assert_eq!(elf.section_headers.len(), 0x19);
         assert!(elf.entry_point() > 0xffffffff80000000 && elf.entry_point() < 0xffffffff90000000);
     }
+
+    #[test_case]
+    fn test_invalid_elf() {
+        let fs_opt = BOOT_FS.read();
+        let fs = fs_opt.as_ref().unwrap();
+        let node = fs.open(&Path::new("/boot/init").unwrap()).unwrap();
+
+        let mut buffer = vec![0; node.size as usize];
+        fs.read(&node, 0, &mut buffer).unwrap();
+        assert!(ElfFile::parse(&buffer).is_ok());
+
+        let parse_with = |patch: &dyn Fn(&mut [u8])| {
+            let mut buffer = buffer.clone();
+            patch(&mut buffer);
+            ElfFile::parse(&buffer).err()
+        };
+        assert_eq!(ElfFile::parse(&buffer[..32]).err(), Some(ElfError::Truncated));
+        assert_eq!(parse_with(&|b| b[0] = 0), Some(ElfError::InvalidMagic));
+        assert_eq!(parse_with(&|b| b[4] = 1), Some(ElfError::UnsupportedClass));
+        assert_eq!(parse_with(&|b| b[18] = 0x03), Some(ElfError::UnsupportedMachine));
+        // e_phentsize
+        assert_eq!(parse_with(&|b| b[54] = 0x20), Some(ElfError::InvalidProgramHeaderSize));
+        // e_phoff
+        assert_eq!(
+            parse_with(&|b| b[32..40].copy_from_slice(&u64::MAX.to_le_bytes())),
+            Some(ElfError::ProgramHeadersOutOfBounds)
+        );
+        let phoff = u64::from_le_bytes(buffer[32..40].try_into().unwrap()) as usize;
+        let load = (0..buffer[56] as usize)
+            .map(|index| phoff + index * size_of::<ProgramHeader>())
+            .find(|offset| buffer[*offset] == 1)
+            .unwrap();
+        // p_filesz of the first PT_LOAD
+        assert_eq!(
+            parse_with(&|b| b[load + 32..load + 40].copy_from_slice(&u64::MAX.to_le_bytes())),
+            Some(ElfError::InvalidSegment)
+        );
+        // p_offset of the first PT_LOAD
+        assert_eq!(
+            parse_with(&|b| b[load + 8..load + 16].copy_from_slice(&(1u64 << 40).to_le_bytes())),
+            Some(ElfError::SegmentOutOfBounds)
+        );
+        // Unknown segment types don't make the file invalid
+        assert_eq!(
+            parse_with(&|b| b[phoff..phoff + 4].copy_from_slice(&0x1234u32.to_le_bytes())),
+            None
+        );
+        assert_eq!(ProgramHeaderType::from(0x1234), ProgramHeaderType::Unknown(0x1234));
+    }
 }
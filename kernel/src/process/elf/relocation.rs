@@ -0,0 +1,244 @@
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+use crate::process::elf::{read, read_table, ElfError, ElfFile, ProgramHeaderType};
+
+const DT_NULL: i64 = 0;
+const DT_PLTRELSZ: i64 = 2;
+const DT_SYMTAB: i64 = 6;
+const DT_RELA: i64 = 7;
+const DT_RELASZ: i64 = 8;
+const DT_RELAENT: i64 = 9;
+const DT_SYMENT: i64 = 11;
+const DT_REL: i64 = 17;
+const DT_PLTREL: i64 = 20;
+const DT_JMPREL: i64 = 23;
+
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_64: u32 = 1;
+const R_X86_64_GLOB_DAT: u32 = 6;
+const R_X86_64_JUMP_SLOT: u32 = 7;
+const R_X86_64_RELATIVE: u32 = 8;
+
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xFFF1;
+
+#[repr(C)]
+struct DynamicEntry {
+    d_tag: i64,
+    d_val: u64,
+}
+
+#[repr(C)]
+struct Rela {
+    r_offset: u64,
+    r_info: u64,
+    r_addend: i64,
+}
+
+impl Rela {
+    fn relocation_type(&self) -> u32 {
+        self.r_info as u32
+    }
+
+    fn symbol_index(&self) -> u64 {
+        self.r_info >> 32
+    }
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+struct Symbol {
+    st_name: u32,
+    st_info: u8,
+    st_other: u8,
+    st_shndx: u16,
+    st_value: u64,
+    st_size: u64,
+}
+
+/// A word of the image to patch once it's loaded: `load base + value` is written at
+/// `load base + offset`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Relocation {
+    pub offset: u64,
+    pub value: u64,
+}
+
+/// The relocation tables found in the dynamic section, as virtual addresses and sizes.
+#[derive(Default)]
+struct DynamicTables {
+    rela: Option<(u64, u64)>,
+    plt_rela: Option<(u64, u64)>,
+    symbol_table: Option<u64>,
+}
+
+impl<'a> ElfFile<'a> {
+    /// The relocations of the file, from its `PT_DYNAMIC` segment. Only the relocations a
+    /// static-PIE needs are supported: `R_X86_64_RELATIVE`, plus `R_X86_64_64`, `GLOB_DAT` and
+    /// `JUMP_SLOT` against symbols defined in the file.
+    pub(crate) fn relocations(&self) -> Result<Vec<Relocation>, ElfError> {
+        let tables = self.dynamic_tables()?;
+
+        let mut relocations = Vec::new();
+        for (address, size) in [tables.rela, tables.plt_rela].into_iter().flatten() {
+            let table = self
+                .file_data_at(address, size)
+                .ok_or(ElfError::InvalidDynamicSection)?;
+            let entries: Vec<Rela> = read_table(table, 0, size as usize / size_of::<Rela>())
+                .ok_or(ElfError::InvalidDynamicSection)?;
+
+            for rela in entries.iter() {
+                if let Some(relocation) = self.relocation(rela, tables.symbol_table)? {
+                    relocations.push(relocation);
+                }
+            }
+        }
+        Ok(relocations)
+    }
+
+    fn dynamic_tables(&self) -> Result<DynamicTables, ElfError> {
+        let mut tables = DynamicTables::default();
+        let Some(dynamic) = self
+            .program_headers
+            .iter()
+            .find(|header| header.header_type() == ProgramHeaderType::Dynamic)
+        else {
+            return Ok(tables);
+        };
+
+        let entries: Vec<DynamicEntry> = read_table(
+            self.data(dynamic),
+            0,
+            dynamic.p_filesz as usize / size_of::<DynamicEntry>(),
+        )
+        .ok_or(ElfError::InvalidDynamicSection)?;
+
+        let (mut rela, mut rela_size) = (None, 0);
+        let (mut plt_rela, mut plt_rela_size) = (None, 0);
+        for entry in entries.iter().take_while(|entry| entry.d_tag != DT_NULL) {
+            match entry.d_tag {
+                DT_RELA => rela = Some(entry.d_val),
+                DT_RELASZ => rela_size = entry.d_val,
+                DT_JMPREL => plt_rela = Some(entry.d_val),
+                DT_PLTRELSZ => plt_rela_size = entry.d_val,
+                DT_SYMTAB => tables.symbol_table = Some(entry.d_val),
+                DT_RELAENT if entry.d_val != size_of::<Rela>() as u64 => {
+                    return Err(ElfError::InvalidDynamicSection)
+                }
+                DT_SYMENT if entry.d_val != size_of::<Symbol>() as u64 => {
+                    return Err(ElfError::InvalidDynamicSection)
+                }
+                // x86-64 only uses relocations with an explicit addend
+                DT_REL => return Err(ElfError::InvalidDynamicSection),
+                DT_PLTREL if entry.d_val != DT_RELA as u64 => {
+                    return Err(ElfError::InvalidDynamicSection)
+                }
+                _ => {}
+            }
+        }
+
+        tables.rela = rela.map(|address| (address, rela_size));
+        tables.plt_rela = plt_rela.map(|address| (address, plt_rela_size));
+        Ok(tables)
+    }
+
+    fn relocation(
+        &self,
+        rela: &Rela,
+        symbol_table: Option<u64>,
+    ) -> Result<Option<Relocation>, ElfError> {
+        let value = match rela.relocation_type() {
+            R_X86_64_NONE => return Ok(None),
+            R_X86_64_RELATIVE => rela.r_addend as u64,
+            R_X86_64_GLOB_DAT | R_X86_64_JUMP_SLOT => {
+                self.symbol_value(symbol_table, rela.symbol_index())?
+            }
+            R_X86_64_64 => self
+                .symbol_value(symbol_table, rela.symbol_index())?
+                .wrapping_add(rela.r_addend as u64),
+            relocation_type => return Err(ElfError::UnsupportedRelocation(relocation_type)),
+        };
+
+        if !self.is_loaded(rela.r_offset, size_of::<u64>() as u64) {
+            return Err(ElfError::InvalidRelocation);
+        }
+        Ok(Some(Relocation {
+            offset: rela.r_offset,
+            value,
+        }))
+    }
+
+    /// The value of a symbol, relative to the load base.
+    fn symbol_value(&self, symbol_table: Option<u64>, index: u64) -> Result<u64, ElfError> {
+        let symbol_size = size_of::<Symbol>() as u64;
+        let address = index
+            .checked_mul(symbol_size)
+            .zip(symbol_table)
+            .and_then(|(offset, symbol_table)| symbol_table.checked_add(offset))
+            .ok_or(ElfError::InvalidDynamicSection)?;
+        let symbol: Symbol = self
+            .file_data_at(address, symbol_size)
+            .and_then(|data| read(data, 0))
+            .ok_or(ElfError::InvalidDynamicSection)?;
+
+        // Absolute symbols don't move with the image, so they can't be expressed relative to it
+        match symbol.st_shndx {
+            SHN_UNDEF | SHN_ABS => Err(ElfError::UnresolvedSymbol),
+            _ => Ok(symbol.st_value),
+        }
+    }
+
+    /// The bytes stored in the file for `size` bytes at the virtual address `address`, if they
+    /// are all in the same loadable segment.
+    fn file_data_at(&self, address: u64, size: u64) -> Option<&'a [u8]> {
+        let header = self.load_segments().find(|header| {
+            address >= header.p_vaddr
+                && address
+                    .checked_add(size)
+                    .is_some_and(|end| end <= header.p_vaddr + header.p_filesz)
+        })?;
+        let start = (address - header.p_vaddr) as usize;
+        Some(&self.data(header)[start..start + size as usize])
+    }
+
+    /// Whether the `size` bytes at the virtual address `address` are all in the same loadable
+    /// segment.
+    fn is_loaded(&self, address: u64, size: u64) -> bool {
+        self.load_segments().any(|header| {
+            address >= header.p_vaddr
+                && address
+                    .checked_add(size)
+                    .is_some_and(|end| end <= header.p_vaddr + header.p_memsz)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use crate::drivers::fs::path::Path;
+    use crate::drivers::fs::BOOT_FS;
+
+    use super::*;
+
+    #[test_case]
+    fn test_static_pie_relocations() {
+        let fs_opt = BOOT_FS.read();
+        let fs = fs_opt.as_ref().unwrap();
+        let node = fs.open(&Path::new("/boot/init").unwrap()).unwrap();
+
+        let mut buffer = vec![0; node.size as usize];
+        fs.read(&node, 0, &mut buffer).unwrap();
+
+        let elf = ElfFile::parse(&buffer).unwrap();
+        assert!(elf.is_position_independent());
+
+        let relocations = elf.relocations().unwrap();
+        assert!(!relocations.is_empty());
+        assert!(relocations
+            .iter()
+            .all(|relocation| elf.is_loaded(relocation.offset, size_of::<u64>() as u64)));
+    }
+}
@@ -0,0 +1,98 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+use kernel_api::error::Errno;
+use spin::Mutex;
+
+use crate::arch::interrupt;
+use crate::arch::memory::paging::Page;
+use crate::memory::address::{PhysicalAddress, VirtualAddress};
+use crate::memory::frame::PhysicalFrame;
+use crate::memory::MEMORY_MAPPER;
+use crate::process::scheduler;
+use crate::process::task::TaskId;
+use crate::process::user::UserPtr;
+
+/// The tasks blocked in [`wait`], by the word they wait on.
+static FUTEXES: Mutex<BTreeMap<FutexKey, VecDeque<TaskId>>> = Mutex::new(BTreeMap::new());
+
+/// Identifies a futex word across the tasks that can see it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum FutexKey {
+    /// A word of a shared mapping, or of kernel memory, by its physical address. Processes
+    /// sharing a page find the same waiters, wherever the page is mapped.
+    Shared(PhysicalAddress),
+    /// A word of a private mapping, by the level 4 table of its address space and its virtual
+    /// address. Its frame changes when a write breaks copy-on-write sharing, so it can't be used.
+    Private(PhysicalFrame, VirtualAddress),
+}
+
+/// Finds the key of the 32-bit word at `addr` in the memory of the current task. The word is
+/// only read, so read-only and copy-on-write pages work as they are. Faulting the page in first
+/// tells whether it's part of a shared mapping.
+fn key(addr: usize) -> Result<FutexKey, Errno> {
+    if addr % size_of::<u32>() != 0 {
+        return Err(Errno::InvalidArgument);
+    }
+    let addr = VirtualAddress::new(addr as u64);
+    let Some(address_space) = scheduler::current_address_space() else {
+        let phys = MEMORY_MAPPER.get().unwrap().translate_addr(addr);
+        return phys.map(FutexKey::Shared).ok_or(Errno::Fault);
+    };
+    let phys = address_space
+        .translate_user(addr, false)
+        .ok_or(Errno::Fault)?;
+    if address_space.is_shared_page(Page::containing_address(addr)) {
+        Ok(FutexKey::Shared(phys))
+    } else {
+        Ok(FutexKey::Private(address_space.level_4_frame(), addr))
+    }
+}
+
+/// Blocks the current task until [`wake`] is called for the word at `addr`, if the word still
+/// holds `expected`. Otherwise fails with [`Errno::TryAgain`], as the waker already changed it.
+/// Returns early if the task is asked to exit.
+pub(crate) fn wait(addr: usize, expected: u32) -> Result<(), Errno> {
+    // The value can't change between the check and the block, nothing else runs meanwhile
+    interrupt::without_interrupts(|| {
+        let key = key(addr)?;
+        if UserPtr::<u32>::new(addr).read()? != expected {
+            return Err(Errno::TryAgain);
+        }
+        let current = scheduler::current();
+        FUTEXES.lock().entry(key).or_default().push_back(current);
+        scheduler::block_current_interruptible();
+        if scheduler::exit_requested() {
+            // Nothing woke the task through the futex, so it's still queued
+            let mut futexes = FUTEXES.lock();
+            if let Some(waiters) = futexes.get_mut(&key) {
+                waiters.retain(|task| *task != current);
+                if waiters.is_empty() {
+                    futexes.remove(&key);
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Wakes up to `count` tasks waiting on the word at `addr`, oldest first. Returns how many there
+/// were.
+pub(crate) fn wake(addr: usize, count: usize) -> Result<usize, Errno> {
+    let key = key(addr)?;
+    let woken: Vec<TaskId> = interrupt::without_interrupts(|| {
+        let mut futexes = FUTEXES.lock();
+        let Some(waiters) = futexes.get_mut(&key) else {
+            return Vec::new();
+        };
+        let woken = waiters.drain(..count.min(waiters.len())).collect();
+        if waiters.is_empty() {
+            futexes.remove(&key);
+        }
+        woken
+    });
+    let count = woken.len();
+    woken.into_iter().for_each(scheduler::wake);
+    Ok(count)
+}
@@ -0,0 +1,117 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+
+use spin::Mutex;
+
+use crate::arch::interrupt;
+use crate::process::scheduler;
+use crate::process::task::TaskId;
+
+type ThreadMain = Box<dyn FnOnce() + Send>;
+
+/// What a thread returned, and the task waiting for it in [`JoinHandle::join`].
+struct JoinState<T> {
+    result: Option<T>,
+    waiter: Option<TaskId>,
+}
+
+/// Owned permission to wait for a kernel thread and take its result.
+pub(crate) struct JoinHandle<T> {
+    state: Arc<Mutex<JoinState<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Blocks until the thread finishes, and returns what it returned.
+    pub fn join(self) -> T {
+        loop {
+            let result = interrupt::without_interrupts(|| {
+                let mut state = self.state.lock();
+                if let Some(result) = state.result.take() {
+                    return Some(result);
+                }
+                state.waiter = Some(scheduler::current());
+                drop(state);
+                scheduler::block_current();
+                None
+            });
+            if let Some(result) = result {
+                return result;
+            }
+        }
+    }
+}
+
+/// Starts a kernel thread that runs `f` on its own stack. It's scheduled like any other task,
+/// with interrupts enabled, so it may be preempted.
+pub(crate) fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let state = Arc::new(Mutex::new(JoinState {
+        result: None,
+        waiter: None,
+    }));
+
+    let thread_state = state.clone();
+    let main: ThreadMain = Box::new(move || {
+        let result = f();
+        let waiter = interrupt::without_interrupts(|| {
+            let mut state = thread_state.lock();
+            state.result = Some(result);
+            state.waiter.take()
+        });
+        if let Some(waiter) = waiter {
+            scheduler::wake(waiter);
+        }
+    });
+
+    let arg = Box::into_raw(Box::new(main)) as usize;
+    scheduler::spawn_kernel(thread_entry, arg);
+    JoinHandle { state }
+}
+
+/// Gives the CPU to the next ready task.
+pub(crate) fn yield_now() {
+    interrupt::yield_now();
+}
+
+extern "C" fn thread_entry(main: usize) -> ! {
+    let main = unsafe { Box::from_raw(main as *mut ThreadMain) };
+    main();
+    scheduler::exit_current();
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[test_case]
+    fn test_join() {
+        let handle = spawn(|| 6 * 7);
+        assert_eq!(handle.join(), 42);
+
+        let threads: Vec<_> = (0..4).map(|i| spawn(move || i * 2)).collect();
+        let results: Vec<_> = threads.into_iter().map(JoinHandle::join).collect();
+        assert_eq!(results, [0, 2, 4, 6]);
+    }
+
+    #[test_case]
+    fn test_yield_now() {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let handle = spawn(|| {
+            for _ in 0..3 {
+                COUNTER.fetch_add(1, Ordering::Relaxed);
+                yield_now();
+            }
+        });
+        while COUNTER.load(Ordering::Relaxed) < 3 {
+            yield_now();
+        }
+        handle.join();
+    }
+}
@@ -1,68 +1,507 @@
-use core::arch::asm;
-use core::ptr;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem::size_of;
 
-use crate::allocate_frame;
-use crate::arch::gdt::SELECTORS;
-use crate::arch::memory::paging::{Page, PAGE_SIZE};
+use kernel_api::env::{AT_ENTRY, AT_NULL, AT_PAGESZ, AT_PHDR, AT_PHENT, AT_PHNUM};
+use kernel_api::error::Errno;
+
+use crate::arch::memory::address_space::{AddressSpace, USER_SPACE_END};
+use crate::arch::memory::paging::{Page, PageFlags, PAGE_SIZE};
+use crate::arch::Context;
 use crate::memory::address::VirtualAddress;
-use crate::memory::MEMORY_MAPPER;
-use crate::process::elf::{ElfFile, ProgramHeaderType};
+use crate::memory::vma::{Vma, VmaKind};
+use crate::process::elf::{ElfError, ElfFile, ProgramHeader};
 
+/// Where position independent executables are loaded.
 const PROCESS_START: u64 = 0xF00D_C0DE_000;
+/// The user stacks sit at the end of the lower half, one per thread, each with an unmapped guard
+/// page below it. The main thread uses the first one. Stacks start with a single page, and grow
+/// down on page faults.
+const STACK_TOP: u64 = USER_SPACE_END;
+const STACK_SIZE: u64 = 16 * PAGE_SIZE;
+/// How much of the stack the arguments and the environment can take.
+const MAX_ARGUMENTS_SIZE: u64 = STACK_SIZE / 4;
+/// How much of the stack the thread-local storage can take.
+const MAX_TLS_SIZE: u64 = STACK_SIZE / 4;
+/// The most threads a process can have at once, as each needs a stack.
+pub(crate) const MAX_THREADS: usize = 64;
+
+/// The initial values of the thread-local variables, in the memory of the loaded program. Every
+/// thread starts with a copy of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TlsTemplate {
+    start: u64,
+    file_size: u64,
+    memory_size: u64,
+    align: u64,
+}
+
+/// A program loaded in a new address space, ready to run.
+pub struct Program {
+    pub address_space: AddressSpace,
+    /// The context the main thread starts from.
+    pub context: Context,
+    pub thread_pointer: VirtualAddress,
+    pub tls: Option<TlsTemplate>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    Elf(ElfError),
+    /// The arguments and the environment don't fit in the initial stack.
+    ArgumentsTooLong,
+}
+
+impl From<ElfError> for LoadError {
+    fn from(error: ElfError) -> Self {
+        Self::Elf(error)
+    }
+}
+
+impl From<LoadError> for Errno {
+    fn from(error: LoadError) -> Self {
+        match error {
+            LoadError::Elf(_) => Errno::ExecFormat,
+            LoadError::ArgumentsTooLong => Errno::ArgumentsTooLong,
+        }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Elf(error) => write!(f, "{}", error),
+            Self::ArgumentsTooLong => write!(f, "ArgumentsTooLong"),
+        }
+    }
+}
+
+/// Loads the ELF file in a new address space, with `args` and `env` on the stack of its main
+/// thread.
+pub fn load(elf_file: &ElfFile, args: &[&str], env: &[&str]) -> Result<Program, LoadError> {
+    // Other executables are linked at the address they run from
+    let base = if elf_file.is_position_independent() {
+        PROCESS_START
+    } else {
+        0
+    };
+    let relocations = elf_file.relocations()?;
+    let pages = segment_pages(elf_file, base).ok_or(ElfError::InvalidLoadAddress)?;
+    let tls = match elf_file.tls_segment() {
+        Some(header) => Some(tls_template(header, base, &pages).ok_or(ElfError::InvalidSegment)?),
+        None => None,
+    };
+
+    let entry_point = base.wrapping_add(elf_file.entry_point());
+    let mut auxv = vec![
+        (AT_PHENT as u64, size_of::<ProgramHeader>() as u64),
+        (AT_PHNUM as u64, elf_file.program_headers.len() as u64),
+        (AT_PAGESZ as u64, PAGE_SIZE),
+        (AT_ENTRY as u64, entry_point),
+    ];
+    if let Some(address) = elf_file.program_headers_address() {
+        auxv.push((AT_PHDR as u64, base.wrapping_add(address)));
+    }
 
-pub fn spawn(elf_file: &ElfFile) {
-    let code_size = 10 * PAGE_SIZE; // 10 pages. TODO: Calculate this properly
-    let code_addr = PROCESS_START;
+    let lowest_guard_page = Page::containing_address(VirtualAddress::new(stacks_start()));
+    let address_space = AddressSpace::new();
+    let overlaps_stack = pages
+        .last_key_value()
+        .is_some_and(|(page, _)| *page >= lowest_guard_page);
+    if overlaps_stack || !pages.keys().all(|page| address_space.is_user_page(*page)) {
+        return Err(ElfError::InvalidLoadAddress.into());
+    }
+
+    for area in segment_areas(&pages) {
+        let added = address_space.add_area(area);
+        assert!(added, "Segment areas overlap");
+    }
+    for (page, flags) in pages.iter() {
+        address_space.map_zeroed_page(*page, *flags);
+    }
 
-    let start_page = Page::containing_address(VirtualAddress::new(code_addr));
-    let end_page = Page::containing_address(VirtualAddress::new(code_addr + code_size));
+    // Only the first p_filesz bytes come from the file. The rest of the segment, up to p_memsz,
+    // is the .bss and stays zeroed.
+    for header in elf_file.load_segments() {
+        address_space.write(
+            VirtualAddress::new(base + header.p_vaddr),
+            elf_file.data(header),
+        );
+    }
 
-    for page in Page::range_inclusive(start_page, end_page) {
-        let frame = allocate_frame!();
-        unsafe {
-            MEMORY_MAPPER
-                .get_unchecked()
-                .map_page(page, frame, true, true)
+    for relocation in relocations {
+        let value = base.wrapping_add(relocation.value);
+        address_space.write(
+            VirtualAddress::new(base + relocation.offset),
+            &value.to_le_bytes(),
+        );
+    }
+
+    // The address space is new, so only a TLS template the segments don't make readable fails
+    let (stack_top, thread_pointer) =
+        thread_stack(&address_space, 0, tls.as_ref()).map_err(|_| ElfError::InvalidSegment)?;
+    let (stack_pointer, initial_stack) =
+        initial_stack(stack_top, args, env, &auxv).ok_or(LoadError::ArgumentsTooLong)?;
+    let written = address_space.write_user(VirtualAddress::new(stack_pointer), &initial_stack);
+    assert!(written, "The initial stack isn't in the stack area");
+
+    let context = Context::new_user(
+        VirtualAddress::new(entry_point),
+        VirtualAddress::new(stack_pointer),
+    );
+    Ok(Program {
+        address_space,
+        context,
+        thread_pointer,
+        tls,
+    })
+}
+
+/// Where the region of the stacks starts, with the guard page of the last one.
+pub(crate) fn stacks_start() -> u64 {
+    thread_stack_top(MAX_THREADS - 1) - STACK_SIZE - PAGE_SIZE
+}
+
+/// Where the stack of the thread using stack `index` ends.
+pub(crate) fn thread_stack_top(index: usize) -> u64 {
+    STACK_TOP - index as u64 * (STACK_SIZE + PAGE_SIZE)
+}
+
+/// Adds the area of stack `index` if it isn't there yet, and sets up the thread-local storage at
+/// its top, as the x86_64 System V ABI lays it out: a copy of the TLS template, followed by the
+/// thread control block whose address is the thread pointer. The control block starts with a pointer to itself.
+/// Returns where the rest of the stack ends, 16 bytes aligned, and the thread pointer.
+///
+/// The process may have mapped something else over the stack, or unmapped its TLS template, in
+/// which case it gets [`Errno::NoMemory`] or [`Errno::Fault`].
+pub(crate) fn thread_stack(
+    address_space: &AddressSpace,
+    index: usize,
+    tls: Option<&TlsTemplate>,
+) -> Result<(u64, VirtualAddress), Errno> {
+    let top = thread_stack_top(index);
+    let limit = VirtualAddress::new(top - STACK_SIZE);
+    let stack_flags = PageFlags {
+        user_accessible: true,
+        writable: true,
+        executable: false,
+    };
+    let added = address_space.add_area(Vma::new(
+        VirtualAddress::new(top - PAGE_SIZE),
+        VirtualAddress::new(top),
+        stack_flags,
+        VmaKind::Stack { limit },
+    ));
+    // A stack used before keeps its area, which may have grown
+    let reused = || match address_space.find_area(VirtualAddress::new(top - PAGE_SIZE)) {
+        Some(Vma {
+            end,
+            kind: VmaKind::Stack { limit: stack_limit },
+            ..
+        }) => end.as_u64() == top && stack_limit == limit,
+        _ => false,
+    };
+    if !added && !reused() {
+        return Err(Errno::NoMemory);
+    }
+
+    let align = tls.map_or(16, |tls| tls.align.max(16));
+    let thread_pointer = (top - 2 * size_of::<u64>() as u64) & !(align - 1);
+    let mut block = Vec::new();
+    if let Some(tls) = tls {
+        // A stack that was used before isn't zeroed anymore
+        block = vec![0; tls.memory_size.next_multiple_of(tls.align) as usize];
+        let copied = address_space.read_user(
+            VirtualAddress::new(tls.start),
+            &mut block[..tls.file_size as usize],
+        );
+        if !copied {
+            return Err(Errno::Fault);
         }
     }
-    let page_ptr: *mut u8 = code_addr as *mut u8;
-    let entry_point = elf_file.entry_point();
+    block.extend(thread_pointer.to_le_bytes());
+    let block_start = thread_pointer - (block.len() - size_of::<u64>()) as u64;
 
-    for (header_idx, header) in elf_file
-        .program_headers
-        .iter()
-        .filter(|h| matches!(h.p_type, ProgramHeaderType::Load))
-        .enumerate()
+    // Goes through page faults like the thread would, as a forked process shares its stacks
+    if !address_space.write_user(VirtualAddress::new(block_start), &block) {
+        return Err(Errno::Fault);
+    }
+    Ok((block_start & !0xF, VirtualAddress::new(thread_pointer)))
+}
+
+/// Checks the PT_TLS segment, once loaded at `base`. Its initial bytes must be part of the
+/// loaded pages, and the whole block must fit in [`MAX_TLS_SIZE`].
+fn tls_template(
+    header: &ProgramHeader,
+    base: u64,
+    pages: &BTreeMap<Page, PageFlags>,
+) -> Option<TlsTemplate> {
+    let align = header.p_align.max(1);
+    if !align.is_power_of_two()
+        || header.p_filesz > header.p_memsz
+        || header.p_memsz.checked_add(align)? > MAX_TLS_SIZE
     {
-        let data = elf_file.data(header_idx);
-        let addr = header.p_vaddr as usize;
-        unsafe {
-            ptr::copy(data.as_ptr(), page_ptr.offset(addr as isize), data.len());
+        return None;
+    }
+
+    let start = base.checked_add(header.p_vaddr)?;
+    if header.p_filesz > 0 {
+        let end = start.checked_add(header.p_filesz - 1)?;
+        let mut template_pages = Page::range_inclusive(
+            Page::containing_address(VirtualAddress::new(start)),
+            Page::containing_address(VirtualAddress::new(end)),
+        );
+        if !template_pages.all(|page| pages.contains_key(&page)) {
+            return None;
         }
     }
 
-    let selectors = SELECTORS.get().expect("GDT not initialized.");
-
-    unsafe {
-        asm!(
-        "cli", // Disable interrupts
-        "push rax",   // Stack segment (SS)
-        "push rsi",   // Stack pointer (RSP)
-        "push 0x200", // RFLAGS with interrupts enabled
-        "push rdx",   // Code segment (CS)
-        "push rdi",   // Instruction pointer (RIP)
-        "iretq",
-        in("rax") selectors.user_data.as_raw(),
-        in("rsi") code_addr + code_size,
-        in("rdx") selectors.user_code.as_raw(),
-        in("rdi") code_addr + entry_point
-        )
+    Some(TlsTemplate {
+        start,
+        file_size: header.p_filesz,
+        memory_size: header.p_memsz,
+        align,
+    })
+}
+
+/// Lays out the initial stack of a process as the System V ABI describes it: `argc`, the `argv`
+/// and `envp` arrays, the auxiliary vector, and the strings they point to. Returns the stack
+/// pointer the process starts with and the bytes to copy there, up to `stack_top`. Returns `None`
+/// if they'd take more than [`MAX_ARGUMENTS_SIZE`].
+fn initial_stack(
+    stack_top: u64,
+    args: &[&str],
+    env: &[&str],
+    auxv: &[(u64, u64)],
+) -> Option<(u64, Vec<u8>)> {
+    let strings_size: u64 = args.iter().chain(env).map(|s| s.len() as u64 + 1).sum();
+    let words = 1 + args.len() + 1 + env.len() + 1 + 2 * (auxv.len() + 1);
+    let size = strings_size
+        .checked_add(words as u64 * size_of::<u64>() as u64)?
+        .checked_add(32)?;
+    if size > MAX_ARGUMENTS_SIZE {
+        return None;
     }
+
+    // The stack pointer has to be 16 bytes aligned when the process starts
+    let strings_start = (stack_top - strings_size) & !0xF;
+    let stack_pointer = (strings_start - (words * size_of::<u64>()) as u64) & !0xF;
+    let mut stack = vec![0; (stack_top - stack_pointer) as usize];
+
+    let mut vector = Vec::with_capacity(words);
+    let mut string_address = strings_start;
+    vector.push(args.len() as u64);
+    for strings in [args, env] {
+        for s in strings {
+            let offset = (string_address - stack_pointer) as usize;
+            stack[offset..offset + s.len()].copy_from_slice(s.as_bytes());
+            vector.push(string_address);
+            string_address += s.len() as u64 + 1;
+        }
+        vector.push(0);
+    }
+    for (key, value) in auxv {
+        vector.extend([*key, *value]);
+    }
+    vector.extend([AT_NULL as u64, 0]);
+
+    for (index, word) in vector.iter().enumerate() {
+        let offset = index * size_of::<u64>();
+        stack[offset..offset + size_of::<u64>()].copy_from_slice(&word.to_le_bytes());
+    }
+    Some((stack_pointer, stack))
 }
 
+/// Merges the runs of contiguous pages with the same access rights into areas.
+fn segment_areas(pages: &BTreeMap<Page, PageFlags>) -> Vec<Vma> {
+    let mut areas: Vec<Vma> = Vec::new();
+    for (page, flags) in pages {
+        match areas.last_mut() {
+            Some(area) if area.end == page.start_address && area.flags == *flags => {
+                area.end = page.start_address + PAGE_SIZE;
+            }
+            _ => areas.push(Vma::new(
+                page.start_address,
+                page.start_address + PAGE_SIZE,
+                *flags,
+                VmaKind::Anonymous,
+            )),
+        }
+    }
+    areas
+}
+
+/// The pages covered by the PT_LOAD segments once loaded at `base`, with the access rights
+/// asked by their `p_flags`. A page shared by two segments gets the rights of both. Returns `None`
+/// if a segment doesn't fit in the address space.
+fn segment_pages(elf_file: &ElfFile, base: u64) -> Option<BTreeMap<Page, PageFlags>> {
+    let mut pages = BTreeMap::new();
+
+    for header in elf_file.load_segments().filter(|header| header.p_memsz > 0) {
+        let start = base.checked_add(header.p_vaddr)?;
+        let end = start.checked_add(header.p_memsz - 1)?;
+        let flags = PageFlags {
+            user_accessible: true,
+            writable: header.is_writable(),
+            executable: header.is_executable(),
+        };
+
+        for page in Page::range_inclusive(
+            Page::containing_address(VirtualAddress::new(start)),
+            Page::containing_address(VirtualAddress::new(end)),
+        ) {
+            pages
+                .entry(page)
+                .and_modify(|page_flags: &mut PageFlags| *page_flags = page_flags.union(flags))
+                .or_insert(flags);
+        }
+    }
+    Some(pages)
+}
+
+#[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::drivers::fs::path::Path;
+    use crate::drivers::fs::BOOT_FS;
+
     #[test_case]
     fn test_heap_allocator() {
-        kernel_api::syscall::spawn("/boot/init");
+        kernel_api::syscall::spawn("/boot/init", &[]).unwrap();
+    }
+
+    #[test_case]
+    fn test_segment_pages() {
+        let fs_opt = BOOT_FS.read();
+        let fs = fs_opt.as_ref().unwrap();
+        let node = fs.open(&Path::new("/boot/init").unwrap()).unwrap();
+        let mut buffer = alloc::vec![0; node.size as usize];
+        fs.read(&node, 0, &mut buffer).unwrap();
+
+        let elf = ElfFile::parse(&buffer).unwrap();
+        let pages = segment_pages(&elf, PROCESS_START).unwrap();
+
+        let entry =
+            Page::containing_address(VirtualAddress::new(PROCESS_START + elf.entry_point()));
+        assert!(pages[&entry].executable);
+        assert!(!pages[&entry].writable);
+        assert!(pages.values().all(|flags| flags.user_accessible));
+        assert!(pages
+            .values()
+            .all(|flags| !(flags.writable && flags.executable)));
+
+        let areas = segment_areas(&pages);
+        assert!(areas.len() <= pages.len());
+        assert!(areas.windows(2).all(|pair| pair[0].end <= pair[1].start));
+        assert!(pages.iter().all(|(page, flags)| areas
+            .iter()
+            .any(|area| area.contains(page.start_address) && area.flags == *flags)));
+    }
+
+    #[test_case]
+    fn test_initial_stack() {
+        let read = |stack: &[u8], address: u64, stack_pointer: u64| {
+            let offset = (address - stack_pointer) as usize;
+            u64::from_le_bytes(stack[offset..offset + 8].try_into().unwrap())
+        };
+        let read_string = |stack: &[u8], address: u64, stack_pointer: u64| {
+            let start = (address - stack_pointer) as usize;
+            let len = stack[start..].iter().position(|byte| *byte == 0).unwrap();
+            alloc::string::String::from_utf8(stack[start..start + len].to_vec()).unwrap()
+        };
+
+        let top = 0x10000;
+        let (sp, stack) = initial_stack(
+            top,
+            &["/boot/init", "-v"],
+            &["HOME=/"],
+            &[(AT_PAGESZ as u64, 4096)],
+        )
+        .unwrap();
+        assert_eq!(sp % 16, 0);
+        assert_eq!(sp + stack.len() as u64, top);
+
+        assert_eq!(read(&stack, sp, sp), 2);
+        assert_eq!(
+            read_string(&stack, read(&stack, sp + 8, sp), sp),
+            "/boot/init"
+        );
+        assert_eq!(read_string(&stack, read(&stack, sp + 16, sp), sp), "-v");
+        assert_eq!(read(&stack, sp + 24, sp), 0);
+        assert_eq!(read_string(&stack, read(&stack, sp + 32, sp), sp), "HOME=/");
+        assert_eq!(read(&stack, sp + 40, sp), 0);
+        assert_eq!(read(&stack, sp + 48, sp), AT_PAGESZ as u64);
+        assert_eq!(read(&stack, sp + 56, sp), 4096);
+        assert_eq!(read(&stack, sp + 64, sp), AT_NULL as u64);
+
+        let too_long = alloc::vec!["x"; MAX_ARGUMENTS_SIZE as usize];
+        assert!(initial_stack(top, &too_long, &[], &[]).is_none());
+    }
+
+    #[test_case]
+    fn test_thread_stack() {
+        let address_space = AddressSpace::new();
+        let template = Page::containing_address(VirtualAddress::new(PROCESS_START));
+        let flags = PageFlags {
+            user_accessible: true,
+            writable: false,
+            executable: false,
+        };
+        address_space.map_zeroed_page(template, flags);
+        address_space.write(template.start_address, b"tdata");
+        let tls = TlsTemplate {
+            start: PROCESS_START,
+            file_size: 5,
+            memory_size: 12,
+            align: 32,
+        };
+
+        let (stack_top, thread_pointer) = thread_stack(&address_space, 1, Some(&tls)).unwrap();
+        let tp = thread_pointer.as_u64();
+        assert_eq!(tp % 32, 0);
+        assert!(tp < thread_stack_top(1) && tp > thread_stack_top(2));
+        assert_eq!(stack_top % 16, 0);
+        assert!(stack_top <= tp - 32);
+
+        let mut self_pointer = [0; 8];
+        assert!(address_space.read_user(thread_pointer, &mut self_pointer));
+        assert_eq!(u64::from_le_bytes(self_pointer), tp);
+        // The block ends at the thread pointer, and the .tbss part is zeroed
+        let mut block = [0xFF; 32];
+        assert!(address_space.read_user(VirtualAddress::new(tp - 32), &mut block));
+        assert_eq!(&block[..5], b"tdata");
+        assert!(block[5..].iter().all(|byte| *byte == 0));
+
+        // Only the top of the stack is mapped, the rest is faulted in down to the guard page
+        let bottom = thread_stack_top(1) - STACK_SIZE;
+        assert_eq!(
+            address_space.translate_addr(VirtualAddress::new(bottom)),
+            None
+        );
+        assert!(address_space.is_user_range(VirtualAddress::new(bottom), 8, true));
+        assert!(!address_space.is_user_range(VirtualAddress::new(bottom - 8), 8, false));
+
+        // A stack used before keeps its area
+        assert!(thread_stack(&address_space, 1, Some(&tls)).is_ok());
+        // Something else mapped over the stack, or a TLS template that isn't mapped anymore
+        let top = thread_stack_top(2);
+        let other = Vma::new(
+            VirtualAddress::new(top - PAGE_SIZE),
+            VirtualAddress::new(top),
+            flags,
+            VmaKind::Anonymous,
+        );
+        assert!(address_space.add_area(other));
+        assert_eq!(
+            thread_stack(&address_space, 2, Some(&tls)),
+            Err(Errno::NoMemory)
+        );
+        address_space.unmap_page(template);
+        assert_eq!(
+            thread_stack(&address_space, 3, Some(&tls)),
+            Err(Errno::Fault)
+        );
     }
 }
@@ -0,0 +1,274 @@
+use kernel_api::error::Errno;
+use kernel_api::syscall::{
+    MAP_ANONYMOUS, MAP_FIXED, MAP_PRIVATE, MAP_SHARED, PROT_EXEC, PROT_NONE, PROT_READ, PROT_WRITE,
+};
+
+use crate::arch::memory::address_space::{AddressSpace, USER_SPACE_END};
+use crate::arch::memory::paging::{PageFlags, PAGE_SIZE};
+use crate::memory::address::VirtualAddress;
+use crate::memory::object::MemoryObject;
+use crate::memory::vma::{Vma, VmaKind};
+use crate::process::{loader, scheduler};
+
+/// Where mappings go when the caller doesn't ask for an address, well above the programs.
+const MMAP_START: u64 = 0x2000_0000_0000;
+
+/// Maps `len` bytes in the memory of the current process, see [`kernel_api::syscall::mmap`].
+/// Returns the address of the mapping.
+pub(crate) fn map(
+    addr: usize,
+    len: usize,
+    prot: usize,
+    flags: usize,
+    path: Option<&str>,
+    offset: usize,
+) -> Result<VirtualAddress, Errno> {
+    // Kernel tasks have no user memory
+    let address_space = scheduler::current_address_space().ok_or(Errno::InvalidArgument)?;
+    map_in(&address_space, addr, len, prot, flags, path, offset)
+}
+
+/// Unmaps the pages of the `len` bytes at `addr` in the memory of the current process.
+pub(crate) fn unmap(addr: usize, len: usize) -> Result<(), Errno> {
+    let address_space = scheduler::current_address_space().ok_or(Errno::InvalidArgument)?;
+    let (start, end) = page_range(addr, len)?;
+    address_space.unmap_range(start, end);
+    Ok(())
+}
+
+/// Gives the pages of the `len` bytes at `addr` in the memory of the current process the `prot`
+/// access rights.
+pub(crate) fn protect(addr: usize, len: usize, prot: usize) -> Result<(), Errno> {
+    let address_space = scheduler::current_address_space().ok_or(Errno::InvalidArgument)?;
+    protect_in(&address_space, addr, len, prot)
+}
+
+fn map_in(
+    address_space: &AddressSpace,
+    addr: usize,
+    len: usize,
+    prot: usize,
+    flags: usize,
+    path: Option<&str>,
+    offset: usize,
+) -> Result<VirtualAddress, Errno> {
+    let shared = flags & MAP_SHARED != 0;
+    let known_flags = MAP_SHARED | MAP_PRIVATE | MAP_FIXED | MAP_ANONYMOUS;
+    if flags & !known_flags != 0
+        || shared == (flags & MAP_PRIVATE != 0)
+        || offset as u64 % PAGE_SIZE != 0
+    {
+        return Err(Errno::InvalidArgument);
+    }
+    let page_flags = page_flags(prot)?;
+    let size = (len as u64)
+        .checked_next_multiple_of(PAGE_SIZE)
+        .filter(|size| *size > 0)
+        .ok_or(Errno::InvalidArgument)?;
+
+    let kind = match (flags & MAP_ANONYMOUS != 0, path) {
+        (true, None) if shared => VmaKind::Object {
+            object: MemoryObject::anonymous(),
+            offset: 0,
+            shared,
+        },
+        (true, None) => VmaKind::Anonymous,
+        (false, Some(path)) => VmaKind::Object {
+            object: MemoryObject::file(path).ok_or(Errno::NoSuchFile)?,
+            offset: offset as u64,
+            shared,
+        },
+        _ => return Err(Errno::InvalidArgument),
+    };
+
+    if flags & MAP_FIXED != 0 {
+        let start = VirtualAddress::new(addr as u64);
+        let end = start
+            .as_u64()
+            .checked_add(size)
+            .ok_or(Errno::InvalidArgument)?;
+        let area = Vma::new(start, VirtualAddress::new(end), page_flags, kind);
+        if start.as_u64() % PAGE_SIZE != 0 || !address_space.replace_area(area) {
+            return Err(Errno::InvalidArgument);
+        }
+        return Ok(start);
+    }
+
+    // The address is only a hint, the mapping goes wherever it fits if it's taken
+    let limit = VirtualAddress::new(loader::stacks_start());
+    let hint = match addr as u64 {
+        0 => MMAP_START,
+        addr => addr - addr % PAGE_SIZE,
+    };
+    let add = |from| {
+        address_space.add_free_area(
+            size,
+            VirtualAddress::new(from),
+            limit,
+            page_flags,
+            kind.clone(),
+        )
+    };
+    add(hint).or_else(|| add(MMAP_START)).ok_or(Errno::NoMemory)
+}
+
+fn protect_in(
+    address_space: &AddressSpace,
+    addr: usize,
+    len: usize,
+    prot: usize,
+) -> Result<(), Errno> {
+    let (start, end) = page_range(addr, len)?;
+    let flags = page_flags(prot)?;
+    address_space
+        .protect_range(start, end, flags)
+        .then_some(())
+        .ok_or(Errno::NoMemory)
+}
+
+/// The pages of the `len` bytes at `addr`, which must be page aligned.
+fn page_range(addr: usize, len: usize) -> Result<(VirtualAddress, VirtualAddress), Errno> {
+    let end = (addr as u64)
+        .checked_add(len as u64)
+        .and_then(|end| end.checked_next_multiple_of(PAGE_SIZE))
+        .ok_or(Errno::InvalidArgument)?;
+    if addr as u64 % PAGE_SIZE != 0 || len == 0 || end > USER_SPACE_END {
+        return Err(Errno::InvalidArgument);
+    }
+    Ok((VirtualAddress::new(addr as u64), VirtualAddress::new(end)))
+}
+
+fn page_flags(prot: usize) -> Result<PageFlags, Errno> {
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(Errno::InvalidArgument);
+    }
+    Ok(PageFlags {
+        user_accessible: prot != PROT_NONE,
+        writable: prot & PROT_WRITE != 0,
+        executable: prot & PROT_EXEC != 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const READ_WRITE: usize = PROT_READ | PROT_WRITE;
+    const ANONYMOUS: usize = MAP_PRIVATE | MAP_ANONYMOUS;
+
+    #[test_case]
+    fn test_map_anonymous() {
+        let address_space = AddressSpace::new();
+        let map =
+            |addr, len, prot, flags, path| map_in(&address_space, addr, len, prot, flags, path, 0);
+
+        let first = map(0, 5000, READ_WRITE, ANONYMOUS, None).unwrap();
+        assert_eq!(first, VirtualAddress::new(MMAP_START));
+        assert!(address_space.write_user(first + 4100, b"heap"));
+        let mut buffer = [0xFF; 4];
+        assert!(address_space.read_user(first + 8000, &mut buffer));
+        assert_eq!(buffer, [0; 4]);
+        assert!(!address_space.read_user(first + 2 * PAGE_SIZE, &mut buffer));
+
+        // Taken hints are moved
+        let second = map(MMAP_START as usize, 1, PROT_READ, ANONYMOUS, None).unwrap();
+        assert_eq!(second, first + 2 * PAGE_SIZE);
+        assert!(!address_space.write_user(second, b"read-only"));
+
+        let invalid = Err(Errno::InvalidArgument);
+        assert_eq!(map(0, 0, READ_WRITE, ANONYMOUS, None), invalid);
+        assert_eq!(map(0, 1, 0x8, ANONYMOUS, None), invalid);
+        assert_eq!(map(0, 1, READ_WRITE, MAP_ANONYMOUS, None), invalid);
+        assert_eq!(map(0, 1, READ_WRITE, ANONYMOUS | MAP_SHARED, None), invalid);
+        assert_eq!(
+            map(0, 1, READ_WRITE, ANONYMOUS, Some("/boot/init")),
+            invalid
+        );
+        assert_eq!(map(0, 1, READ_WRITE, MAP_PRIVATE, None), invalid);
+        assert_eq!(
+            map(0x1234, 1, READ_WRITE, ANONYMOUS | MAP_FIXED, None),
+            invalid
+        );
+    }
+
+    #[test_case]
+    fn test_map_fixed() {
+        let address_space = AddressSpace::new();
+        let first = map_in(&address_space, 0, 1, READ_WRITE, ANONYMOUS, None, 0).unwrap();
+        assert!(address_space.write_user(first, b"replaced"));
+
+        let flags = ANONYMOUS | MAP_FIXED;
+        let addr = first.as_u64() as usize;
+        let fixed = map_in(&address_space, addr, 1, PROT_READ, flags, None, 0);
+        assert_eq!(fixed, Ok(first));
+        let mut buffer = [0xFF; 8];
+        assert!(address_space.read_user(first, &mut buffer));
+        assert_eq!(buffer, [0; 8]);
+        assert!(!address_space.write_user(first, b"replaced"));
+    }
+
+    #[test_case]
+    fn test_shared_after_fork() {
+        let parent = AddressSpace::new();
+        let shared = MAP_SHARED | MAP_ANONYMOUS;
+        let shared = map_in(&parent, 0, 1, READ_WRITE, shared, None, 0).unwrap();
+        let private = map_in(&parent, 0, 1, READ_WRITE, ANONYMOUS, None, 0).unwrap();
+        assert!(parent.write_user(shared, b"parent"));
+        assert!(parent.write_user(private, b"parent"));
+
+        let child = parent.fork();
+        assert!(child.write_user(shared, b"child!"));
+        assert!(child.write_user(private, b"child!"));
+        let mut buffer = [0; 6];
+        assert!(parent.read_user(shared, &mut buffer));
+        assert_eq!(&buffer, b"child!");
+        assert!(parent.read_user(private, &mut buffer));
+        assert_eq!(&buffer, b"parent");
+    }
+
+    #[test_case]
+    fn test_map_file() {
+        let address_space = AddressSpace::new();
+        let map =
+            |flags, path, offset| map_in(&address_space, 0, 8, PROT_READ, flags, path, offset);
+
+        let file = map(MAP_PRIVATE, Some("/boot/init"), 0).unwrap();
+        let mut magic = [0; 4];
+        assert!(address_space.read_user(file, &mut magic));
+        assert_eq!(&magic, b"\x7FELF");
+        assert!(!address_space.write_user(file, b"ELF"));
+
+        let missing = map(MAP_SHARED, Some("/boot/missing"), 0);
+        assert_eq!(missing, Err(Errno::NoSuchFile));
+        let unaligned = map(MAP_PRIVATE, Some("/boot/init"), 8);
+        assert_eq!(unaligned, Err(Errno::InvalidArgument));
+    }
+
+    #[test_case]
+    fn test_protect_and_unmap() {
+        let address_space = AddressSpace::new();
+        let start = map_in(&address_space, 0, 8192, READ_WRITE, ANONYMOUS, None, 0).unwrap();
+        let addr = start.as_u64() as usize;
+        assert!(address_space.write_user(start, b"data"));
+
+        let mut buffer = [0; 4];
+        protect_in(&address_space, addr, 1, PROT_READ).unwrap();
+        assert!(!address_space.write_user(start, b"data"));
+        assert!(address_space.write_user(start + PAGE_SIZE, b"data"));
+        protect_in(&address_space, addr, 1, PROT_NONE).unwrap();
+        assert!(!address_space.read_user(start, &mut buffer));
+        protect_in(&address_space, addr, 1, READ_WRITE).unwrap();
+        assert!(address_space.read_user(start, &mut buffer));
+        assert_eq!(&buffer, b"data");
+
+        let beyond = protect_in(&address_space, addr, 3 * PAGE_SIZE as usize, PROT_READ);
+        assert_eq!(beyond, Err(Errno::NoMemory));
+        let unaligned = protect_in(&address_space, addr + 1, 1, PROT_READ);
+        assert_eq!(unaligned, Err(Errno::InvalidArgument));
+
+        address_space.unmap_range(start, start + PAGE_SIZE);
+        assert_eq!(address_space.translate_addr(start), None);
+        assert!(!address_space.read_user(start, &mut buffer));
+        assert!(address_space.read_user(start + PAGE_SIZE, &mut buffer));
+    }
+}
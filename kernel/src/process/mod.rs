@@ -1,2 +1,37 @@
+use core::fmt;
+
+use crate::println;
+
 pub(crate) mod elf;
-pub(crate) mod loader;
\ No newline at end of file
+pub(crate) mod futex;
+pub(crate) mod kthread;
+pub(crate) mod loader;
+pub(crate) mod mmap;
+pub(crate) mod scheduler;
+pub(crate) mod table;
+pub(crate) mod task;
+pub(crate) mod user;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(usize);
+
+impl Pid {
+    pub const fn new(pid: usize) -> Self {
+        Self(pid)
+    }
+
+    pub fn as_usize(&self) -> usize {
+        self.0
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+pub(crate) fn init() {
+    scheduler::init();
+    println!("Scheduler initialized.");
+}
@@ -0,0 +1,363 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+
+use spin::{Mutex, Once};
+
+use crate::arch;
+use crate::arch::gdt;
+use crate::arch::interrupt;
+use crate::arch::memory::address_space;
+use crate::arch::memory::address_space::AddressSpace;
+use crate::arch::syscall;
+use crate::arch::Context;
+use crate::memory::address::VirtualAddress;
+use crate::process::task::{Task, TaskId, TaskState};
+use crate::process::Pid;
+
+/// The task of the boot context. It only runs when no other task is ready.
+const IDLE_TASK_ID: TaskId = TaskId::new(0);
+
+pub static SCHEDULER: Once<Mutex<Scheduler>> = Once::new();
+
+pub(crate) fn init() {
+    SCHEDULER.call_once(|| Mutex::new(Scheduler::new()));
+}
+
+/// Round-robin scheduler. Every call to [`Scheduler::schedule`] moves the current task to the back
+/// of the run queue and switches to the one at the front.
+pub struct Scheduler {
+    tasks: BTreeMap<TaskId, Task>,
+    run_queue: VecDeque<TaskId>,
+    current: TaskId,
+    next_id: usize,
+}
+
+impl Scheduler {
+    fn new() -> Self {
+        let mut tasks = BTreeMap::new();
+        tasks.insert(IDLE_TASK_ID, Task::current(IDLE_TASK_ID));
+
+        Self {
+            tasks,
+            run_queue: VecDeque::new(),
+            current: IDLE_TASK_ID,
+            next_id: 1,
+        }
+    }
+
+    pub fn current(&self) -> TaskId {
+        self.current
+    }
+
+    pub fn current_pid(&self) -> Option<Pid> {
+        self.tasks[&self.current].pid
+    }
+
+    /// The address space of the current task, or `None` if it only runs kernel code.
+    pub(crate) fn current_address_space(&self) -> Option<Arc<AddressSpace>> {
+        self.tasks[&self.current].address_space.clone()
+    }
+
+    pub(crate) fn next_task_id(&mut self) -> TaskId {
+        let id = TaskId::new(self.next_id);
+        self.next_id += 1;
+        id
+    }
+
+    /// Adds a task to the back of the run queue.
+    pub(crate) fn add(&mut self, task: Task) {
+        let id = task.id;
+        self.tasks.insert(id, task);
+        self.run_queue.push_back(id);
+    }
+
+    /// Moves a blocked task back to the run queue.
+    pub(crate) fn wake(&mut self, id: TaskId) {
+        if let Some(task) = self.tasks.get_mut(&id) {
+            if task.state == TaskState::Blocked {
+                task.state = TaskState::Ready;
+                task.interruptible = false;
+                self.run_queue.push_back(id);
+            }
+        }
+    }
+
+    /// Asks a task to terminate, see [`request_exit`].
+    fn request_exit(&mut self, id: TaskId) {
+        if let Some(task) = self.tasks.get_mut(&id) {
+            task.exit_requested = true;
+            if task.interruptible {
+                self.wake(id);
+            }
+        }
+    }
+
+    fn set_current_state(&mut self, state: TaskState) {
+        self.tasks
+            .get_mut(&self.current)
+            .expect("Current task not found.")
+            .state = state;
+    }
+
+    /// Saves the context of the current task and returns the context of the next one to run.
+    fn schedule(&mut self, context: VirtualAddress) -> VirtualAddress {
+        self.reap();
+
+        let current = self
+            .tasks
+            .get_mut(&self.current)
+            .expect("Current task not found.");
+        current.context = context;
+        if current.state == TaskState::Running {
+            current.state = TaskState::Ready;
+            if current.id != IDLE_TASK_ID {
+                self.run_queue.push_back(current.id);
+            }
+        }
+
+        let next_id = loop {
+            let id = self.run_queue.pop_front().unwrap_or(IDLE_TASK_ID);
+            // Tasks asked to exit while they ran user code terminate instead of resuming it
+            let next = self.tasks.get_mut(&id).expect("Next task not found.");
+            if next.exit_requested && next.in_user_mode() {
+                next.state = TaskState::Dead;
+                continue;
+            }
+            break id;
+        };
+        self.switch_to(next_id)
+    }
+
+    fn switch_to(&mut self, id: TaskId) -> VirtualAddress {
+        let next = self.tasks.get_mut(&id).expect("Next task not found.");
+        next.state = TaskState::Running;
+        self.current = id;
+
+        if let Some(kernel_stack) = &next.kernel_stack {
+            gdt::set_kernel_stack(kernel_stack.top());
+            syscall::set_kernel_stack(kernel_stack.top());
+        }
+        match &next.address_space {
+            Some(address_space) => address_space.activate(),
+            None => address_space::activate_kernel(),
+        }
+        arch::set_thread_pointer(next.thread_pointer);
+
+        next.context
+    }
+
+    /// Frees the tasks that finished. The current one is kept, as its stack is still in use.
+    fn reap(&mut self) {
+        let current = self.current;
+        self.tasks
+            .retain(|id, task| *id == current || task.state != TaskState::Dead);
+    }
+}
+
+/// Called by the interrupt wrappers with the context of the interrupted task.
+pub(crate) extern "C" fn schedule(context: *mut Context) -> *mut Context {
+    let Some(scheduler) = SCHEDULER.get() else {
+        return context;
+    };
+
+    scheduler
+        .lock()
+        .schedule(VirtualAddress::from_ptr(context))
+        .as_mut_ptr()
+}
+
+/// Runs `f` with the scheduler locked. Interrupts are disabled meanwhile, as the timer interrupt
+/// needs the lock too.
+fn with_scheduler<R>(f: impl FnOnce(&mut Scheduler) -> R) -> R {
+    let scheduler = SCHEDULER.get().expect("Scheduler not initialized.");
+    interrupt::without_interrupts(|| f(&mut scheduler.lock()))
+}
+
+pub(crate) fn spawn(
+    pid: Option<Pid>,
+    context: Context,
+    address_space: Option<Arc<AddressSpace>>,
+    thread_pointer: VirtualAddress,
+) -> TaskId {
+    with_scheduler(|scheduler| {
+        let id = scheduler.next_task_id();
+        let task = Task::new(id, pid, context, address_space, thread_pointer);
+        scheduler.add(task);
+        id
+    })
+}
+
+/// Adds a task that runs `entry(arg)` in ring 0, on its own stack.
+pub(crate) fn spawn_kernel(entry: extern "C" fn(usize) -> !, arg: usize) -> TaskId {
+    with_scheduler(|scheduler| {
+        let id = scheduler.next_task_id();
+        scheduler.add(Task::new_kernel(id, entry, arg));
+        id
+    })
+}
+
+pub(crate) fn current() -> TaskId {
+    with_scheduler(|scheduler| scheduler.current())
+}
+
+pub(crate) fn current_pid() -> Option<Pid> {
+    with_scheduler(|scheduler| scheduler.current_pid())
+}
+
+pub(crate) fn current_address_space() -> Option<Arc<AddressSpace>> {
+    with_scheduler(|scheduler| scheduler.current_address_space())
+}
+
+/// Moves the current task to another address space, and releases the one it used.
+pub(crate) fn set_current_address_space(address_space: Arc<AddressSpace>) {
+    with_scheduler(|scheduler| {
+        let current = scheduler.current;
+        let task = scheduler
+            .tasks
+            .get_mut(&current)
+            .expect("Current task not found.");
+        address_space.activate();
+        task.address_space = Some(address_space);
+    })
+}
+
+pub(crate) fn current_thread_pointer() -> VirtualAddress {
+    with_scheduler(|scheduler| scheduler.tasks[&scheduler.current].thread_pointer)
+}
+
+/// Changes the thread pointer of the current task, e.g. after exec.
+pub(crate) fn set_current_thread_pointer(thread_pointer: VirtualAddress) {
+    with_scheduler(|scheduler| {
+        let current = scheduler.current;
+        let task = scheduler
+            .tasks
+            .get_mut(&current)
+            .expect("Current task not found.");
+        task.thread_pointer = thread_pointer;
+        arch::set_thread_pointer(thread_pointer);
+    })
+}
+
+pub(crate) fn wake(id: TaskId) {
+    with_scheduler(|scheduler| scheduler.wake(id));
+}
+
+/// Takes the current task out of the run queue until [`wake`] is called for it.
+///
+/// To avoid missing a wake-up, callers check their condition and call this with interrupts
+/// disabled. Nothing else runs on the CPU in between, and the task is still switched out.
+pub(crate) fn block_current() {
+    with_scheduler(|scheduler| scheduler.set_current_state(TaskState::Blocked));
+    interrupt::yield_now();
+}
+
+/// Like [`block_current`], but [`request_exit`] wakes the task too. Callers check
+/// [`exit_requested`] once woken, and return early so the task can terminate. The task doesn't
+/// block at all if it was already asked to exit.
+pub(crate) fn block_current_interruptible() {
+    let blocked = with_scheduler(|scheduler| {
+        let current = scheduler.current;
+        let task = scheduler
+            .tasks
+            .get_mut(&current)
+            .expect("Current task not found.");
+        if task.exit_requested {
+            return false;
+        }
+        task.state = TaskState::Blocked;
+        task.interruptible = true;
+        true
+    });
+    if blocked {
+        interrupt::yield_now();
+    }
+}
+
+/// Asks another task to terminate, e.g. a thread of a process that exits. It isn't stopped on the
+/// spot, as it may hold locks in the kernel: it terminates itself the next time it would return to
+/// user mode, and the waits it's blocked in return early.
+pub(crate) fn request_exit(id: TaskId) {
+    with_scheduler(|scheduler| {
+        assert_ne!(
+            id, scheduler.current,
+            "The current task can't be asked to exit."
+        );
+        scheduler.request_exit(id);
+    })
+}
+
+/// Whether the current task was asked to terminate, see [`request_exit`].
+pub(crate) fn exit_requested() -> bool {
+    with_scheduler(|scheduler| scheduler.tasks[&scheduler.current].exit_requested)
+}
+
+/// Terminates the current task if it was asked to. Called before returning to user mode.
+pub(crate) fn exit_if_requested() {
+    if exit_requested() {
+        exit_current();
+    }
+}
+
+/// Terminates the current task and switches to the next one.
+pub(crate) fn exit_current() -> ! {
+    with_scheduler(|scheduler| scheduler.set_current_state(TaskState::Dead));
+    interrupt::yield_now();
+    unreachable!("A dead task was scheduled again.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_scheduler_initialized() {
+        assert!(SCHEDULER.is_completed());
+    }
+
+    #[test_case]
+    fn test_round_robin() {
+        let mut scheduler = Scheduler::new();
+        let first = scheduler.next_task_id();
+        let second = scheduler.next_task_id();
+        scheduler.tasks.insert(first, Task::current(first));
+        scheduler.tasks.insert(second, Task::current(second));
+        scheduler.tasks.get_mut(&first).unwrap().state = TaskState::Ready;
+        scheduler.tasks.get_mut(&second).unwrap().state = TaskState::Ready;
+        scheduler.run_queue.extend([first, second]);
+
+        scheduler.schedule(VirtualAddress::new(0x1000));
+        assert_eq!(scheduler.current(), first);
+        scheduler.schedule(VirtualAddress::new(0x2000));
+        assert_eq!(scheduler.current(), second);
+        scheduler.schedule(VirtualAddress::new(0x3000));
+        assert_eq!(scheduler.current(), first);
+        assert_eq!(
+            scheduler.tasks[&second].context,
+            VirtualAddress::new(0x3000)
+        );
+    }
+
+    #[test_case]
+    fn test_request_exit() {
+        let mut scheduler = Scheduler::new();
+        let blocked = scheduler.next_task_id();
+        let interruptible = scheduler.next_task_id();
+        for id in [blocked, interruptible] {
+            let mut task = Task::current(id);
+            task.state = TaskState::Blocked;
+            scheduler.tasks.insert(id, task);
+        }
+        scheduler
+            .tasks
+            .get_mut(&interruptible)
+            .unwrap()
+            .interruptible = true;
+
+        // Only interruptible waits are cut short, the other task exits once it's woken
+        scheduler.request_exit(blocked);
+        scheduler.request_exit(interruptible);
+        assert_eq!(scheduler.run_queue, [interruptible]);
+        assert_eq!(scheduler.tasks[&blocked].state, TaskState::Blocked);
+        assert!(scheduler.tasks[&blocked].exit_requested);
+    }
+}
@@ -0,0 +1,501 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+use kernel_api::error::Errno;
+use spin::Mutex;
+
+use crate::arch::memory::address_space::{AddressSpace, USER_SPACE_END};
+use crate::arch::Context;
+use crate::memory::address::VirtualAddress;
+use crate::process::elf::ElfFile;
+use crate::process::loader::{LoadError, Program, TlsTemplate};
+use crate::process::task::TaskId;
+use crate::process::{loader, scheduler, Pid};
+use crate::trace;
+
+pub(crate) static PROCESS_TABLE: Mutex<ProcessTable> = Mutex::new(ProcessTable::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Running,
+    /// The process exited, but its parent didn't collect the exit code yet.
+    Zombie(i32),
+}
+
+pub struct Process {
+    pub pid: Pid,
+    pub parent: Option<Pid>,
+    pub children: Vec<Pid>,
+    pub state: ProcessState,
+    /// Released when the process exits. The tasks keep their own reference until they're reaped.
+    pub(crate) address_space: Option<Arc<AddressSpace>>,
+    /// The template of the thread-local storage of the program.
+    tls: Option<TlsTemplate>,
+    threads: Vec<Thread>,
+    /// Stacks left by exited threads, reused before new ones are mapped.
+    free_stacks: Vec<usize>,
+    /// How many stacks the process has mapped.
+    stack_count: usize,
+    /// Tasks of this process blocked in [`wait`].
+    child_waiters: Vec<TaskId>,
+}
+
+impl Process {
+    /// A process whose only thread is `task`, on the first stack.
+    fn new(
+        pid: Pid,
+        parent: Option<Pid>,
+        address_space: Option<Arc<AddressSpace>>,
+        tls: Option<TlsTemplate>,
+        task: TaskId,
+    ) -> Self {
+        Self {
+            pid,
+            parent,
+            children: Vec::new(),
+            state: ProcessState::Running,
+            address_space,
+            tls,
+            threads: vec![Thread::new(task, 0)],
+            free_stacks: Vec::new(),
+            stack_count: 1,
+            child_waiters: Vec::new(),
+        }
+    }
+
+    fn thread_mut(&mut self, task: TaskId) -> Option<&mut Thread> {
+        self.threads.iter_mut().find(|thread| thread.task == task)
+    }
+
+    /// Whether `task` is a thread of the process. Threads stop being part of it when another one
+    /// makes the process exit or exec.
+    fn has_thread(&self, task: TaskId) -> bool {
+        self.threads.iter().any(|thread| thread.task == task)
+    }
+
+    /// Asks the running threads other than `task` to exit, and forgets about all of them. They
+    /// may still run in the kernel for a while, but they're not part of the process anymore.
+    fn exit_other_threads(&mut self, task: TaskId) {
+        for thread in &self.threads {
+            if thread.task != task && thread.state == ThreadState::Running {
+                scheduler::request_exit(thread.task);
+            }
+        }
+        self.threads.retain(|thread| thread.task == task);
+    }
+
+    fn allocate_stack(&mut self) -> Option<usize> {
+        if let Some(stack) = self.free_stacks.pop() {
+            return Some(stack);
+        }
+        if self.stack_count == loader::MAX_THREADS {
+            return None;
+        }
+        self.stack_count += 1;
+        Some(self.stack_count - 1)
+    }
+}
+
+/// A task running in the address space of a process.
+struct Thread {
+    task: TaskId,
+    /// The index of its user stack, see [`loader::thread_stack`].
+    stack: usize,
+    state: ThreadState,
+    /// The task blocked in [`join_thread`] until this one exits.
+    joiner: Option<TaskId>,
+}
+
+impl Thread {
+    fn new(task: TaskId, stack: usize) -> Self {
+        Self {
+            task,
+            stack,
+            state: ThreadState::Running,
+            joiner: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ThreadState {
+    Running,
+    /// The thread exited, but wasn't joined yet.
+    Exited(i32),
+}
+
+pub struct ProcessTable {
+    processes: BTreeMap<Pid, Process>,
+    next_pid: usize,
+}
+
+impl ProcessTable {
+    const fn new() -> Self {
+        Self {
+            processes: BTreeMap::new(),
+            next_pid: 1,
+        }
+    }
+
+    fn next_pid(&mut self) -> Pid {
+        let pid = Pid::new(self.next_pid);
+        self.next_pid += 1;
+        pid
+    }
+
+    fn insert(&mut self, process: Process) {
+        if let Some(parent) = process.parent.and_then(|pid| self.processes.get_mut(&pid)) {
+            parent.children.push(process.pid);
+        }
+        self.processes.insert(process.pid, process);
+    }
+
+    /// Turns the process into a zombie. Returns the tasks waiting for it to exit.
+    fn exit(&mut self, pid: Pid, exit_code: i32) -> Vec<TaskId> {
+        let process = self.processes.get_mut(&pid).expect("Process not found.");
+        process.state = ProcessState::Zombie(exit_code);
+        process.address_space = None;
+        let parent = process.parent;
+        let children = core::mem::take(&mut process.children);
+
+        // Orphans are not waited for by anyone
+        for child in children {
+            if let Some(child) = self.processes.get_mut(&child) {
+                child.parent = None;
+                if matches!(child.state, ProcessState::Zombie(_)) {
+                    let child_pid = child.pid;
+                    self.processes.remove(&child_pid);
+                }
+            }
+        }
+
+        match parent.and_then(|parent| self.processes.get_mut(&parent)) {
+            Some(parent) => core::mem::take(&mut parent.child_waiters),
+            None => {
+                self.processes.remove(&pid);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Removes an exited child of `parent` from the table. `child` selects which one, or any if
+    /// `None`. Returns `None` if there's no such child, or `Some(None)` if it's still running.
+    fn reap_child(&mut self, parent: Pid, child: Option<Pid>) -> Option<Option<(Pid, i32)>> {
+        let children = &self.processes.get(&parent)?.children;
+        let mut candidates = children
+            .iter()
+            .filter(|pid| child.map_or(true, |child| child == **pid))
+            .peekable();
+        candidates.peek()?;
+
+        let zombie = candidates.find_map(|pid| match self.processes[pid].state {
+            ProcessState::Zombie(exit_code) => Some((*pid, exit_code)),
+            ProcessState::Running => None,
+        });
+
+        if let Some((pid, _)) = zombie {
+            self.processes.remove(&pid);
+            let parent = self.processes.get_mut(&parent).unwrap();
+            parent.children.retain(|child| *child != pid);
+        }
+        Some(zombie)
+    }
+}
+
+/// Creates a process from the ELF file, as a child of the current one.
+pub(crate) fn spawn(elf_file: &ElfFile, args: &[&str]) -> Result<Pid, LoadError> {
+    let parent = scheduler::current_pid();
+    let program = loader::load(elf_file, args, &[])?;
+    let address_space = Arc::new(program.address_space);
+
+    let mut table = PROCESS_TABLE.lock();
+    let pid = table.next_pid();
+    let task = scheduler::spawn(
+        Some(pid),
+        program.context,
+        Some(address_space.clone()),
+        program.thread_pointer,
+    );
+    table.insert(Process::new(
+        pid,
+        parent,
+        Some(address_space),
+        program.tls,
+        task,
+    ));
+    trace!("Process {} created with task {:?}", pid, task);
+    Ok(pid)
+}
+
+/// Creates a copy of the current process, whose task resumes from `context` with a syscall
+/// result of 0. Only the calling thread is copied. Returns `None` if the current task isn't part
+/// of a process.
+pub(crate) fn fork(context: &Context) -> Option<Pid> {
+    let parent = scheduler::current_pid()?;
+    let current = scheduler::current();
+
+    let mut table = PROCESS_TABLE.lock();
+    let parent_process = table.processes.get_mut(&parent)?;
+    let address_space = Arc::new(parent_process.address_space.as_ref()?.fork());
+    let tls = parent_process.tls;
+    let stack_count = parent_process.stack_count;
+    let stack = parent_process.thread_mut(current)?.stack;
+    let mut context = *context;
+    context.set_syscall_result(0);
+
+    let pid = table.next_pid();
+    let thread_pointer = scheduler::current_thread_pointer();
+    let task = scheduler::spawn(
+        Some(pid),
+        context,
+        Some(address_space.clone()),
+        thread_pointer,
+    );
+    let mut process = Process::new(pid, Some(parent), Some(address_space), tls, task);
+    // The stacks of the other threads are copied too, and free for new ones
+    process.threads[0].stack = stack;
+    process.stack_count = stack_count;
+    process.free_stacks = (0..stack_count).filter(|index| *index != stack).collect();
+    table.insert(process);
+    trace!(
+        "Process {} forked from {} with task {:?}",
+        pid,
+        parent,
+        task
+    );
+    Some(pid)
+}
+
+/// Replaces the program of the current process with `program`. The other threads are terminated,
+/// and the pid and the parent are kept. Returns the context the current task resumes from, or
+/// `None` if it isn't part of a process.
+pub(crate) fn exec(program: Program) -> Option<Context> {
+    let pid = scheduler::current_pid()?;
+    let current = scheduler::current();
+    let address_space = Arc::new(program.address_space);
+
+    let mut table = PROCESS_TABLE.lock();
+    let process = table.processes.get_mut(&pid)?;
+    // Another thread may have made the process exit or exec already
+    if !process.has_thread(current) {
+        return None;
+    }
+    process.exit_other_threads(current);
+    process.threads = vec![Thread::new(current, 0)];
+    process.free_stacks.clear();
+    process.stack_count = 1;
+    process.tls = program.tls;
+
+    // Released at the end, once the new address space is active
+    let _previous = process.address_space.replace(address_space.clone());
+    scheduler::set_current_address_space(address_space);
+    scheduler::set_current_thread_pointer(program.thread_pointer);
+    trace!("Process {} replaced its program", pid);
+    Some(program.context)
+}
+
+/// Terminates the current process with the given exit code, with all its threads.
+pub(crate) fn exit(exit_code: i32) -> ! {
+    if let Some(pid) = scheduler::current_pid() {
+        let current = scheduler::current();
+        let waiters = {
+            let mut table = PROCESS_TABLE.lock();
+            match table.processes.get_mut(&pid) {
+                // Another thread may have made the process exit or exec already
+                Some(process) if process.has_thread(current) => {
+                    process.exit_other_threads(current);
+                    Some(table.exit(pid, exit_code))
+                }
+                _ => None,
+            }
+        };
+        if let Some(waiters) = waiters {
+            waiters.into_iter().for_each(scheduler::wake);
+            trace!("Process {} exited with code {}", pid, exit_code);
+        }
+    }
+    scheduler::exit_current();
+}
+
+/// Starts a thread of the current process at `entry`, with `arg` as the argument. It gets its own
+/// stack and thread-local storage, see [`loader::thread_stack`].
+pub(crate) fn create_thread(entry: VirtualAddress, arg: usize) -> Result<TaskId, Errno> {
+    let pid = scheduler::current_pid().ok_or(Errno::InvalidArgument)?;
+    let current = scheduler::current();
+    if entry.as_u64() >= USER_SPACE_END {
+        return Err(Errno::InvalidArgument);
+    }
+
+    let mut table = PROCESS_TABLE.lock();
+    let process = table
+        .processes
+        .get_mut(&pid)
+        .filter(|process| process.has_thread(current))
+        .ok_or(Errno::NoSuchProcess)?;
+    let address_space = process.address_space.clone().unwrap();
+    let stack = process.allocate_stack().ok_or(Errno::TryAgain)?;
+    let (stack_top, thread_pointer) =
+        match loader::thread_stack(&address_space, stack, process.tls.as_ref()) {
+            Ok(stack) => stack,
+            Err(error) => {
+                process.free_stacks.push(stack);
+                return Err(error);
+            }
+        };
+
+    // Enter like a call would, with the return address slot below the aligned stack top
+    let stack_pointer = VirtualAddress::new(stack_top - size_of::<u64>() as u64);
+    let mut context = Context::new_user(entry, stack_pointer);
+    context.set_argument(arg);
+    let task = scheduler::spawn(Some(pid), context, Some(address_space), thread_pointer);
+    process.threads.push(Thread::new(task, stack));
+    trace!("Process {} started thread {:?}", pid, task);
+    Ok(task)
+}
+
+/// Terminates the current thread. The process exits if it was the last one.
+pub(crate) fn exit_thread(exit_code: i32) -> ! {
+    let Some(pid) = scheduler::current_pid() else {
+        scheduler::exit_current();
+    };
+    let current = scheduler::current();
+
+    let joiner = {
+        let mut table = PROCESS_TABLE.lock();
+        let Some(process) = table
+            .processes
+            .get_mut(&pid)
+            .filter(|process| process.has_thread(current))
+        else {
+            // The process exited or exec'd meanwhile, the thread was asked to exit anyway
+            drop(table);
+            scheduler::exit_current();
+        };
+        let others_running = process
+            .threads
+            .iter()
+            .any(|thread| thread.task != current && thread.state == ThreadState::Running);
+        if !others_running {
+            drop(table);
+            exit(exit_code);
+        }
+        let thread = process.thread_mut(current).unwrap();
+        thread.state = ThreadState::Exited(exit_code);
+        let (stack, joiner) = (thread.stack, thread.joiner.take());
+        process.free_stacks.push(stack);
+        joiner
+    };
+    if let Some(joiner) = joiner {
+        scheduler::wake(joiner);
+    }
+    scheduler::exit_current();
+}
+
+/// Blocks until the thread of the current process running as `task` exits, and returns its exit
+/// code.
+pub(crate) fn join_thread(task: TaskId) -> Result<i32, Errno> {
+    let pid = scheduler::current_pid().ok_or(Errno::InvalidArgument)?;
+    let current = scheduler::current();
+    if task == current {
+        return Err(Errno::InvalidArgument);
+    }
+
+    loop {
+        {
+            let mut table = PROCESS_TABLE.lock();
+            let process = table.processes.get_mut(&pid).ok_or(Errno::NoSuchProcess)?;
+            let index = process
+                .threads
+                .iter()
+                .position(|thread| thread.task == task)
+                .ok_or(Errno::NoSuchProcess)?;
+            let thread = &mut process.threads[index];
+            match thread.state {
+                ThreadState::Exited(exit_code) => {
+                    process.threads.remove(index);
+                    return Ok(exit_code);
+                }
+                ThreadState::Running if thread.joiner.is_some_and(|joiner| joiner != current) => {
+                    return Err(Errno::InvalidArgument);
+                }
+                ThreadState::Running => thread.joiner = Some(current),
+            }
+        }
+        scheduler::block_current_interruptible();
+        if scheduler::exit_requested() {
+            return Err(Errno::NoSuchProcess);
+        }
+    }
+}
+
+/// Blocks until a child of the current process exits. Returns its pid and exit code, or `None`
+/// if the current process has no such child.
+pub(crate) fn wait(child: Option<Pid>) -> Option<(Pid, i32)> {
+    let pid = scheduler::current_pid()?;
+    loop {
+        {
+            let mut table = PROCESS_TABLE.lock();
+            if let Some(exited) = table.reap_child(pid, child)? {
+                return Some(exited);
+            }
+            let current = scheduler::current();
+            table
+                .processes
+                .get_mut(&pid)
+                .unwrap()
+                .child_waiters
+                .push(current);
+        }
+        scheduler::block_current_interruptible();
+        if scheduler::exit_requested() {
+            return None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(table: &mut ProcessTable, parent: Option<Pid>) -> Pid {
+        let pid = table.next_pid();
+        table.insert(Process::new(pid, parent, None, None, TaskId::new(0)));
+        pid
+    }
+
+    #[test_case]
+    fn test_reap_child() {
+        let mut table = ProcessTable::new();
+        let parent = process(&mut table, None);
+        let first = process(&mut table, Some(parent));
+        let second = process(&mut table, Some(parent));
+        assert_eq!(
+            table.processes.get(&parent).unwrap().children,
+            [first, second]
+        );
+
+        assert_eq!(table.reap_child(parent, None), Some(None));
+        table.exit(second, 42);
+        assert_eq!(table.reap_child(parent, Some(first)), Some(None));
+        assert_eq!(table.reap_child(parent, None), Some(Some((second, 42))));
+        assert!(table.processes.get(&second).is_none());
+        assert_eq!(table.reap_child(parent, Some(second)), None);
+    }
+
+    #[test_case]
+    fn test_exit_without_parent() {
+        let mut table = ProcessTable::new();
+        let parent = process(&mut table, None);
+        let child = process(&mut table, Some(parent));
+
+        table.exit(parent, 0);
+        assert!(table.processes.get(&parent).is_none());
+        assert_eq!(table.processes.get(&child).unwrap().parent, None);
+
+        table.exit(child, 0);
+        assert!(table.processes.get(&child).is_none());
+    }
+}
@@ -0,0 +1,142 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
+use core::mem::size_of;
+use core::ptr;
+
+use crate::arch::memory::address_space::AddressSpace;
+use crate::arch::Context;
+use crate::memory::address::VirtualAddress;
+use crate::process::Pid;
+
+const KERNEL_STACK_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TaskId(usize);
+
+impl TaskId {
+    pub const fn new(id: usize) -> Self {
+        Self(id)
+    }
+
+    pub fn as_usize(&self) -> usize {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    Ready,
+    Running,
+    /// Waiting for an event. The task is out of the run queue until it's woken up.
+    Blocked,
+    Dead,
+}
+
+/// The stack used by a task while it runs in ring 0.
+pub struct KernelStack {
+    stack: Box<[u8]>,
+}
+
+impl KernelStack {
+    fn new() -> Self {
+        Self {
+            stack: vec![0; KERNEL_STACK_SIZE].into_boxed_slice(),
+        }
+    }
+
+    pub fn top(&self) -> VirtualAddress {
+        // Keep the top 16 bytes aligned, as the CPU does when it pushes an interrupt frame
+        (VirtualAddress::from_ptr(self.stack.as_ptr()) + KERNEL_STACK_SIZE as u64).align_down(16)
+    }
+}
+
+pub struct Task {
+    pub id: TaskId,
+    /// The process the task belongs to, or `None` for kernel tasks.
+    pub pid: Option<Pid>,
+    pub(crate) state: TaskState,
+    /// Where the [`Context`] of the task was saved the last time it was switched out.
+    pub(crate) context: VirtualAddress,
+    pub(crate) kernel_stack: Option<KernelStack>,
+    pub(crate) address_space: Option<Arc<AddressSpace>>,
+    /// Where the thread-local storage of a user task is, see [`crate::arch::set_thread_pointer`].
+    pub(crate) thread_pointer: VirtualAddress,
+    /// Set when another thread made the process exit or exec. The task terminates instead of
+    /// returning to user mode, see [`crate::process::scheduler::request_exit`].
+    pub(crate) exit_requested: bool,
+    /// Whether the task is blocked in a wait that an exit request cuts short.
+    pub(crate) interruptible: bool,
+}
+
+impl Task {
+    /// The task for the code that is already running, e.g. the boot context.
+    pub(crate) fn current(id: TaskId) -> Self {
+        Self {
+            id,
+            pid: None,
+            state: TaskState::Running,
+            context: VirtualAddress::zeroed(),
+            kernel_stack: None,
+            address_space: None,
+            thread_pointer: VirtualAddress::zeroed(),
+            exit_requested: false,
+            interruptible: false,
+        }
+    }
+
+    pub(crate) fn new(
+        id: TaskId,
+        pid: Option<Pid>,
+        context: Context,
+        address_space: Option<Arc<AddressSpace>>,
+        thread_pointer: VirtualAddress,
+    ) -> Self {
+        let mut task = Self::with_stack(id, pid, KernelStack::new(), context, address_space);
+        task.thread_pointer = thread_pointer;
+        task
+    }
+
+    /// A kernel task that runs `entry(arg)` on its kernel stack.
+    pub(crate) fn new_kernel(id: TaskId, entry: extern "C" fn(usize) -> !, arg: usize) -> Self {
+        let kernel_stack = KernelStack::new();
+        // The context is popped before `entry` runs, so the stack starts at the top. The slot
+        // below it stands for the return address of a call, which keeps the usual alignment.
+        let stack_pointer = VirtualAddress::new(kernel_stack.top().as_u64() - 8);
+        let entry = VirtualAddress::new(entry as usize as u64);
+        let context = Context::new_kernel(entry, stack_pointer, arg);
+        Self::with_stack(id, None, kernel_stack, context, None)
+    }
+
+    /// Whether the task was switched out while running user code.
+    pub(crate) fn in_user_mode(&self) -> bool {
+        self.kernel_stack.is_some() && unsafe { (*self.context.as_ptr::<Context>()).in_user_mode() }
+    }
+
+    fn with_stack(
+        id: TaskId,
+        pid: Option<Pid>,
+        kernel_stack: KernelStack,
+        context: Context,
+        address_space: Option<Arc<AddressSpace>>,
+    ) -> Self {
+        // The task starts by being "resumed" from a context placed at the top of its stack
+        let context_addr =
+            VirtualAddress::new(kernel_stack.top().as_u64() - size_of::<Context>() as u64);
+        unsafe {
+            ptr::write(context_addr.as_mut_ptr::<Context>(), context);
+        }
+
+        Self {
+            id,
+            pid,
+            state: TaskState::Ready,
+            context: context_addr,
+            kernel_stack: Some(kernel_stack),
+            address_space,
+            thread_pointer: VirtualAddress::zeroed(),
+            exit_requested: false,
+            interruptible: false,
+        }
+    }
+}
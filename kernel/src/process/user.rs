@@ -0,0 +1,181 @@
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::mem::size_of;
+use core::{ptr, slice};
+
+use kernel_api::error::Errno;
+use kernel_api::syscall::{StringRef, Timespec};
+
+use crate::memory::address::VirtualAddress;
+use crate::process::scheduler;
+
+/// Types that can be copied out of user memory: every bit pattern is a valid value.
+pub(crate) unsafe trait Plain: Copy + Default {}
+
+unsafe impl Plain for u8 {}
+unsafe impl Plain for i32 {}
+unsafe impl Plain for u32 {}
+unsafe impl Plain for usize {}
+unsafe impl Plain for StringRef {}
+unsafe impl Plain for Timespec {}
+
+/// A pointer to a value in the memory of the calling process.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct UserPtr<T> {
+    addr: usize,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Plain> UserPtr<T> {
+    pub fn new(addr: usize) -> Self {
+        Self {
+            addr,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn read(&self) -> Result<T, Errno> {
+        Ok(UserSlice::new(self.addr, 1)?.read()?[0])
+    }
+
+    pub fn write(&self, value: T) -> Result<(), Errno> {
+        UserSlice::new(self.addr, 1)?.write(&[value])
+    }
+}
+
+/// `len` values starting at `addr` in the memory of the calling process.
+///
+/// The memory is only accessed through copies, after checking that every page of the range is
+/// mapped for user mode, so a bad pointer turns into [`Errno::Fault`] instead of a page fault in
+/// the kernel. Tasks without an address space run kernel code, and their pointers are trusted.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct UserSlice<T> {
+    addr: usize,
+    len: usize,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Plain> UserSlice<T> {
+    pub fn new(addr: usize, len: usize) -> Result<Self, Errno> {
+        let size = len.checked_mul(size_of::<T>()).ok_or(Errno::Fault)?;
+        addr.checked_add(size).ok_or(Errno::Fault)?;
+        Ok(Self {
+            addr,
+            len,
+            _marker: PhantomData,
+        })
+    }
+
+    fn size(&self) -> usize {
+        self.len * size_of::<T>()
+    }
+
+    /// Copies the values into a new vector.
+    pub fn read(&self) -> Result<Vec<T>, Errno> {
+        // Checked first, so a bogus length can't make the kernel allocate a huge buffer
+        if let Some(address_space) = scheduler::current_address_space() {
+            let addr = VirtualAddress::new(self.addr as u64);
+            if !address_space.is_user_range(addr, self.size(), false) {
+                return Err(Errno::Fault);
+            }
+        }
+        let mut values = vec![T::default(); self.len];
+        self.read_into(&mut values)?;
+        Ok(values)
+    }
+
+    fn read_into(&self, values: &mut [T]) -> Result<(), Errno> {
+        if self.len == 0 {
+            return Ok(());
+        }
+        let buffer =
+            unsafe { slice::from_raw_parts_mut(values.as_mut_ptr() as *mut u8, self.size()) };
+        match scheduler::current_address_space() {
+            Some(address_space) => address_space
+                .read_user(VirtualAddress::new(self.addr as u64), buffer)
+                .then_some(())
+                .ok_or(Errno::Fault),
+            None => {
+                unsafe {
+                    ptr::copy_nonoverlapping(
+                        self.addr as *const u8,
+                        buffer.as_mut_ptr(),
+                        buffer.len(),
+                    )
+                };
+                Ok(())
+            }
+        }
+    }
+
+    /// Copies `values` to the start of the slice. Fails if there are more values than fit.
+    pub fn write(&self, values: &[T]) -> Result<(), Errno> {
+        if values.len() > self.len {
+            return Err(Errno::InvalidArgument);
+        }
+        if values.is_empty() {
+            return Ok(());
+        }
+        let data = unsafe {
+            slice::from_raw_parts(values.as_ptr() as *const u8, values.len() * size_of::<T>())
+        };
+        match scheduler::current_address_space() {
+            Some(address_space) => address_space
+                .write_user(VirtualAddress::new(self.addr as u64), data)
+                .then_some(())
+                .ok_or(Errno::Fault),
+            None => {
+                unsafe {
+                    ptr::copy_nonoverlapping(data.as_ptr(), self.addr as *mut u8, data.len())
+                };
+                Ok(())
+            }
+        }
+    }
+}
+
+impl UserSlice<u8> {
+    /// Copies the bytes into a string. Fails with [`Errno::InvalidArgument`] if they aren't UTF-8.
+    pub fn read_string(&self) -> Result<String, Errno> {
+        String::from_utf8(self.read()?).map_err(|_| Errno::InvalidArgument)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::arch::memory::address_space::{AddressSpace, USER_SPACE_END};
+    use crate::arch::memory::paging::{Page, PageFlags, PAGE_SIZE};
+
+    use super::*;
+
+    #[test_case]
+    fn test_user_ranges() {
+        assert!(UserSlice::<u8>::new(usize::MAX, 2).is_err());
+        assert!(UserSlice::<usize>::new(0x1000, usize::MAX / 4).is_err());
+        assert_eq!(UserSlice::<StringRef>::new(0x1000, 3).unwrap().size(), 48);
+
+        let address_space = AddressSpace::new();
+        let page = Page::containing_address(VirtualAddress::new(0xC0_FFEE_0000));
+        let flags = PageFlags {
+            user_accessible: true,
+            writable: false,
+            executable: false,
+        };
+        address_space.map_zeroed_page(page, flags);
+        address_space.write(page.start_address + 8, b"user");
+
+        let start = page.start_address.as_u64();
+        let mut buffer = [0; 4];
+        assert!(address_space.read_user(VirtualAddress::new(start + 8), &mut buffer));
+        assert_eq!(&buffer, b"user");
+        // Read-only, and the next page isn't mapped
+        assert!(!address_space.write_user(VirtualAddress::new(start), b"user"));
+        assert!(!address_space.read_user(VirtualAddress::new(start + PAGE_SIZE - 2), &mut buffer));
+        // Kernel pages are never user memory
+        let kernel = VirtualAddress::new(0xFEED_CAFE_000);
+        assert!(!address_space.read_user(kernel, &mut buffer));
+        assert!(!address_space.read_user(VirtualAddress::new(USER_SPACE_END - 2), &mut buffer));
+    }
+}
@@ -0,0 +1,86 @@
+use crate::arch::interrupt;
+use crate::sync::mutex::MutexGuard;
+use crate::sync::wait_queue::WaitQueue;
+
+/// Lets tasks sleep until a condition on the data of a [`Mutex`](super::mutex::Mutex) holds.
+pub(crate) struct Condvar {
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Releases the lock and blocks until the condition variable is notified, then takes the
+    /// lock again. The task may also wake up spuriously, so callers check their condition again,
+    /// or use [`Condvar::wait_while`].
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        // A notification can't be missed, as nothing else runs before the task is blocked
+        interrupt::without_interrupts(|| {
+            drop(guard);
+            self.waiters.wait();
+        });
+        mutex.lock()
+    }
+
+    /// Waits as long as `condition` holds for the data.
+    pub fn wait_while<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_one(&self) {
+        self.waiters.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.waiters.wake_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::collections::VecDeque;
+    use alloc::sync::Arc;
+
+    use crate::process::kthread;
+    use crate::sync::mutex::Mutex;
+
+    use super::*;
+
+    #[test_case]
+    fn test_condvar() {
+        let state = Arc::new((Mutex::new(VecDeque::new()), Condvar::new()));
+
+        let consumer = {
+            let state = state.clone();
+            kthread::spawn(move || {
+                let (queue, condvar) = &*state;
+                let mut sum = 0;
+                for _ in 0..5 {
+                    let mut queue = condvar.wait_while(queue.lock(), |queue| queue.is_empty());
+                    sum += queue.pop_front().unwrap();
+                }
+                sum
+            })
+        };
+
+        let (queue, condvar) = &*state;
+        for value in 1..=5 {
+            queue.lock().push_back(value);
+            condvar.notify_one();
+            kthread::yield_now();
+        }
+        assert_eq!(consumer.join(), 15);
+    }
+}
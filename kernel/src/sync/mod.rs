@@ -0,0 +1,7 @@
+//! Primitives that put the waiting task to sleep instead of spinning. They need the scheduler,
+//! and can't be used from interrupt handlers.
+
+pub(crate) mod condvar;
+pub(crate) mod mutex;
+pub(crate) mod semaphore;
+pub(crate) mod wait_queue;
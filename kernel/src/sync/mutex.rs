@@ -0,0 +1,103 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::sync::wait_queue::WaitQueue;
+
+/// A mutual exclusion lock whose waiters sleep until it's released, so it can be held across
+/// long operations such as device I/O.
+pub(crate) struct Mutex<T: ?Sized> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Blocks until the lock is free, and takes it.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        self.waiters.wait_until(|| self.try_lock())
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| MutexGuard { mutex: self })
+    }
+}
+
+/// Gives access to the data of a [`Mutex`], and releases it when dropped.
+pub(crate) struct MutexGuard<'a, T: ?Sized> {
+    pub(super) mutex: &'a Mutex<T>,
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        self.mutex.waiters.wake_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+
+    use crate::process::kthread;
+
+    use super::*;
+
+    #[test_case]
+    fn test_mutex() {
+        let counter = Arc::new(Mutex::new(0));
+        let guard = counter.lock();
+        assert!(counter.try_lock().is_none());
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let counter = counter.clone();
+                kthread::spawn(move || {
+                    for _ in 0..10 {
+                        let mut value = counter.lock();
+                        let read = *value;
+                        // Other threads run meanwhile, but can't take the lock
+                        kthread::yield_now();
+                        *value = read + 1;
+                    }
+                })
+            })
+            .collect();
+        kthread::yield_now();
+        drop(guard);
+
+        threads.into_iter().for_each(kthread::JoinHandle::join);
+        assert_eq!(*counter.lock(), 40);
+    }
+}
@@ -0,0 +1,70 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::sync::wait_queue::WaitQueue;
+
+/// A counter of available resources. Tasks that acquire one while none is left sleep until
+/// another task releases one.
+pub(crate) struct Semaphore {
+    count: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Self {
+            count: AtomicUsize::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Blocks until a resource is available, and takes it.
+    pub fn acquire(&self) {
+        self.waiters.wait_until(|| self.try_acquire().then_some(()))
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        self.count
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |count| {
+                count.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    /// Gives back a resource, and wakes a task waiting for one.
+    pub fn release(&self) {
+        self.count.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+
+    use crate::process::kthread;
+
+    use super::*;
+
+    #[test_case]
+    fn test_semaphore() {
+        let semaphore = Arc::new(Semaphore::new(1));
+        semaphore.acquire();
+        assert!(!semaphore.try_acquire());
+
+        let done = Arc::new(Semaphore::new(0));
+        let thread = {
+            let (semaphore, done) = (semaphore.clone(), done.clone());
+            kthread::spawn(move || {
+                semaphore.acquire();
+                done.release();
+            })
+        };
+        kthread::yield_now();
+        assert!(!done.try_acquire());
+
+        semaphore.release();
+        done.acquire();
+        thread.join();
+        assert!(!semaphore.try_acquire());
+    }
+}
@@ -0,0 +1,63 @@
+use alloc::collections::VecDeque;
+
+use spin::Mutex;
+
+use crate::arch::interrupt;
+use crate::process::scheduler;
+use crate::process::task::TaskId;
+
+/// Tasks blocked until something else wakes them, in the order they started waiting.
+pub(crate) struct WaitQueue {
+    waiters: Mutex<VecDeque<TaskId>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Blocks the current task until it's woken.
+    ///
+    /// Like [`scheduler::block_current`], it must be called with interrupts disabled, in the same
+    /// critical section that checked the condition the task waits for.
+    pub fn wait(&self) {
+        self.waiters.lock().push_back(scheduler::current());
+        scheduler::block_current();
+    }
+
+    /// Blocks the current task until `condition` returns a value, and returns it. The condition
+    /// is checked with interrupts disabled, again every time the task is woken.
+    pub fn wait_until<R>(&self, mut condition: impl FnMut() -> Option<R>) -> R {
+        loop {
+            let result = interrupt::without_interrupts(|| {
+                let result = condition();
+                if result.is_none() {
+                    self.wait();
+                }
+                result
+            });
+            if let Some(result) = result {
+                return result;
+            }
+        }
+    }
+
+    /// Wakes the task that has waited the longest. Returns whether there was one.
+    pub fn wake_one(&self) -> bool {
+        let waiter = interrupt::without_interrupts(|| self.waiters.lock().pop_front());
+        if let Some(waiter) = waiter {
+            scheduler::wake(waiter);
+        }
+        waiter.is_some()
+    }
+
+    /// Wakes every waiting task. Returns how many there were.
+    pub fn wake_all(&self) -> usize {
+        let waiters = interrupt::without_interrupts(|| core::mem::take(&mut *self.waiters.lock()));
+        let count = waiters.len();
+        waiters.into_iter().for_each(scheduler::wake);
+        count
+    }
+}
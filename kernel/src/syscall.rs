@@ -1,50 +1,359 @@
+use alloc::string::String;
 use alloc::vec;
+use alloc::vec::Vec;
+use core::time::Duration;
 
-use kernel_api::syscall::EXIT;
-use kernel_api::syscall::PRINT_LINE;
-use kernel_api::syscall::SPAWN;
+use kernel_api::error::{self, Errno, SyscallResult};
+use kernel_api::syscall::{
+    StringRef, Timespec, ANY_CHILD, CLOCK_GETTIME, CLOCK_MONOTONIC, CLOCK_REALTIME, EXEC, EXIT,
+    FORK, FUTEX, FUTEX_WAIT, FUTEX_WAKE, MAX_ARGUMENTS, MMAP, MPROTECT, MUNMAP, NANOSLEEP,
+    PRINT_LINE, SPAWN, THREAD_CREATE, THREAD_EXIT, THREAD_JOIN, WAIT,
+};
 
+use crate::arch::Context;
 use crate::drivers::fs::path::Path;
 use crate::drivers::fs::BOOT_FS;
+use crate::memory::address::VirtualAddress;
 use crate::process::elf::ElfFile;
-use crate::{println, trace};
+use crate::process::loader::LoadError;
+use crate::process::task::TaskId;
+use crate::process::user::{UserPtr, UserSlice};
+use crate::process::{futex, loader, mmap, table, Pid};
+use crate::{println, time, trace};
 
-pub fn dispatcher(syscall_number: usize, arg1: usize, arg2: usize) {
-    match syscall_number {
+/// Runs the syscall and returns the value handed back to the caller in `rax`: the result, or the
+/// negated [`Errno`] if the syscall failed.
+/// `context` is the state of the calling task saved on syscall entry, which the syscall may
+/// change.
+pub fn dispatcher(syscall_number: usize, args: [usize; 6], context: &mut Context) -> usize {
+    let [arg1, arg2, arg3, arg4, arg5, arg6] = args;
+    let result = match syscall_number {
         SPAWN => spawn(arg1, arg2),
-        EXIT => exit(),
+        EXIT => exit(arg1),
+        WAIT => wait(arg1, arg2),
+        FORK => fork(context),
+        EXEC => exec(arg1, arg2, arg3, arg4, context),
+        THREAD_CREATE => thread_create(arg1, arg2),
+        THREAD_EXIT => thread_exit(arg1),
+        THREAD_JOIN => thread_join(arg1, arg2),
+        FUTEX => futex(arg1, arg2, arg3),
+        CLOCK_GETTIME => clock_gettime(arg1, arg2),
+        NANOSLEEP => nanosleep(arg1),
+        MMAP => mmap(arg1, arg2, arg3, arg4, arg5, arg6),
+        MUNMAP => munmap(arg1, arg2),
+        MPROTECT => mprotect(arg1, arg2, arg3),
         PRINT_LINE => println(arg1, arg2),
-        _ => println!("Unknown syscall: {}", syscall_number),
+        _ => {
+            println!("Unknown syscall: {}", syscall_number);
+            Err(Errno::NotImplemented)
+        }
+    };
+    error::encode(result)
+}
+
+/// Starts the program whose path is the first of the `p1` arguments at `p0`. Returns the pid of
+/// the new process.
+fn spawn(p0: usize, p1: usize) -> SyscallResult {
+    let args = read_arguments(p0, p1)?;
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    trace!("Spawning process: {}", args[0]);
+
+    let buffer = read_program(args[0])?;
+    let elf = ElfFile::parse(&buffer).map_err(LoadError::from);
+    match elf.and_then(|elf| table::spawn(&elf, &args)) {
+        Ok(pid) => Ok(pid.as_usize()),
+        Err(error) => {
+            trace!("Can't start {}: {}", args[0], error);
+            Err(error.into())
+        }
     }
 }
 
-fn spawn(p0: usize, p1: usize) {
-    let path = {
-        let slice = unsafe { core::slice::from_raw_parts(p0 as *const u8, p1) };
-        core::str::from_utf8(slice)
+/// Replaces the program of the calling process with the one whose path is the first of the `p1`
+/// arguments at `p0`, started with the `p3` environment variables at `p2`. On success, the
+/// caller resumes at the entry point of the new program instead of returning.
+fn exec(p0: usize, p1: usize, p2: usize, p3: usize, context: &mut Context) -> SyscallResult {
+    let args = read_arguments(p0, p1)?;
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    if p3 > MAX_ARGUMENTS {
+        return Err(Errno::ArgumentsTooLong);
+    }
+    let env = read_strings(p2, p3)?;
+    let env: Vec<&str> = env.iter().map(String::as_str).collect();
+    trace!("Executing {}", args[0]);
+
+    // The current program keeps running if the new one can't be loaded
+    let buffer = read_program(args[0])?;
+    let elf = ElfFile::parse(&buffer).map_err(LoadError::from);
+    let program = elf
+        .and_then(|elf| loader::load(&elf, &args, &env))
+        .map_err(|error| {
+            trace!("Can't execute {}: {}", args[0], error);
+            Errno::from(error)
+        })?;
+
+    // Kernel tasks have no user mappings to replace
+    *context = table::exec(program).ok_or(Errno::InvalidArgument)?;
+    Ok(0)
+}
+
+/// Copies the argument list of a program, whose first entry is its path.
+fn read_arguments(ptr: usize, count: usize) -> Result<Vec<String>, Errno> {
+    if count == 0 {
+        return Err(Errno::InvalidArgument);
+    }
+    if count > MAX_ARGUMENTS {
+        return Err(Errno::ArgumentsTooLong);
     }
-    .unwrap();
-    trace!("Spawning process: {}", path);
+    read_strings(ptr, count)
+}
+
+/// Copies `count` strings from the [`StringRef`] array at `ptr`.
+fn read_strings(ptr: usize, count: usize) -> Result<Vec<String>, Errno> {
+    UserSlice::<StringRef>::new(ptr, count)?
+        .read()?
+        .iter()
+        .map(|s| UserSlice::<u8>::new(s.ptr, s.len)?.read_string())
+        .collect()
+}
 
+/// Reads the whole executable at `path` from the boot file system.
+fn read_program(path: &str) -> Result<Vec<u8>, Errno> {
     let fs_opt = BOOT_FS.read();
     let fs = fs_opt.as_ref().unwrap();
-    let node = fs.open(&Path::new(path).unwrap()).unwrap();
+    let path = Path::new(path).ok_or(Errno::InvalidArgument)?;
+    let node = fs.open(&path).ok_or(Errno::NoSuchFile)?;
 
     let mut buffer = vec![0; node.size as usize];
-    fs.read(&node, 0, &mut buffer).unwrap();
+    fs.read(&node, 0, &mut buffer).map_err(|_| Errno::Io)?;
+    Ok(buffer)
+}
+
+fn exit(p0: usize) -> ! {
+    let exit_code = p0 as i32;
+    trace!("Exiting process with code {}...", exit_code);
+    table::exit(exit_code);
+}
 
-    let elf = ElfFile::parse(&buffer);
-    crate::process::loader::spawn(&elf);
+fn wait(p0: usize, p1: usize) -> SyscallResult {
+    let child = match p0 {
+        ANY_CHILD => None,
+        pid => Some(Pid::new(pid)),
+    };
+
+    let (pid, exit_code) = table::wait(child).ok_or(Errno::NoChild)?;
+    UserPtr::<i32>::new(p1).write(exit_code)?;
+    Ok(pid.as_usize())
+}
+
+/// Creates a copy of the calling process. Returns the pid of the child, which gets 0 instead.
+fn fork(context: &Context) -> SyscallResult {
+    // Kernel tasks have no process to copy
+    let pid = table::fork(context).ok_or(Errno::InvalidArgument)?;
+    Ok(pid.as_usize())
+}
+
+/// Starts a thread of the calling process at `p0`, with `p1` as its argument. Returns its id.
+fn thread_create(p0: usize, p1: usize) -> SyscallResult {
+    let task = table::create_thread(VirtualAddress::new(p0 as u64), p1)?;
+    Ok(task.as_usize())
+}
+
+fn thread_exit(p0: usize) -> ! {
+    let exit_code = p0 as i32;
+    trace!("Exiting thread with code {}...", exit_code);
+    table::exit_thread(exit_code);
+}
+
+/// Waits for the thread `p0` of the calling process, and stores its exit code at `p1`.
+fn thread_join(p0: usize, p1: usize) -> SyscallResult {
+    let exit_code = table::join_thread(TaskId::new(p0))?;
+    UserPtr::<i32>::new(p1).write(exit_code)?;
+    Ok(0)
+}
+
+/// Runs the futex operation `p1` on the 32-bit word at `p0`. [`FUTEX_WAIT`] blocks if the word
+/// holds `p2`, and [`FUTEX_WAKE`] wakes up to `p2` waiters and returns how many it woke.
+fn futex(p0: usize, p1: usize, p2: usize) -> SyscallResult {
+    match p1 {
+        FUTEX_WAIT => futex::wait(p0, p2 as u32).map(|_| 0),
+        FUTEX_WAKE => futex::wake(p0, p2),
+        _ => Err(Errno::InvalidArgument),
+    }
+}
+
+/// Stores the time of clock `p0` at `p1`.
+fn clock_gettime(p0: usize, p1: usize) -> SyscallResult {
+    let time = match p0 {
+        CLOCK_REALTIME => time::realtime(),
+        CLOCK_MONOTONIC => time::now(),
+        _ => return Err(Errno::InvalidArgument),
+    };
+    UserPtr::<Timespec>::new(p1).write(Timespec::from(time))?;
+    Ok(0)
 }
 
-fn exit() {
-    trace!("Exiting process...");
+/// Blocks the calling thread for the duration at `p0`.
+fn nanosleep(p0: usize) -> SyscallResult {
+    let duration = Duration::try_from(UserPtr::<Timespec>::new(p0).read()?)?;
+    time::sleep(duration);
+    Ok(0)
 }
 
-fn println(p0: usize, p1: usize) {
-    let s = {
-        let slice = unsafe { core::slice::from_raw_parts(p0 as *const u8, p1) };
-        core::str::from_utf8(slice)
+/// Maps `p1` bytes with the access rights `p2` and the flags `p3`, at `p0` or wherever they fit.
+/// Unless the mapping is anonymous, `p4` points to the path of the mapped file, which is mapped
+/// from offset `p5`. Returns the address of the mapping.
+fn mmap(p0: usize, p1: usize, p2: usize, p3: usize, p4: usize, p5: usize) -> SyscallResult {
+    let path = match p4 {
+        0 => None,
+        ptr => {
+            let path = UserPtr::<StringRef>::new(ptr).read()?;
+            Some(UserSlice::<u8>::new(path.ptr, path.len)?.read_string()?)
+        }
     };
-    println!("{}", s.unwrap());
+    let addr = mmap::map(p0, p1, p2, p3, path.as_deref(), p5)?;
+    Ok(addr.as_u64() as usize)
+}
+
+/// Unmaps the `p1` bytes at `p0`.
+fn munmap(p0: usize, p1: usize) -> SyscallResult {
+    mmap::unmap(p0, p1)?;
+    Ok(0)
+}
+
+/// Gives the `p1` bytes at `p0` the access rights `p2`.
+fn mprotect(p0: usize, p1: usize, p2: usize) -> SyscallResult {
+    mmap::protect(p0, p1, p2)?;
+    Ok(0)
+}
+
+fn println(p0: usize, p1: usize) -> SyscallResult {
+    let s = UserSlice::<u8>::new(p0, p1)?.read_string()?;
+    println!("{}", s);
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicU32;
+
+    use kernel_api::make_syscall;
+    use kernel_api::sync::{Condvar, Mutex};
+    use kernel_api::syscall::{
+        clock_gettime, exec, futex_wait, futex_wake, mmap, mprotect, munmap, nanosleep, spawn,
+        thread_create, thread_join, MAP_ANONYMOUS, MAP_PRIVATE, PROT_READ,
+    };
+
+    use crate::process::kthread;
+
+    use super::*;
+
+    #[test_case]
+    fn test_spawn_errors() {
+        assert_eq!(spawn("/boot/missing", &[]), Err(Errno::NoSuchFile));
+        assert_eq!(spawn("/README.md", &[]), Err(Errno::ExecFormat));
+        assert_eq!(spawn("relative", &[]), Err(Errno::InvalidArgument));
+    }
+
+    #[test_case]
+    fn test_exec_errors() {
+        assert_eq!(exec("/boot/missing", &[], &[]), Errno::NoSuchFile);
+        assert_eq!(exec("/README.md", &[], &["KEY=value"]), Errno::ExecFormat);
+        // Kernel tasks have no program to replace
+        assert_eq!(exec("/boot/init", &[], &[]), Errno::InvalidArgument);
+    }
+
+    #[test_case]
+    fn test_thread_errors() {
+        extern "C" fn entry(_: usize) -> ! {
+            unreachable!()
+        }
+        // Threads belong to a process, kernel tasks use kthread instead
+        assert_eq!(thread_create(entry, 0), Err(Errno::InvalidArgument));
+        assert_eq!(thread_join(1), Err(Errno::InvalidArgument));
+    }
+
+    #[test_case]
+    fn test_mmap_errors() {
+        // Kernel tasks have no user memory to map
+        let flags = MAP_PRIVATE | MAP_ANONYMOUS;
+        assert_eq!(
+            mmap(0, 4096, PROT_READ, flags, None, 0),
+            Err(Errno::InvalidArgument)
+        );
+        let addr = 0x2000_0000_0000 as *mut u8;
+        assert_eq!(munmap(addr, 4096), Err(Errno::InvalidArgument));
+        assert_eq!(mprotect(addr, 4096, PROT_READ), Err(Errno::InvalidArgument));
+    }
+
+    #[test_case]
+    fn test_futex() {
+        let word = AtomicU32::new(1);
+        assert_eq!(futex_wait(&word, 0), Err(Errno::TryAgain));
+        assert_eq!(futex_wake(&word, 1), Ok(0));
+        let misaligned = unsafe { &*(word.as_ptr().byte_add(1) as *const AtomicU32) };
+        assert_eq!(futex_wake(misaligned, 1), Err(Errno::InvalidArgument));
+
+        // Userland locks, with kernel threads standing in for the threads of a process
+        let state = Arc::new((Mutex::new(0), Condvar::new()));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let state = state.clone();
+                kthread::spawn(move || {
+                    let (counter, condvar) = &*state;
+                    for _ in 0..10 {
+                        let mut value = counter.lock();
+                        let read = *value;
+                        kthread::yield_now();
+                        *value = read + 1;
+                    }
+                    condvar.notify_all();
+                })
+            })
+            .collect();
+
+        let (counter, condvar) = &*state;
+        let value = condvar.wait_while(counter.lock(), |value| *value < 40);
+        assert_eq!(*value, 40);
+        drop(value);
+        threads.into_iter().for_each(kthread::JoinHandle::join);
+    }
+
+    #[test_case]
+    fn test_clocks() {
+        let start = clock_gettime(CLOCK_MONOTONIC).unwrap();
+        nanosleep(Duration::from_millis(5)).unwrap();
+        assert!(clock_gettime(CLOCK_MONOTONIC).unwrap() - start >= Duration::from_millis(5));
+        assert!(clock_gettime(CLOCK_REALTIME).unwrap() >= start);
+        assert_eq!(clock_gettime(7), Err(Errno::InvalidArgument));
+
+        let invalid = Timespec {
+            seconds: 0,
+            nanoseconds: 1_000_000_000,
+        };
+        let result = unsafe { make_syscall!(NANOSLEEP, &invalid as *const Timespec) };
+        assert_eq!(error::decode(result), Err(Errno::InvalidArgument));
+    }
+
+    #[test_case]
+    fn test_unknown_syscall() {
+        let result = unsafe { make_syscall!(0x999) };
+        assert_eq!(error::decode(result), Err(Errno::NotImplemented));
+    }
+
+    #[test_case]
+    fn test_result_encoding() {
+        assert_eq!(error::decode(error::encode(Ok(42))), Ok(42));
+        assert_eq!(
+            error::decode(error::encode(Err(Errno::NoChild))),
+            Err(Errno::NoChild)
+        );
+        assert_eq!(error::decode(usize::MAX - 0x2000), Ok(usize::MAX - 0x2000));
+        // Codes without a variant are still errors
+        assert_eq!(
+            error::decode(1000usize.wrapping_neg()),
+            Err(Errno::Unknown(1000))
+        );
+    }
 }
@@ -0,0 +1,147 @@
+use core::fmt;
+use core::time::Duration;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// A date and time of the day in UTC, as calendars and the CMOS clock count it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct DateTime {
+    pub year: u16,
+    /// 1 to 12.
+    pub month: u8,
+    /// 1 to 31.
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// The date at `time` since the Unix epoch.
+    pub fn from_unix(time: Duration) -> Self {
+        let seconds = time.as_secs();
+        let (year, month, day) = civil_from_days(seconds / SECONDS_PER_DAY);
+        let seconds_of_day = seconds % SECONDS_PER_DAY;
+        Self {
+            year,
+            month,
+            day,
+            hour: (seconds_of_day / 3600) as u8,
+            minute: (seconds_of_day / 60 % 60) as u8,
+            second: (seconds_of_day % 60) as u8,
+        }
+    }
+
+    /// Time since the Unix epoch. Dates before it saturate to 0.
+    pub fn to_unix(&self) -> Duration {
+        let days = days_from_civil(self.year, self.month, self.day);
+        let seconds = self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64;
+        Duration::from_secs((days * SECONDS_PER_DAY as i64 + seconds as i64).max(0) as u64)
+    }
+
+    /// Reads the date and time fields of a FAT directory entry. Returns `None` if they're not
+    /// set.
+    pub fn from_fat(date: u16, time: u16) -> Option<Self> {
+        let date_time = Self {
+            year: 1980 + (date >> 9),
+            month: (date >> 5 & 0xF) as u8,
+            day: (date & 0x1F) as u8,
+            hour: (time >> 11) as u8,
+            minute: (time >> 5 & 0x3F) as u8,
+            second: (time & 0x1F) as u8 * 2,
+        };
+        (date_time.month != 0 && date_time.day != 0).then_some(date_time)
+    }
+
+    /// The date and time fields of a FAT directory entry. FAT counts from 1980 with a
+    /// resolution of 2 seconds.
+    pub fn to_fat(&self) -> (u16, u16) {
+        let date =
+            (self.year.saturating_sub(1980) << 9) | (self.month as u16) << 5 | self.day as u16;
+        let time = (self.hour as u16) << 11 | (self.minute as u16) << 5 | self.second as u16 / 2;
+        (date, time)
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Days since 1970-01-01 of a date of the proleptic Gregorian calendar. Years start in March
+/// here, so the leap day is the last day of the year.
+fn days_from_civil(year: u16, month: u8, day: u8) -> i64 {
+    let year = year as i64 - (month <= 2) as i64;
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_from_march = (month as i64 + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The inverse of [`days_from_civil`].
+fn civil_from_days(days: u64) -> (u16, u8, u8) {
+    let days = days as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year as u16, month as u8, day as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+
+    #[test_case]
+    fn test_unix_time() {
+        let leap_day = DateTime {
+            year: 2024,
+            month: 2,
+            day: 29,
+            hour: 12,
+            minute: 34,
+            second: 56,
+        };
+        assert_eq!(leap_day.to_unix(), Duration::from_secs(1_709_210_096));
+        assert_eq!(
+            DateTime::from_unix(Duration::from_secs(1_709_210_096)),
+            leap_day
+        );
+
+        let epoch = DateTime::from_unix(Duration::ZERO);
+        assert_eq!((epoch.year, epoch.month, epoch.day), (1970, 1, 1));
+        let millennium = DateTime::from_unix(Duration::from_secs(946_684_799));
+        assert_eq!(millennium.to_string(), "1999-12-31 23:59:59");
+        assert_eq!(
+            DateTime::from_unix(Duration::from_secs(951_868_800)).month,
+            3
+        );
+    }
+
+    #[test_case]
+    fn test_fat_timestamps() {
+        let date_time = DateTime::from_unix(Duration::from_secs(1_709_210_096));
+        let (date, time) = date_time.to_fat();
+        assert_eq!(date, (44 << 9) | (2 << 5) | 29);
+        assert_eq!(DateTime::from_fat(date, time), Some(date_time));
+        assert_eq!(DateTime::from_fat(0, 0), None);
+    }
+}
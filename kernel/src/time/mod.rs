@@ -0,0 +1,153 @@
+//! Kernel time since boot, counted in timer ticks until a finer clock source is registered.
+
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use spin::{Mutex, Once};
+
+use crate::arch::interrupt;
+use crate::process::scheduler;
+use crate::process::task::TaskId;
+
+pub(crate) mod date;
+
+/// How many times per second the timer interrupt fires.
+pub(crate) const TIMER_FREQUENCY: u64 = 1000;
+/// The period of the timer interrupt, which is also the resolution of the clocks and timers.
+pub(crate) const TICK: Duration = Duration::from_nanos(1_000_000_000 / TIMER_FREQUENCY);
+
+/// Nanoseconds since boot.
+static MONOTONIC: AtomicU64 = AtomicU64::new(0);
+/// The wall-clock time at boot, in nanoseconds since the Unix epoch. It stays 0 until a
+/// real-time clock sets it.
+static BOOT_REALTIME: AtomicU64 = AtomicU64::new(0);
+
+/// The tasks sleeping in [`sleep_until`], by deadline.
+static TIMERS: Mutex<BTreeSet<(Duration, TaskId)>> = Mutex::new(BTreeSet::new());
+
+/// The clock source, and the time since boot when its counter was 0.
+static CLOCK_SOURCE: Once<(ClockSource, Duration)> = Once::new();
+
+/// A counter with a finer resolution than the tick, such as the HPET.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ClockSource {
+    /// Reads the time since the counter was 0.
+    pub read: fn() -> Duration,
+    /// Requests an interrupt when the counter reaches the given time, which calls
+    /// [`expire_timers`]. `None` if the device has no timers, and sleeps end on a tick.
+    pub arm: Option<fn(Duration)>,
+}
+
+/// Makes the clocks read `source` instead of counting ticks. The time keeps going from where the
+/// ticks left it.
+pub(crate) fn set_clock_source(source: ClockSource) {
+    let ticks = Duration::from_nanos(MONOTONIC.load(Ordering::Relaxed));
+    CLOCK_SOURCE.call_once(|| (source, ticks.saturating_sub((source.read)())));
+}
+
+/// Time since boot. It never goes backwards.
+pub(crate) fn now() -> Duration {
+    match CLOCK_SOURCE.get() {
+        Some((source, offset)) => (source.read)() + *offset,
+        None => Duration::from_nanos(MONOTONIC.load(Ordering::Relaxed)),
+    }
+}
+
+/// Time since the Unix epoch.
+pub(crate) fn realtime() -> Duration {
+    Duration::from_nanos(BOOT_REALTIME.load(Ordering::Relaxed)) + now()
+}
+
+/// Sets the wall-clock time to `time` since the Unix epoch. The monotonic clock isn't affected.
+pub(crate) fn set_realtime(time: Duration) {
+    let boot = time.saturating_sub(now());
+    BOOT_REALTIME.store(boot.as_nanos() as u64, Ordering::Relaxed);
+}
+
+/// Advances the clocks by one tick, and wakes the tasks whose deadline passed. Called by the
+/// timer interrupt.
+pub(crate) fn tick() {
+    MONOTONIC.fetch_add(TICK.as_nanos() as u64, Ordering::Relaxed);
+    expire_timers();
+}
+
+/// Wakes the tasks whose deadline passed, and arms the clock source for the next one. Called from
+/// interrupt handlers.
+pub(crate) fn expire_timers() {
+    let now = now();
+    let (expired, next): (Vec<TaskId>, _) = {
+        let mut timers = TIMERS.lock();
+        let mut expired = Vec::new();
+        while let Some(&(deadline, task)) = timers.first() {
+            if deadline > now {
+                break;
+            }
+            timers.pop_first();
+            expired.push(task);
+        }
+        (expired, timers.first().map(|(deadline, _)| *deadline))
+    };
+    if let Some(deadline) = next {
+        arm(deadline);
+    }
+    expired.into_iter().for_each(scheduler::wake);
+}
+
+/// Requests an interrupt from the clock source at `deadline`, if it has timers.
+fn arm(deadline: Duration) {
+    if let Some((ClockSource { arm: Some(arm), .. }, offset)) = CLOCK_SOURCE.get() {
+        arm(deadline.saturating_sub(*offset));
+    }
+}
+
+/// Blocks the current task until [`now`] reaches `deadline`, or until it's asked to exit.
+pub(crate) fn sleep_until(deadline: Duration) {
+    loop {
+        let done = interrupt::without_interrupts(|| {
+            if now() >= deadline {
+                return true;
+            }
+            let current = scheduler::current();
+            let earliest = {
+                let mut timers = TIMERS.lock();
+                timers.insert((deadline, current));
+                timers.first() == Some(&(deadline, current))
+            };
+            if earliest {
+                arm(deadline);
+            }
+            scheduler::block_current_interruptible();
+            if scheduler::exit_requested() {
+                // The timer didn't expire, so it's still queued
+                TIMERS.lock().remove(&(deadline, current));
+                return true;
+            }
+            false
+        });
+        if done {
+            return;
+        }
+    }
+}
+
+/// Blocks the current task for at least `duration`, unless it's asked to exit.
+pub(crate) fn sleep(duration: Duration) {
+    sleep_until(now() + duration);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_sleep() {
+        let start = now();
+        sleep(Duration::from_millis(20));
+        assert!(now() - start >= Duration::from_millis(20));
+
+        sleep_until(start);
+        assert!(realtime() >= now());
+    }
+}
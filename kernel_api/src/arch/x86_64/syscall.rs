@@ -1,34 +1,114 @@
 use core::arch::asm;
 
+/// Whether the code runs in ring 3. `syscall` only works from there, so code running in ring 0,
+/// like the kernel tests, makes syscalls with `int 0x80` instead.
+#[inline(always)]
+fn in_user_mode() -> bool {
+    let cs: u16;
+    unsafe {
+        asm!("mov {0:x}, cs", out(reg) cs, options(nomem, nostack, preserves_flags));
+    }
+    cs & 3 == 3
+}
+
+macro_rules! syscall {
+    ($($reg:tt = $arg:expr),*) => {{
+        let res: usize;
+        if in_user_mode() {
+            asm!(
+            "syscall",
+            $(in($reg) $arg,)*
+            lateout("rax") res, lateout("rcx") _, lateout("r11") _
+            );
+        } else {
+            asm!(
+            "int 0x80",
+            $(in($reg) $arg,)*
+            lateout("rax") res
+            );
+        }
+        res
+    }};
+}
+
 #[inline(always)]
 pub unsafe fn syscall0(syscall_number: usize) -> usize {
-    let res: usize;
-    asm!(
-    "int 0x80", in("rax") syscall_number,
-    lateout("rax") res
-    );
-    res
+    syscall!("rax" = syscall_number)
 }
 
 #[inline(always)]
 pub unsafe fn syscall1(syscall_number: usize, arg1: usize) -> usize {
-    let res: usize;
-    asm!(
-    "int 0x80",
-    in("rax") syscall_number,
-    in("rdi") arg1,
-    lateout("rax") res
-    );
-    res
+    syscall!("rax" = syscall_number, "rdi" = arg1)
 }
 
 #[inline(always)]
 pub unsafe fn syscall2(syscall_number: usize, arg1: usize, arg2: usize) -> usize {
-    let res: usize;
-    asm!(
-    "int 0x80", in("rax") syscall_number,
-    in("rdi") arg1, in("rsi") arg2,
-    lateout("rax") res
-    );
-    res
+    syscall!("rax" = syscall_number, "rdi" = arg1, "rsi" = arg2)
+}
+
+#[inline(always)]
+pub unsafe fn syscall3(syscall_number: usize, arg1: usize, arg2: usize, arg3: usize) -> usize {
+    syscall!(
+        "rax" = syscall_number,
+        "rdi" = arg1,
+        "rsi" = arg2,
+        "rdx" = arg3
+    )
+}
+
+#[inline(always)]
+pub unsafe fn syscall4(
+    syscall_number: usize,
+    arg1: usize,
+    arg2: usize,
+    arg3: usize,
+    arg4: usize,
+) -> usize {
+    syscall!(
+        "rax" = syscall_number,
+        "rdi" = arg1,
+        "rsi" = arg2,
+        "rdx" = arg3,
+        "r10" = arg4
+    )
+}
+
+#[inline(always)]
+pub unsafe fn syscall5(
+    syscall_number: usize,
+    arg1: usize,
+    arg2: usize,
+    arg3: usize,
+    arg4: usize,
+    arg5: usize,
+) -> usize {
+    syscall!(
+        "rax" = syscall_number,
+        "rdi" = arg1,
+        "rsi" = arg2,
+        "rdx" = arg3,
+        "r10" = arg4,
+        "r8" = arg5
+    )
+}
+
+#[inline(always)]
+pub unsafe fn syscall6(
+    syscall_number: usize,
+    arg1: usize,
+    arg2: usize,
+    arg3: usize,
+    arg4: usize,
+    arg5: usize,
+    arg6: usize,
+) -> usize {
+    syscall!(
+        "rax" = syscall_number,
+        "rdi" = arg1,
+        "rsi" = arg2,
+        "rdx" = arg3,
+        "r10" = arg4,
+        "r8" = arg5,
+        "r9" = arg6
+    )
 }
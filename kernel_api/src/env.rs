@@ -0,0 +1,100 @@
+//! The arguments and environment a process was started with, read from its initial stack.
+
+use core::ffi::{c_char, CStr};
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+/// Ends the auxiliary vector.
+pub const AT_NULL: usize = 0;
+/// The address of the program headers of the executable.
+pub const AT_PHDR: usize = 3;
+/// The size of a program header entry.
+pub const AT_PHENT: usize = 4;
+/// The number of program headers.
+pub const AT_PHNUM: usize = 5;
+pub const AT_PAGESZ: usize = 6;
+/// The entry point of the executable.
+pub const AT_ENTRY: usize = 9;
+
+static ARGC: AtomicUsize = AtomicUsize::new(0);
+static ARGV: AtomicPtr<*const c_char> = AtomicPtr::new(core::ptr::null_mut());
+static ENVP: AtomicPtr<*const c_char> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Defines `_start`, which reads the initial stack and calls `$main`. The process exits with code
+/// 0 when `$main` returns.
+#[macro_export]
+macro_rules! entry_point {
+    ($main:path) => {
+        core::arch::global_asm!(
+            ".globl _start",
+            "_start:",
+            "mov rdi, rsp",
+            "call {}",
+            sym __kernel_api_start,
+        );
+
+        extern "C" fn __kernel_api_start(stack: *const usize) -> ! {
+            unsafe { $crate::env::init(stack) };
+            $main();
+            $crate::syscall::exit(0)
+        }
+    };
+}
+
+/// Records where `argv` and `envp` are, from the stack pointer the process started with.
+///
+/// # Safety
+/// `stack` must point to an initial stack laid out as the System V ABI describes it.
+#[doc(hidden)]
+pub unsafe fn init(stack: *const usize) {
+    let argc = *stack;
+    let argv = stack.add(1) as *mut *const c_char;
+    ARGC.store(argc, Ordering::Relaxed);
+    ARGV.store(argv, Ordering::Relaxed);
+    ENVP.store(argv.add(argc + 1), Ordering::Relaxed);
+}
+
+/// The arguments of the process. The first one is the path of the program.
+pub fn args() -> Strings {
+    Strings {
+        next: ARGV.load(Ordering::Relaxed),
+    }
+}
+
+/// The environment of the process, as `KEY=VALUE` strings.
+pub fn vars() -> Strings {
+    Strings {
+        next: ENVP.load(Ordering::Relaxed),
+    }
+}
+
+/// The value of the environment variable `key`.
+pub fn var(key: &str) -> Option<&'static str> {
+    vars().find_map(|var| var.strip_prefix(key)?.strip_prefix('='))
+}
+
+/// Iterates over a null terminated array of C strings. Strings that aren't valid UTF-8 are
+/// skipped.
+pub struct Strings {
+    next: *const *const c_char,
+}
+
+impl Iterator for Strings {
+    type Item = &'static str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.next.is_null() {
+                return None;
+            }
+            let string = unsafe { *self.next };
+            if string.is_null() {
+                return None;
+            }
+            self.next = unsafe { self.next.add(1) };
+
+            if let Ok(string) = unsafe { CStr::from_ptr(string) }.to_str() {
+                return Some(string);
+            }
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use core::fmt;
+
+/// The largest error code. A syscall that fails returns the negated code in `rax`, so results in
+/// `[-MAX_ERRNO, -1]` are errors and everything else is a value.
+pub const MAX_ERRNO: usize = 4095;
+
+/// Errors returned by syscalls. The codes are the ones POSIX systems use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Errno {
+    /// `ENOENT`: the file doesn't exist.
+    NoSuchFile,
+    /// `ESRCH`: the process or thread doesn't exist.
+    NoSuchProcess,
+    /// `EIO`: the device failed.
+    Io,
+    /// `E2BIG`: the argument list is too long.
+    ArgumentsTooLong,
+    /// `ENOEXEC`: the file isn't a valid executable.
+    ExecFormat,
+    /// `ECHILD`: the process has no such child.
+    NoChild,
+    /// `EAGAIN`: a resource is exhausted for now, e.g. the process can't have more threads.
+    TryAgain,
+    /// `ENOMEM`: there's no room for the mapping, or the range isn't mapped.
+    NoMemory,
+    /// `EFAULT`: a pointer argument is outside the memory of the process.
+    Fault,
+    /// `EINVAL`: an argument is invalid.
+    InvalidArgument,
+    /// `ENOSYS`: the syscall doesn't exist.
+    NotImplemented,
+    /// A code in the error range that none of the others have.
+    Unknown(usize),
+}
+
+impl Errno {
+    pub fn from_code(code: usize) -> Self {
+        match code {
+            2 => Self::NoSuchFile,
+            3 => Self::NoSuchProcess,
+            5 => Self::Io,
+            7 => Self::ArgumentsTooLong,
+            8 => Self::ExecFormat,
+            10 => Self::NoChild,
+            11 => Self::TryAgain,
+            12 => Self::NoMemory,
+            14 => Self::Fault,
+            22 => Self::InvalidArgument,
+            38 => Self::NotImplemented,
+            code => Self::Unknown(code),
+        }
+    }
+
+    pub fn code(self) -> usize {
+        match self {
+            Self::NoSuchFile => 2,
+            Self::NoSuchProcess => 3,
+            Self::Io => 5,
+            Self::ArgumentsTooLong => 7,
+            Self::ExecFormat => 8,
+            Self::NoChild => 10,
+            Self::TryAgain => 11,
+            Self::NoMemory => 12,
+            Self::Fault => 14,
+            Self::InvalidArgument => 22,
+            Self::NotImplemented => 38,
+            Self::Unknown(code) => code,
+        }
+    }
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+pub type SyscallResult = Result<usize, Errno>;
+
+/// Turns a syscall result into the value returned in `rax`.
+pub fn encode(result: SyscallResult) -> usize {
+    match result {
+        Ok(value) => value,
+        Err(errno) => errno.code().wrapping_neg(),
+    }
+}
+
+/// Turns the value a syscall returned in `rax` back into a result.
+pub fn decode(value: usize) -> SyscallResult {
+    let code = value.wrapping_neg();
+    match code {
+        1..=MAX_ERRNO => Err(Errno::from_code(code)),
+        _ => Ok(value),
+    }
+}
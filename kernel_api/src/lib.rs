@@ -1,4 +1,7 @@
 #![no_std]
 
 pub mod arch;
+pub mod env;
+pub mod error;
+pub mod sync;
 pub mod syscall;
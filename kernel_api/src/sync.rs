@@ -0,0 +1,140 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::syscall::{futex_wait, futex_wake};
+
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+/// Locked, and other threads may be blocked waiting for it.
+const CONTENDED: u32 = 2;
+
+/// A mutual exclusion lock for the threads of a process. Taking a free lock doesn't enter the
+/// kernel, and waiting threads sleep on a futex.
+pub struct Mutex<T: ?Sized> {
+    state: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            state: AtomicU32::new(UNLOCKED),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Blocks until the lock is free, and takes it.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        if self
+            .state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            // The lock can't tell whether other threads still wait, so it stays contended and
+            // the unlock wakes one
+            while self.state.swap(CONTENDED, Ordering::Acquire) != UNLOCKED {
+                let _ = futex_wait(&self.state, CONTENDED);
+            }
+        }
+        MutexGuard { mutex: self }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| MutexGuard { mutex: self })
+    }
+
+    fn unlock(&self) {
+        if self.state.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+            let _ = futex_wake(&self.state, 1);
+        }
+    }
+}
+
+/// Gives access to the data of a [`Mutex`], and releases it when dropped.
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+/// Lets threads sleep until a condition on the data of a [`Mutex`] holds.
+pub struct Condvar {
+    /// Changed by every notification, so a waiter can tell whether one happened since it
+    /// released the lock.
+    sequence: AtomicU32,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            sequence: AtomicU32::new(0),
+        }
+    }
+
+    /// Releases the lock and blocks until the condition variable is notified, then takes the
+    /// lock again. The thread may also wake up spuriously, so callers check their condition
+    /// again, or use [`Condvar::wait_while`].
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        let mutex = guard.mutex;
+        drop(guard);
+        // Returns right away if a notification came after the unlock
+        let _ = futex_wait(&self.sequence, sequence);
+        mutex.lock()
+    }
+
+    /// Waits as long as `condition` holds for the data.
+    pub fn wait_while<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_one(&self) {
+        self.sequence.fetch_add(1, Ordering::Release);
+        let _ = futex_wake(&self.sequence, 1);
+    }
+
+    pub fn notify_all(&self) {
+        self.sequence.fetch_add(1, Ordering::Release);
+        let _ = futex_wake(&self.sequence, usize::MAX);
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
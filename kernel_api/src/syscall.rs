@@ -1,8 +1,110 @@
+use core::sync::atomic::AtomicU32;
+use core::time::Duration;
+
+use crate::error::{decode, Errno};
+
 pub const SPAWN: usize = 0x1;
 pub const EXIT: usize = 0x2;
+pub const WAIT: usize = 0x3;
+pub const FORK: usize = 0x4;
+pub const EXEC: usize = 0x5;
+pub const THREAD_CREATE: usize = 0x6;
+pub const THREAD_EXIT: usize = 0x7;
+pub const THREAD_JOIN: usize = 0x8;
+pub const FUTEX: usize = 0x9;
+pub const CLOCK_GETTIME: usize = 0xA;
+pub const NANOSLEEP: usize = 0xB;
+pub const MMAP: usize = 0xC;
+pub const MUNMAP: usize = 0xD;
+pub const MPROTECT: usize = 0xE;
 // TODO: Remove this. This syscall is for testing purposes only.
 pub const PRINT_LINE: usize = 0x404;
 
+pub type Pid = usize;
+pub type Tid = usize;
+
+/// Makes [`wait`] return the first child that exits.
+pub const ANY_CHILD: Pid = 0;
+
+/// The exit code of a process killed for an invalid memory access.
+pub const EXIT_SEGFAULT: i32 = 139;
+
+/// The operations of the [`FUTEX`] syscall.
+pub const FUTEX_WAIT: usize = 0;
+pub const FUTEX_WAKE: usize = 1;
+
+/// The clocks of [`clock_gettime`]. The real-time clock follows the wall-clock time, and may
+/// jump when it's set. The monotonic one counts from boot and never goes backwards.
+pub const CLOCK_REALTIME: usize = 0;
+pub const CLOCK_MONOTONIC: usize = 1;
+
+/// The access rights of memory mapped by [`mmap`], or changed by [`mprotect`]. [`PROT_NONE`]
+/// memory can't be accessed at all. Writable or executable memory is readable too.
+pub const PROT_NONE: usize = 0;
+pub const PROT_READ: usize = 1 << 0;
+pub const PROT_WRITE: usize = 1 << 1;
+pub const PROT_EXEC: usize = 1 << 2;
+
+/// The flags of [`mmap`]. Exactly one of [`MAP_SHARED`] and [`MAP_PRIVATE`] is needed: changes
+/// to shared memory are seen by every process mapping it, including forks, while private memory
+/// is copied on write. [`MAP_FIXED`] maps at the given address exactly, replacing what was there,
+/// instead of taking it as a hint. [`MAP_ANONYMOUS`] memory is zeroed instead of coming from a
+/// file.
+pub const MAP_SHARED: usize = 1 << 0;
+pub const MAP_PRIVATE: usize = 1 << 1;
+pub const MAP_FIXED: usize = 1 << 4;
+pub const MAP_ANONYMOUS: usize = 1 << 5;
+
+/// The most arguments a program can be started with, including its path. It's also the most
+/// environment variables [`exec`] takes.
+pub const MAX_ARGUMENTS: usize = 32;
+
+/// A string handed to the kernel, as its address and length.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StringRef {
+    pub ptr: usize,
+    pub len: usize,
+}
+
+impl StringRef {
+    pub fn new(s: &str) -> Self {
+        Self {
+            ptr: s.as_ptr() as usize,
+            len: s.len(),
+        }
+    }
+}
+
+/// A point in time or a duration handed to the kernel.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timespec {
+    pub seconds: u64,
+    /// Always below a second.
+    pub nanoseconds: u64,
+}
+
+impl From<Duration> for Timespec {
+    fn from(duration: Duration) -> Self {
+        Self {
+            seconds: duration.as_secs(),
+            nanoseconds: duration.subsec_nanos() as u64,
+        }
+    }
+}
+
+impl TryFrom<Timespec> for Duration {
+    type Error = Errno;
+
+    fn try_from(time: Timespec) -> Result<Self, Errno> {
+        if time.nanoseconds >= 1_000_000_000 {
+            return Err(Errno::InvalidArgument);
+        }
+        Ok(Duration::new(time.seconds, time.nanoseconds as u32))
+    }
+}
+
 #[macro_export]
 macro_rules! make_syscall {
     ($n:expr) => {
@@ -14,21 +116,210 @@ macro_rules! make_syscall {
     ($n:expr, $a1:expr, $a2:expr) => {
         $crate::arch::syscall::syscall2($n as usize, $a1 as usize, $a2 as usize)
     };
+    ($n:expr, $a1:expr, $a2:expr, $a3:expr) => {
+        $crate::arch::syscall::syscall3($n as usize, $a1 as usize, $a2 as usize, $a3 as usize)
+    };
+    ($n:expr, $a1:expr, $a2:expr, $a3:expr, $a4:expr) => {
+        $crate::arch::syscall::syscall4(
+            $n as usize,
+            $a1 as usize,
+            $a2 as usize,
+            $a3 as usize,
+            $a4 as usize,
+        )
+    };
+    ($n:expr, $a1:expr, $a2:expr, $a3:expr, $a4:expr, $a5:expr) => {
+        $crate::arch::syscall::syscall5(
+            $n as usize,
+            $a1 as usize,
+            $a2 as usize,
+            $a3 as usize,
+            $a4 as usize,
+            $a5 as usize,
+        )
+    };
+    ($n:expr, $a1:expr, $a2:expr, $a3:expr, $a4:expr, $a5:expr, $a6:expr) => {
+        $crate::arch::syscall::syscall6(
+            $n as usize,
+            $a1 as usize,
+            $a2 as usize,
+            $a3 as usize,
+            $a4 as usize,
+            $a5 as usize,
+            $a6 as usize,
+        )
+    };
 }
 
+/// Starts the program at `path` as a child of the current process and returns its pid. The
+/// program gets `path` followed by `args` as its arguments.
 #[inline(always)]
-pub fn spawn(path: &str) {
+pub fn spawn(path: &str, args: &[&str]) -> Result<Pid, Errno> {
+    let argv = argument_refs(path, args)?;
+    decode(unsafe { make_syscall!(SPAWN, argv.as_ptr(), args.len() + 1) })
+}
+
+/// Replaces the program of the current process with the one at `path`, which gets `path`
+/// followed by `args` as its arguments, and `env` as its environment (`KEY=value` strings). The
+/// pid stays the same. Only returns if the program can't be started.
+#[inline(always)]
+pub fn exec(path: &str, args: &[&str], env: &[&str]) -> Errno {
+    let argv = match argument_refs(path, args) {
+        Ok(argv) => argv,
+        Err(errno) => return errno,
+    };
+    if env.len() > MAX_ARGUMENTS {
+        return Errno::ArgumentsTooLong;
+    }
+    let mut envp = [StringRef::default(); MAX_ARGUMENTS];
+    for (var, s) in envp.iter_mut().zip(env) {
+        *var = StringRef::new(s);
+    }
+
+    let result = unsafe {
+        make_syscall!(
+            EXEC,
+            argv.as_ptr(),
+            args.len() + 1,
+            envp.as_ptr(),
+            env.len()
+        )
+    };
+    match decode(result) {
+        Err(errno) => errno,
+        Ok(_) => unreachable!("exec returned without an error"),
+    }
+}
+
+fn argument_refs(path: &str, args: &[&str]) -> Result<[StringRef; MAX_ARGUMENTS], Errno> {
+    if args.len() >= MAX_ARGUMENTS {
+        return Err(Errno::ArgumentsTooLong);
+    }
+    let mut argv = [StringRef::default(); MAX_ARGUMENTS];
+    for (arg, s) in argv.iter_mut().zip(core::iter::once(&path).chain(args)) {
+        *arg = StringRef::new(s);
+    }
+    Ok(argv)
+}
+
+#[inline(always)]
+pub fn exit(exit_code: i32) -> ! {
     unsafe {
-        make_syscall!(SPAWN, path.as_ptr() as usize, path.len());
+        make_syscall!(EXIT, exit_code);
     }
+    unreachable!("The process should have exited");
 }
 
+/// Blocks until the child `pid` exits, or any child with [`ANY_CHILD`]. Returns the pid and the
+/// exit code of the child, or [`Errno::NoChild`] if there's no child to wait for.
 #[inline(always)]
-pub fn exit() {
-    // TODO: add enum for exit code
+pub fn wait(pid: Pid) -> Result<(Pid, i32), Errno> {
+    let mut exit_code = 0i32;
+    let child = decode(unsafe { make_syscall!(WAIT, pid, &mut exit_code as *mut i32) })?;
+    Ok((child, exit_code))
+}
+
+/// Creates a copy of the current process. Returns the pid of the child in the parent, and 0 in
+/// the child. Memory is shared copy-on-write, so the copy is cheap until one of them writes.
+#[inline(always)]
+pub fn fork() -> Result<Pid, Errno> {
+    decode(unsafe { make_syscall!(FORK) })
+}
+
+/// Starts a thread of the current process that runs `entry(arg)` on its own stack, with its own
+/// copy of the thread-local variables. `entry` must end with [`thread_exit`]. Returns the id of
+/// the thread.
+#[inline(always)]
+pub fn thread_create(entry: extern "C" fn(usize) -> !, arg: usize) -> Result<Tid, Errno> {
+    decode(unsafe { make_syscall!(THREAD_CREATE, entry as usize, arg) })
+}
+
+/// Terminates the current thread. The process exits with `exit_code` if it was its last thread.
+#[inline(always)]
+pub fn thread_exit(exit_code: i32) -> ! {
     unsafe {
-        make_syscall!(EXIT);
+        make_syscall!(THREAD_EXIT, exit_code);
     }
+    unreachable!("The thread should have exited");
+}
+
+/// Blocks until the thread `tid` of the current process exits, and returns its exit code. A
+/// thread can only be joined once.
+#[inline(always)]
+pub fn thread_join(tid: Tid) -> Result<i32, Errno> {
+    let mut exit_code = 0i32;
+    decode(unsafe { make_syscall!(THREAD_JOIN, tid, &mut exit_code as *mut i32) })?;
+    Ok(exit_code)
+}
+
+/// Blocks until [`futex_wake`] is called on `word`, unless it doesn't hold `expected` anymore,
+/// which fails with [`Errno::TryAgain`]. It may also return without a wake-up, so callers check
+/// the word again.
+#[inline(always)]
+pub fn futex_wait(word: &AtomicU32, expected: u32) -> Result<(), Errno> {
+    decode(unsafe { make_syscall!(FUTEX, word.as_ptr(), FUTEX_WAIT, expected) })?;
+    Ok(())
+}
+
+/// Wakes up to `count` threads blocked in [`futex_wait`] on `word`, and returns how many were.
+/// Processes sharing the memory of `word` share its waiters too.
+#[inline(always)]
+pub fn futex_wake(word: &AtomicU32, count: usize) -> Result<usize, Errno> {
+    decode(unsafe { make_syscall!(FUTEX, word.as_ptr(), FUTEX_WAKE, count) })
+}
+
+/// Reads `clock`, [`CLOCK_REALTIME`] or [`CLOCK_MONOTONIC`]. The real-time clock counts from the
+/// Unix epoch.
+#[inline(always)]
+pub fn clock_gettime(clock: usize) -> Result<Duration, Errno> {
+    let mut time = Timespec::default();
+    decode(unsafe { make_syscall!(CLOCK_GETTIME, clock, &mut time as *mut Timespec) })?;
+    Duration::try_from(time)
+}
+
+/// Blocks the current thread for at least `duration`.
+#[inline(always)]
+pub fn nanosleep(duration: Duration) -> Result<(), Errno> {
+    let time = Timespec::from(duration);
+    decode(unsafe { make_syscall!(NANOSLEEP, &time as *const Timespec) })?;
+    Ok(())
+}
+
+/// Maps `len` bytes of memory with the `prot` access rights, and returns their address. The
+/// memory holds the file at `path` from `offset` on, or zeros with [`MAP_ANONYMOUS`]. `addr` is a
+/// hint, unless `flags` has [`MAP_FIXED`]. Pages are only allocated when they're first accessed.
+/// The file system is read-only, so changes to a shared file mapping are never written back.
+#[inline(always)]
+pub fn mmap(
+    addr: usize,
+    len: usize,
+    prot: usize,
+    flags: usize,
+    path: Option<&str>,
+    offset: usize,
+) -> Result<*mut u8, Errno> {
+    let file = path.map(StringRef::new);
+    let file_ptr = file
+        .as_ref()
+        .map_or(0, |file| file as *const StringRef as usize);
+    let addr = decode(unsafe { make_syscall!(MMAP, addr, len, prot, flags, file_ptr, offset) })?;
+    Ok(addr as *mut u8)
+}
+
+/// Unmaps the pages of the `len` bytes at `addr`. Parts of the range that aren't mapped are
+/// skipped.
+#[inline(always)]
+pub fn munmap(addr: *mut u8, len: usize) -> Result<(), Errno> {
+    decode(unsafe { make_syscall!(MUNMAP, addr, len) })?;
+    Ok(())
+}
+
+/// Changes the access rights of the pages of the `len` bytes at `addr` to `prot`. Fails with
+/// [`Errno::NoMemory`] if part of the range isn't mapped.
+#[inline(always)]
+pub fn mprotect(addr: *mut u8, len: usize, prot: usize) -> Result<(), Errno> {
+    decode(unsafe { make_syscall!(MPROTECT, addr, len, prot) })?;
+    Ok(())
 }
 
 #[inline(always)]
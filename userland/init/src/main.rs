@@ -2,12 +2,12 @@
 #![no_main]
 
 use core::panic::PanicInfo;
-use kernel_api::syscall::{exit, println};
+use kernel_api::syscall::println;
 
-#[no_mangle]
-pub extern "C" fn _start() {
+kernel_api::entry_point!(main);
+
+fn main() {
     println("Init process started.");
-    exit();
 }
 
 #[panic_handler]